@@ -0,0 +1,57 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{KvStore, KvsEngine, SledWrapper, StoreOption};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tempfile::TempDir;
+
+const KEYS: usize = 1000;
+const VALUE_SIZE: usize = 100;
+
+fn fill<E: KvsEngine>(engine: &E) {
+    for i in 0..KEYS {
+        engine
+            .set(format!("key{}", i), "v".repeat(VALUE_SIZE))
+            .unwrap();
+    }
+}
+
+fn random_gets<E: KvsEngine>(engine: &E, rng: &mut StdRng) {
+    let key = format!("key{}", rng.gen_range(0..KEYS));
+    assert!(engine.get(key).unwrap().is_some());
+}
+
+// Point reads of values in the active log file, in sealed ones once
+// the store is opened again, and from the value cache.
+fn get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    let mut rng = StdRng::seed_from_u64(1);
+
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    fill(&store);
+    group.bench_function(BenchmarkId::new("kvs", "active"), |b| {
+        b.iter(|| random_gets(&store, &mut rng))
+    });
+    drop(store);
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    group.bench_function(BenchmarkId::new("kvs", "sealed"), |b| {
+        b.iter(|| random_gets(&store, &mut rng))
+    });
+    drop(store);
+    let option = StoreOption {
+        value_cache_size: 1024 * 1024,
+        ..StoreOption::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), option).unwrap();
+    group.bench_function(BenchmarkId::new("kvs", "cached"), |b| {
+        b.iter(|| random_gets(&store, &mut rng))
+    });
+
+    let temp_dir = TempDir::new().unwrap();
+    let db = SledWrapper::new(sled::open(temp_dir.path()).unwrap());
+    fill(&db);
+    group.bench_function("sled", |b| b.iter(|| random_gets(&db, &mut rng)));
+    group.finish();
+}
+
+criterion_group!(benches, get_bench);
+criterion_main!(benches);
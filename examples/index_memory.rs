@@ -0,0 +1,33 @@
+// Memory taken by the key dir per key, in every index mode.
+//
+//     cargo run --release --example index_memory [KEYS]
+use kvs::{IndexMode, KvStore, KvsEngine, Result, StoreOption};
+use std::env;
+use tempfile::TempDir;
+
+fn main() -> Result<()> {
+    let count: u64 = match env::args().nth(1) {
+        Some(count) => count.parse().expect("KEYS must be a number"),
+        None => 1_000_000,
+    };
+    println!("{:<10} {:>12} {:>14}", "mode", "keys", "bytes/key");
+    for index_mode in [IndexMode::Standard, IndexMode::Compact, IndexMode::HashOnly] {
+        let temp_dir = TempDir::new()?;
+        let option = StoreOption {
+            index_mode,
+            ..StoreOption::default()
+        };
+        let store = KvStore::open_with(temp_dir.path(), option)?;
+        for i in 0..count {
+            store.set(format!("user:{:012}", i), String::new())?;
+        }
+        let stats = store.stats();
+        println!(
+            "{:<10} {:>12} {:>14.1}",
+            format!("{:?}", index_mode),
+            stats.keys,
+            stats.index_bytes_per_key()
+        );
+    }
+    Ok(())
+}
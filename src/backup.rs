@@ -0,0 +1,57 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{engines::engine_type_of, KvsEngine, KvsError, Result};
+
+/// Write a snapshot of `engine` to `archive`, a tar of a data directory
+/// which `restore` turns back into a store of the same engine.
+pub fn backup<E: KvsEngine>(engine: &E, archive: &Path) -> Result<()> {
+    let parent = match archive.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    // next to the archive, so that the snapshot is hard linked where it can be
+    let snapshot = tempfile::Builder::new()
+        .prefix(".snapshot")
+        .tempdir_in(parent)?;
+    engine.snapshot(snapshot.path())?;
+
+    // a partially written archive never replaces a complete one
+    let tmp = tempfile::Builder::new()
+        .prefix(".archive")
+        .tempfile_in(parent)?;
+    let mut builder = tar::Builder::new(BufWriter::new(tmp.reopen()?));
+    builder.append_dir_all(".", snapshot.path())?;
+    builder.into_inner()?.flush()?;
+    tmp.as_file().sync_all()?;
+    tmp.persist(archive).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Unpack `archive` into `dir`, which must not hold a store already.
+pub fn restore(archive: &Path, dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut tar = tar::Archive::new(BufReader::new(File::open(archive)?));
+    // unpacked aside first, an unreadable archive leaves `dir` untouched
+    let unpacked = tempfile::Builder::new()
+        .prefix(".restore")
+        .tempdir_in(dir)?;
+    tar.unpack(unpacked.path())?;
+    if engine_type_of(unpacked.path())?.is_none() {
+        return Err(KvsError::UnknownEngineType);
+    }
+    let entries = fs::read_dir(unpacked.path())?.collect::<io::Result<Vec<_>>>()?;
+    if entries
+        .iter()
+        .any(|entry| dir.join(entry.file_name()).exists())
+    {
+        return Err(KvsError::DirectoryNotEmpty(dir.to_path_buf()));
+    }
+    for entry in entries {
+        fs::rename(entry.path(), dir.join(entry.file_name()))?;
+    }
+    Ok(())
+}
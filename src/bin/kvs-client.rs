@@ -1,12 +1,26 @@
-use std::process::exit;
+use std::{path::PathBuf, process::exit};
 
-use clap::Parser;
-use kvs::{ClientCommand, ClientOption, KvsClient, Result};
+use clap::{CommandFactory, ErrorKind, Parser};
+use kvs::{
+    ClientCommand, ClientOption, KvsClient, KvsError, Page, PoolOption, Result, ShardedKvsClient,
+};
 
 fn main() -> Result<()> {
     // `kvs-client set <KEY> <VALUE> [--addr IP-PORT]`
     // `kvs-client get <KEY> [--addr IP-PORT]`
     // `kvs-client rm <KEY> [--addr IP-PORT]`
+    // `kvs-client keys [--cursor KEY] [--limit N] [--addr IP-PORT]`
+    // `kvs-client scan [--cursor KEY] [--limit N] [--addr IP-PORT]`
+    // `kvs-client mget <KEY>... [--addr IP-PORT]`
+    // `kvs-client mset <KEY> <VALUE>... [--addr IP-PORT]`
+    // `kvs-client --cluster IP-PORT,... <get|set|rm|mget|mset> ...`
+    // `kvs-client --cluster IP-PORT,... migrate <TARGET>`
+    // `kvs-client snapshot <DEST-DIR> [--addr IP-PORT]`
+    // `kvs-client backup <ARCHIVE> [--addr IP-PORT]`
+    // `kvs-client promote [--addr IP-PORT]`
+    // `kvs-client add-node <ID> <NODE-ADDR> [--addr IP-PORT]`
+    // `kvs-client remove-node <ID> [--addr IP-PORT]`
+    // `kvs-client --ns <NAMESPACE> <get|set|rm|keys|scan|mget|mset> ...`
     // `kvs-client -V`
     if let Err(e) = run(ClientOption::parse()) {
         eprintln!("{}", e);
@@ -16,20 +30,122 @@ fn main() -> Result<()> {
 }
 
 fn run(opt: ClientOption) -> Result<()> {
+    // a single server is a cluster of one shard
+    let option = PoolOption {
+        namespace: opt.ns.clone(),
+        ..PoolOption::default()
+    };
+    let client = |addr| {
+        if opt.cluster.is_empty() {
+            ShardedKvsClient::new(&[addr], option.clone())
+        } else {
+            ShardedKvsClient::new(&opt.cluster, option.clone())
+        }
+    };
+    let connect = |addr| -> Result<KvsClient> {
+        Ok(KvsClient::connect(addr)?.with_namespace(opt.ns.clone()))
+    };
     match opt.command {
         ClientCommand::get { key, addr } => {
-            if let Some(value) = KvsClient::connect(addr)?.get(key)? {
+            if let Some(value) = client(addr).get(key)? {
                 println!("{}", value);
             } else {
                 println!("Key not found");
             }
         }
         ClientCommand::set { key, value, addr } => {
-            KvsClient::connect(addr)?.set(key, value)?;
+            client(addr).set(key, value)?;
         }
         ClientCommand::rm { key, addr } => {
-            KvsClient::connect(addr)?.remove(key)?;
+            client(addr).remove(key)?;
+        }
+        ClientCommand::keys {
+            cursor,
+            limit,
+            addr,
+        } => {
+            print_page(connect(addr)?.keys(cursor, limit)?, |key| key);
+        }
+        ClientCommand::scan {
+            cursor,
+            limit,
+            addr,
+        } => {
+            let page = connect(addr)?.scan(cursor, limit)?;
+            print_page(page, |(key, value)| format!("{}\t{}", key, value));
+        }
+        ClientCommand::mget { keys, addr } => {
+            for value in client(addr).mget(keys)? {
+                println!("{}", value.as_deref().unwrap_or("Key not found"));
+            }
+        }
+        ClientCommand::mset { pairs, addr } => {
+            if pairs.len() % 2 != 0 {
+                ClientOption::command()
+                    .error(
+                        ErrorKind::WrongNumberOfValues,
+                        format!("missing value for key {}", pairs[pairs.len() - 1]),
+                    )
+                    .exit();
+            }
+            let pairs = pairs
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            client(addr).mset(pairs)?;
+        }
+        ClientCommand::migrate { target } => {
+            if opt.cluster.is_empty() {
+                ClientOption::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "migrate needs the servers of the cluster in '--cluster'",
+                    )
+                    .exit();
+            }
+            ShardedKvsClient::new(&opt.cluster, PoolOption::default()).add_server(target)?;
+        }
+        ClientCommand::snapshot { dest_dir, addr } => {
+            KvsClient::connect(addr)?.snapshot(server_path(dest_dir))?;
+        }
+        ClientCommand::backup { archive, addr } => {
+            KvsClient::connect(addr)?.backup(server_path(archive))?;
+        }
+        ClientCommand::promote { addr } => {
+            KvsClient::connect(addr)?.promote()?;
+        }
+        ClientCommand::add_node {
+            id,
+            node_addr,
+            addr,
+        } => {
+            let add_node = |addr| KvsClient::connect(addr)?.add_node(id, node_addr.to_string());
+            match add_node(addr) {
+                Err(KvsError::Redirect(leader)) => add_node(leader)?,
+                result => result?,
+            }
+        }
+        ClientCommand::remove_node { id, addr } => {
+            let remove_node = |addr| KvsClient::connect(addr)?.remove_node(id);
+            match remove_node(addr) {
+                Err(KvsError::Redirect(leader)) => remove_node(leader)?,
+                result => result?,
+            }
         }
     }
     Ok(())
 }
+
+// Taken by the server relative to its backup directory.
+fn server_path(path: PathBuf) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn print_page<T>(page: Page<T>, line: fn(T) -> String) {
+    for item in page.items {
+        println!("{}", line(item));
+    }
+    if let Some(cursor) = page.cursor {
+        eprintln!("Next page: --cursor {}", cursor);
+    }
+}
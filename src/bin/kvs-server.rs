@@ -1,16 +1,19 @@
 use std::env::current_dir;
+use std::path::Path;
 use std::process::exit;
 
-use clap::Parser;
+use clap::{CommandFactory, ErrorKind, Parser};
 use kvs::{
-    engine_type_of, set_engine_type,
+    engine_type_of,
+    raft::{RaftNode, RaftOption, TcpTransport},
+    set_engine_type,
     thread_pool::{RayonThreadPool, ThreadPool},
-    EngineType, KvStore, KvsError, KvsServer, Result, ServerOption, SledWrapper,
+    EncryptionKey, EngineType, KvStore, KvsEngine, KvsError, KvsServer, Result, ServerOption,
+    SledWrapper, StoreOption,
 };
 
 #[macro_use]
 extern crate log;
-#[macro_use(slog_o)]
 extern crate slog;
 extern crate slog_async;
 extern crate slog_scope;
@@ -18,10 +21,33 @@ extern crate slog_stdlog;
 extern crate slog_term;
 
 fn main() -> Result<()> {
-    // kvs-server [--addr IP-PORT] [--engine ENGINE-NAME]
+    // kvs-server [--addr IP-PORT] [--engine ENGINE-NAME] [--replica-of IP-PORT]
+    // kvs-server [--addr IP-PORT] [--engine ENGINE-NAME] --raft-id ID --peers ID=IP-PORT,...
+    // kvs-server [--backup-dir PATH]
+    // kvs-server [--history-retention N]
+    // kvs-server [--key-file PATH] [--old-key-file PATH]... [--max-open-files N] [--value-cache-size BYTES] ...
     // kvs-server -V
     init_logger();
     let option = ServerOption::parse();
+    if option.engine_type != EngineType::kvs {
+        let encrypted = option.key_file.is_some() || !option.old_key_files.is_empty();
+        let conflict = if encrypted {
+            Some("encryption is only supported by the kvs engine")
+        } else if option.max_open_files.is_some() {
+            Some("--max-open-files is only supported by the kvs engine")
+        } else if option.value_cache_size.is_some() {
+            Some("--value-cache-size is only supported by the kvs engine")
+        } else if option.history_retention.is_some() {
+            Some("--history-retention is only supported by the kvs engine")
+        } else {
+            None
+        };
+        if let Some(message) = conflict {
+            ServerOption::command()
+                .error(ErrorKind::ArgumentConflict, message)
+                .exit();
+        }
+    }
     if let Err(e) = run(option) {
         eprintln!("{}", e);
         exit(1);
@@ -34,8 +60,9 @@ fn run(option: ServerOption) -> Result<()> {
     if let Some(previous_engine_type) = engine_type_of(path)? {
         if previous_engine_type != option.engine_type {
             error!(
-                "Imcompatible engine type, want {}, got {}",
-                previous_engine_type, option.engine_type
+                "Imcompatible engine type, want {}, got {}, \
+                 convert the directory with `kvs migrate --from {} --to {}`",
+                previous_engine_type, option.engine_type, previous_engine_type, option.engine_type
             );
             return Err(KvsError::ImcompatibleEngineType);
         }
@@ -49,26 +76,94 @@ fn run(option: ServerOption) -> Result<()> {
         option.engine_type,
         path.display()
     );
+    if let Some(leader) = option.replica_of {
+        info!("Replicating from {}", leader);
+    }
     match option.engine_type {
         EngineType::kvs => {
-            let engine = KvStore::open(path)?;
-            KvsServer::new(engine, pool).run(&option.addr)?;
+            let engine = KvStore::open_with(path, store_option(&option)?)?;
+            serve(engine, pool, path, &option)?;
         }
         EngineType::sled => {
             let engine = SledWrapper::new(sled::open(path)?);
-            KvsServer::new(engine, pool).run(&option.addr)?;
+            serve(engine, pool, path, &option)?;
         }
     }
     info!("Server done!");
     Ok(())
 }
 
+fn store_option(option: &ServerOption) -> Result<StoreOption> {
+    let encryption_key = match &option.key_file {
+        Some(path) => Some(EncryptionKey::from_file(path)?),
+        None => EncryptionKey::from_env()?,
+    };
+    let old_encryption_keys = option
+        .old_key_files
+        .iter()
+        .map(|path| EncryptionKey::from_file(path))
+        .collect::<Result<_>>()?;
+    let default = StoreOption::default();
+    Ok(StoreOption {
+        encryption_key,
+        old_encryption_keys,
+        max_open_files: option.max_open_files.unwrap_or(default.max_open_files),
+        value_cache_size: option.value_cache_size.unwrap_or(default.value_cache_size),
+        history_retention: option
+            .history_retention
+            .unwrap_or(default.history_retention),
+        ..default
+    })
+}
+
+fn serve<E: KvsEngine>(
+    engine: E,
+    pool: RayonThreadPool,
+    path: &Path,
+    option: &ServerOption,
+) -> Result<()> {
+    if let (Some(id), Some(members)) = (option.raft_id, &option.peers) {
+        info!("Raft node {} of {:?}", id, members);
+        let raft_option = RaftOption {
+            id,
+            members: members.clone(),
+            ..RaftOption::default()
+        };
+        let node = RaftNode::start(
+            engine,
+            path.to_path_buf(),
+            raft_option,
+            TcpTransport::default(),
+        )?;
+        return configure(KvsServer::with_raft(node, pool), path, option)?.run(&option.addr);
+    }
+    let server = KvsServer::new(engine, pool);
+    let server = match option.replica_of {
+        Some(leader) => server.replica_of(leader),
+        None => server,
+    };
+    configure(server, path, option)?.run(&option.addr)
+}
+
+// Settings shared by every kind of server.
+fn configure<E: KvsEngine>(
+    server: KvsServer<E, RayonThreadPool>,
+    path: &Path,
+    option: &ServerOption,
+) -> Result<KvsServer<E, RayonThreadPool>> {
+    let server = server.with_data_dir(path)?;
+    Ok(match &option.backup_dir {
+        Some(dir) => server.with_backup_dir(dir.clone()),
+        None => server,
+    })
+}
+
 fn init_logger() {
     use slog::Drain;
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
     let drain = slog_async::Async::new(drain).build().fuse();
-    let logger = slog::Logger::root(drain, slog_o!());
+    let logger = slog::Logger::root(drain, slog::o!());
     let _scope_guard = slog_scope::set_global_logger(logger);
     _scope_guard.cancel_reset();
     slog_stdlog::init_with_level(log::Level::Info).unwrap();
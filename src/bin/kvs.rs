@@ -1,10 +1,46 @@
-use std::{env::current_dir, process::exit};
+use std::{
+    env::current_dir,
+    fs::File,
+    io::{self, BufWriter},
+    path::Path,
+    process::exit,
+};
 
-use clap::Parser;
-use kvs::{Command, KvStore, KvsCliOption, KvsEngine, Result};
+use clap::{CommandFactory, ErrorKind, Parser};
+use kvs::{
+    backup, convert, engine_type_of, export, import, restore, Command, EncryptionKey, EngineType,
+    KvStore, KvsCliOption, KvsClient, KvsEngine, Page, Result, SledWrapper, StoreOption,
+};
+
+// Bind `$engine` to the namespace `$ns` of the store in `$dir`, of the
+// engine the directory is written by, and evaluate `$body` with it.
+macro_rules! with_engine {
+    ($dir:expr, $ns:expr, |$engine:ident| $body:expr) => {
+        match engine_type_of($dir)? {
+            Some(EngineType::sled) => {
+                let $engine = in_namespace(SledWrapper::new(sled::open($dir)?), $ns)?;
+                $body
+            }
+            _ => {
+                let $engine = in_namespace(open_store($dir)?, $ns)?;
+                $body
+            }
+        }
+    };
+}
+
+// Values are encrypted with the key in `ENCRYPTION_KEY_ENV`, if set.
+fn open_store(dir: &Path) -> Result<KvStore> {
+    let option = StoreOption {
+        encryption_key: EncryptionKey::from_env()?,
+        ..StoreOption::default()
+    };
+    KvStore::open_with(dir, option)
+}
 
 fn main() -> Result<()> {
-    let cli = KvsCliOption::parse();
+    let KvsCliOption { command, ns } = KvsCliOption::parse();
+    let ns = ns.as_deref();
 
     // mathes for command line arguments
     // `kvs set <KEY> <VALUE>`
@@ -13,16 +49,33 @@ fn main() -> Result<()> {
     // Get the string value of a given string key
     // `kvs rm <KEY>`
     // Remove a given key
+    // `kvs keys [--cursor KEY] [--limit N]`
+    // List keys, the cursor of the next page is printed last
+    // `kvs scan [--cursor KEY] [--limit N]`
+    // List key-value pairs, the same way
+    // `kvs backup <ARCHIVE> [--addr IP-PORT]`
+    // Write a backup archive of the store, or of the store of a server
+    // `kvs export [--format jsonl|csv] [--output FILE]`
+    // Write every key/value pair of the store, of every namespace unless --ns is given
+    // `kvs import [--format jsonl|csv] [FILE]`
+    // Set the key/value pairs of an export in their namespaces, or in --ns
+    // `kvs migrate --from <ENGINE> --to <ENGINE> <DIR>`
+    // Convert a data directory to another engine in place
+    // `kvs restore <ARCHIVE>`
+    // Restore a backup archive into the current directory
+    // `kvs --ns <NAMESPACE> ...`
+    // Work in a namespace of the store instead of the default one
     // `kvs -V`
     // Print the version
+    // Values are encrypted with the key in KVS_ENCRYPTION_KEY, if set
 
-    match cli.command {
+    match command {
         Command::set { key, value } => {
-            let store = KvStore::open(current_dir()?)?;
+            let store = in_namespace(open_store(&current_dir()?)?, ns)?;
             store.set(key.to_string(), value.to_string())?;
         }
         Command::get { key } => {
-            let store = KvStore::open(current_dir()?)?;
+            let store = in_namespace(open_store(&current_dir()?)?, ns)?;
             if let Some(value) = store.get(key.to_string())? {
                 println!("{}", value)
             } else {
@@ -30,7 +83,7 @@ fn main() -> Result<()> {
             }
         }
         Command::rm { key } => {
-            let store = KvStore::open(current_dir()?)?;
+            let store = in_namespace(open_store(&current_dir()?)?, ns)?;
             match store.remove(key.to_string()) {
                 Ok(()) => {}
                 Err(kvs::KvsError::KeyNotFound) => {
@@ -40,6 +93,69 @@ fn main() -> Result<()> {
                 Err(e) => return Err(e),
             }
         }
+        Command::keys { cursor, limit } => {
+            let page = with_engine!(&current_dir()?, ns, |engine| engine
+                .list_keys(cursor, limit)?);
+            print_page(page, |key| key);
+        }
+        Command::scan { cursor, limit } => {
+            let page = with_engine!(&current_dir()?, ns, |engine| engine.scan(cursor, limit)?);
+            print_page(page, |(key, value)| format!("{}\t{}", key, value));
+        }
+        Command::backup {
+            archive,
+            addr: Some(addr),
+        } => {
+            KvsClient::connect(addr)?.backup(archive.to_string_lossy().into_owned())?;
+        }
+        Command::backup {
+            archive,
+            addr: None,
+        } => {
+            with_engine!(&current_dir()?, None, |engine| backup(&engine, &archive)?);
+        }
+        Command::export { format, output } => {
+            with_engine!(&current_dir()?, None, |engine| match output {
+                Some(path) => export(&engine, format, ns, BufWriter::new(File::create(path)?))?,
+                None => export(&engine, format, ns, io::stdout().lock())?,
+            });
+        }
+        Command::import { format, input } => {
+            with_engine!(&current_dir()?, None, |engine| match input {
+                Some(path) => import(&engine, format, ns, File::open(path)?)?,
+                None => import(&engine, format, ns, io::stdin().lock())?,
+            });
+        }
+        Command::migrate { from, to, dir } => {
+            if from == to {
+                KvsCliOption::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        format!("the directory is already written by {}", to),
+                    )
+                    .exit();
+            }
+            convert(&dir, from, to)?;
+        }
+        Command::restore { archive } => {
+            restore(&archive, &current_dir()?)?;
+        }
     }
     Ok(())
 }
+
+fn print_page<T>(page: Page<T>, line: fn(T) -> String) {
+    for item in page.items {
+        println!("{}", line(item));
+    }
+    if let Some(cursor) = page.cursor {
+        eprintln!("Next page: --cursor {}", cursor);
+    }
+}
+
+fn in_namespace<E: KvsEngine>(engine: E, ns: Option<&str>) -> Result<E> {
+    match ns {
+        Some(name) => engine.open_tree(name),
+        None => Ok(engine),
+    }
+}
@@ -1,8 +1,11 @@
 use clap::{AppSettings, ArgEnum, Parser, Subcommand};
-use std::{fmt::Display, net::SocketAddr};
+use std::{fmt::Display, net::SocketAddr, path::PathBuf};
+
+use crate::raft::{Members, NodeId};
 
 const DEFAULT_LISTENING_ADDR: &str = "127.0.0.1:4000";
 const DEFAULT_KV_STORAGE_ENGINE: EngineType = EngineType::kvs;
+const DEFAULT_PAGE_SIZE: usize = 100;
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Copy, Clone, ArgEnum, PartialEq, Eq)]
@@ -20,6 +23,27 @@ impl Display for EngineType {
     }
 }
 
+/// Format of key/value pairs exported from a store
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, ArgEnum, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// one JSON object with a key, a value and its namespace per line,
+    /// without a namespace for the default one
+    jsonl,
+    /// a 'key,value,namespace' header, then a row per pair,
+    /// with an empty namespace for the default one
+    csv,
+}
+
+impl Display for ExportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportFormat::jsonl => f.write_str("jsonl"),
+            ExportFormat::csv => f.write_str("csv"),
+        }
+    }
+}
+
 impl slog::Value for EngineType {
     fn serialize(
         &self,
@@ -59,6 +83,86 @@ pub enum Command {
         /// Key of the value that you want to remove
         key: String,
     },
+
+    /// List keys in ascending order, a page at a time
+    keys {
+        /// Last key of the previous page
+        #[clap(long("cursor"), value_name("KEY"))]
+        cursor: Option<String>,
+        /// Number of keys in a page
+        #[clap(long("limit"), default_value_t = DEFAULT_PAGE_SIZE)]
+        limit: usize,
+    },
+
+    /// List key-value pairs in ascending key order, a page at a time
+    scan {
+        /// Last key of the previous page
+        #[clap(long("cursor"), value_name("KEY"))]
+        cursor: Option<String>,
+        /// Number of pairs in a page
+        #[clap(long("limit"), default_value_t = DEFAULT_PAGE_SIZE)]
+        limit: usize,
+    },
+
+    /// Write a backup archive of the store
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    backup {
+        /// Path of the archive
+        #[clap(parse(from_os_str))]
+        archive: PathBuf,
+        /// Back up the store of a running server instead, the archive is
+        /// then written by the server, relative to its backup directory
+        #[clap(long("addr"), value_name("IP-PORT"), parse(try_from_str))]
+        addr: Option<SocketAddr>,
+    },
+
+    /// Write every key/value pair of the store, of every namespace unless --ns is given
+    export {
+        #[clap(
+            long("format"),
+            arg_enum,
+            default_value_t = ExportFormat::jsonl,
+        )]
+        format: ExportFormat,
+        /// File to write, standard output if missing
+        #[clap(long("output"), short('o'), parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+
+    /// Set the key/value pairs of an export in their namespaces, or in --ns
+    import {
+        #[clap(
+            long("format"),
+            arg_enum,
+            default_value_t = ExportFormat::jsonl,
+        )]
+        format: ExportFormat,
+        /// File to read, standard input if missing
+        #[clap(parse(from_os_str))]
+        input: Option<PathBuf>,
+    },
+
+    /// Convert a data directory to another engine in place
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    migrate {
+        /// Engine the directory is written by
+        #[clap(long("from"), arg_enum)]
+        from: EngineType,
+        /// Engine to convert to
+        #[clap(long("to"), arg_enum)]
+        to: EngineType,
+        /// Data directory to convert
+        #[clap(parse(from_os_str))]
+        dir: PathBuf,
+    },
+
+    /// Restore a backup archive into the current directory
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    restore {
+        /// Path of the archive
+        #[clap(parse(from_os_str))]
+        archive: PathBuf,
+    },
 }
 
 #[derive(Debug, Parser)]
@@ -87,6 +191,73 @@ pub struct ServerOption {
     #[clap(arg_enum)]
     /// Storage engine type of kvs server.
     pub engine_type: EngineType,
+    #[clap(long("replica-of"), value_name("IP-PORT"), parse(try_from_str))]
+    /// Address of the leader to replicate from.
+    /// If set, the server is a read-only follower until promoted.
+    pub replica_of: Option<SocketAddr>,
+    #[clap(
+        long("raft-id"),
+        value_name("ID"),
+        requires("peers"),
+        conflicts_with("replica-of")
+    )]
+    /// Id of this server in a raft cluster.
+    pub raft_id: Option<NodeId>,
+    #[clap(
+        long("peers"),
+        value_name("ID=IP-PORT,..."),
+        requires("raft-id"),
+        parse(try_from_str = parse_members),
+    )]
+    /// Initial members of the raft cluster, this server included.
+    /// Leave this server out to join an existing cluster through `add-node`.
+    pub peers: Option<Members>,
+    #[clap(long("key-file"), value_name("PATH"), parse(from_os_str))]
+    /// File holding the key values are encrypted with, as 64 hex digits.
+    /// If not set, the key is taken from KVS_ENCRYPTION_KEY, if set.
+    /// For the kvs engine only.
+    pub key_file: Option<PathBuf>,
+    #[clap(
+        long("old-key-file"),
+        value_name("PATH"),
+        parse(from_os_str),
+        multiple_occurrences(true)
+    )]
+    /// File holding a former key, needed to read values
+    /// until compaction encrypts them with the current key.
+    pub old_key_files: Vec<PathBuf>,
+    #[clap(long("max-open-files"), value_name("N"))]
+    /// Log files kept open for reading at most, by every connection together.
+    /// For the kvs engine only.
+    pub max_open_files: Option<usize>,
+    #[clap(long("value-cache-size"), value_name("BYTES"))]
+    /// Bytes of recently read values kept in memory, none by default.
+    /// For the kvs engine only.
+    pub value_cache_size: Option<usize>,
+    #[clap(long("history-retention"), value_name("N"))]
+    /// Latest mutations compaction keeps in the log, none by default,
+    /// so that followers reconnecting after them need no snapshot.
+    /// For the kvs engine only.
+    pub history_retention: Option<u64>,
+    #[clap(long("backup-dir"), value_name("PATH"), parse(from_os_str))]
+    /// Directory clients may write snapshots and backups into.
+    /// If not set, clients cannot ask for snapshots or backups.
+    pub backup_dir: Option<PathBuf>,
+}
+
+fn parse_members(s: &str) -> std::result::Result<Members, String> {
+    let mut members = Members::new();
+    for member in s.split(',').filter(|member| !member.is_empty()) {
+        let (id, addr) = member
+            .split_once('=')
+            .ok_or_else(|| format!("expect ID=IP-PORT, got {}", member))?;
+        let id = id.parse().map_err(|_| format!("invalid node id {}", id))?;
+        let addr: SocketAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address {}", addr))?;
+        members.insert(id, addr.to_string());
+    }
+    Ok(members)
 }
 
 #[allow(non_camel_case_types)]
@@ -138,6 +309,161 @@ pub enum ClientCommand {
         /// Target address of this command
         addr: SocketAddr,
     },
+
+    /// Add a node to a raft cluster
+    #[clap(name("add-node"), setting(AppSettings::ArgRequiredElseHelp))]
+    add_node {
+        /// Id of the new node
+        id: NodeId,
+        /// Listening address of the new node
+        #[clap(parse(try_from_str))]
+        node_addr: SocketAddr,
+        #[clap(
+            long("addr"),
+            value_name("IP-PORT"),
+            default_value_t = DEFAULT_LISTENING_ADDR.parse().unwrap(),
+            parse(try_from_str),
+        )]
+        /// Target address of this command
+        addr: SocketAddr,
+    },
+
+    /// Remove a node from a raft cluster
+    #[clap(name("remove-node"), setting(AppSettings::ArgRequiredElseHelp))]
+    remove_node {
+        /// Id of the node to remove
+        id: NodeId,
+        #[clap(
+            long("addr"),
+            value_name("IP-PORT"),
+            default_value_t = DEFAULT_LISTENING_ADDR.parse().unwrap(),
+            parse(try_from_str),
+        )]
+        /// Target address of this command
+        addr: SocketAddr,
+    },
+
+    /// List keys in ascending order, a page at a time
+    keys {
+        /// Last key of the previous page
+        #[clap(long("cursor"), value_name("KEY"))]
+        cursor: Option<String>,
+        /// Number of keys in a page
+        #[clap(long("limit"), default_value_t = DEFAULT_PAGE_SIZE)]
+        limit: usize,
+        #[clap(
+            long("addr"),
+            value_name("IP-PORT"),
+            default_value_t = DEFAULT_LISTENING_ADDR.parse().unwrap(),
+            parse(try_from_str),
+        )]
+        /// Target address of this command
+        addr: SocketAddr,
+    },
+
+    /// List key-value pairs in ascending key order, a page at a time
+    scan {
+        /// Last key of the previous page
+        #[clap(long("cursor"), value_name("KEY"))]
+        cursor: Option<String>,
+        /// Number of pairs in a page
+        #[clap(long("limit"), default_value_t = DEFAULT_PAGE_SIZE)]
+        limit: usize,
+        #[clap(
+            long("addr"),
+            value_name("IP-PORT"),
+            default_value_t = DEFAULT_LISTENING_ADDR.parse().unwrap(),
+            parse(try_from_str),
+        )]
+        /// Target address of this command
+        addr: SocketAddr,
+    },
+
+    /// Get the values of many keys
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    mget {
+        /// Keys of the values that you want to get
+        #[clap(required(true))]
+        keys: Vec<String>,
+        #[clap(
+            long("addr"),
+            value_name("IP-PORT"),
+            default_value_t = DEFAULT_LISTENING_ADDR.parse().unwrap(),
+            parse(try_from_str),
+        )]
+        /// Target address of this command
+        addr: SocketAddr,
+    },
+
+    /// Set many keys, given as KEY VALUE pairs
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    mset {
+        /// Keys each followed by its value
+        #[clap(value_name("KEY VALUE"), required(true))]
+        pairs: Vec<String>,
+        #[clap(
+            long("addr"),
+            value_name("IP-PORT"),
+            default_value_t = DEFAULT_LISTENING_ADDR.parse().unwrap(),
+            parse(try_from_str),
+        )]
+        /// Target address of this command
+        addr: SocketAddr,
+    },
+
+    /// Add a server to the sharded cluster given by '--cluster',
+    /// moving over the keys it takes over
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    migrate {
+        /// Address of the new server
+        #[clap(parse(try_from_str))]
+        target: SocketAddr,
+    },
+
+    /// Make the server write a snapshot of its store into a directory
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    snapshot {
+        /// Directory relative to the backup directory of the server,
+        /// must be empty or missing
+        #[clap(parse(from_os_str))]
+        dest_dir: PathBuf,
+        #[clap(
+            long("addr"),
+            value_name("IP-PORT"),
+            default_value_t = DEFAULT_LISTENING_ADDR.parse().unwrap(),
+            parse(try_from_str),
+        )]
+        /// Target address of this command
+        addr: SocketAddr,
+    },
+
+    /// Make the server write a backup archive of its store
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    backup {
+        /// Path of the archive relative to the backup directory of the server
+        #[clap(parse(from_os_str))]
+        archive: PathBuf,
+        #[clap(
+            long("addr"),
+            value_name("IP-PORT"),
+            default_value_t = DEFAULT_LISTENING_ADDR.parse().unwrap(),
+            parse(try_from_str),
+        )]
+        /// Target address of this command
+        addr: SocketAddr,
+    },
+
+    /// Promote a follower to leader
+    promote {
+        #[clap(
+            long("addr"),
+            value_name("IP-PORT"),
+            default_value_t = DEFAULT_LISTENING_ADDR.parse().unwrap(),
+            parse(try_from_str),
+        )]
+        /// Target address of this command
+        addr: SocketAddr,
+    },
 }
 
 #[derive(Debug, Parser)]
@@ -150,6 +476,19 @@ pub enum ClientCommand {
 pub struct ClientOption {
     #[clap(subcommand)]
     pub command: ClientCommand,
+    #[clap(
+        long("cluster"),
+        value_name("IP-PORT,..."),
+        global(true),
+        use_value_delimiter(true),
+        parse(try_from_str)
+    )]
+    /// Addresses of a sharded cluster, keys are spread over them.
+    /// If set, '--addr' of get, set, rm, mget and mset is ignored.
+    pub cluster: Vec<SocketAddr>,
+    #[clap(long("ns"), value_name("NAMESPACE"), global(true))]
+    /// Namespace of the keys, the default one if not set
+    pub ns: Option<String>,
 }
 
 #[derive(Debug, Parser)]
@@ -162,4 +501,7 @@ pub struct ClientOption {
 pub struct KvsCliOption {
     #[clap(subcommand)]
     pub command: Command,
+    #[clap(long("ns"), value_name("NAMESPACE"), global(true))]
+    /// Namespace of the keys, the default one if not set
+    pub ns: Option<String>,
 }
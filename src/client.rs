@@ -1,20 +1,33 @@
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::{TcpStream, ToSocketAddrs},
+    io::{self, BufReader, BufWriter, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
 };
 
 use serde::Deserialize;
-use serde_json::{self, de::IoRead, Deserializer};
+use serde_json::{self, de::IoRead, Deserializer, StreamDeserializer};
 
 use crate::{
-    common::{GetResponse, RemoveResponse, Request, SetResponse},
-    KvsError::ServerErrorMessage,
+    common::TransactionResponse,
+    common::{
+        GetResponse, MembershipResponse, PromoteResponse, RaftResponse, RemoveResponse, Request,
+        SetResponse, WatchEvent, WatchResponse,
+    },
+    common::{HashRange, KeysResponse, MigrateResponse, Page, ScanResponse, SnapshotResponse},
+    raft::{NodeId, RaftMessage, RaftReply},
+    KvsError::{self, Moved, Redirect, ServerErrorMessage},
     Result,
 };
 
+// a key may be migrated again while being looked up
+const MAX_MOVES: usize = 4;
+
 pub struct KvsClient {
     reader: Deserializer<IoRead<BufReader<TcpStream>>>,
     writer: BufWriter<TcpStream>,
+    stream: TcpStream,
+    // namespace of requests on keys, the default one if `None`
+    namespace: Option<String>,
 }
 
 impl KvsClient {
@@ -22,38 +35,289 @@ impl KvsClient {
     where
         T: ToSocketAddrs,
     {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
+        Self::from_stream(TcpStream::connect(addr)?)
+    }
+
+    /// Connect to `addr`, giving up after `connect_timeout`.
+    /// Every response has to arrive within `read_timeout`.
+    pub fn connect_timeout(
+        addr: &SocketAddr,
+        connect_timeout: Duration,
+        read_timeout: Duration,
+    ) -> Result<Self> {
+        let stream = TcpStream::connect_timeout(addr, connect_timeout)?;
+        stream.set_read_timeout(Some(read_timeout))?;
+        Self::from_stream(stream)
+    }
+
+    fn from_stream(stream: TcpStream) -> Result<Self> {
+        let tcp_reader = stream.try_clone()?;
+        let tcp_writer = stream.try_clone()?;
         Ok(KvsClient {
             reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
             writer: BufWriter::new(tcp_writer),
+            stream,
+            namespace: None,
         })
     }
 
+    /// Send requests on keys to the namespace `name` from now on.
+    pub fn with_namespace(mut self, name: Option<String>) -> Self {
+        self.namespace = name;
+        self
+    }
+
+    // Peek without blocking: a closed connection reads 0 bytes,
+    // while an idle but healthy one would block.
+    pub(crate) fn is_alive(&self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let alive = match self.stream.peek(&mut [0; 1]) {
+            Ok(0) => false,
+            Ok(_) => true,
+            Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+        };
+        self.stream.set_nonblocking(false).is_ok() && alive
+    }
+
+    // Requests on migrated keys are answered with `Moved`
+    // and resent to the new owner.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
+        let namespace = self.namespace.clone();
+        let request = Request::Set {
+            key,
+            value,
+            namespace,
+        };
+        self.call(&request, Self::recv_set)
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let namespace = self.namespace.clone();
+        self.call(&Request::Get { key, namespace }, Self::recv_get)
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let namespace = self.namespace.clone();
+        self.call(&Request::Remove { key, namespace }, Self::recv_remove)
+    }
+
+    /// Start a transaction in the namespace of the client. Requests on keys
+    /// go into it until `commit` or `abort`.
+    pub fn begin(&mut self) -> Result<()> {
+        let namespace = self.namespace.clone();
+        self.send(&Request::Begin { namespace })?;
+        self.recv_transaction()
+    }
+
+    /// Fails with `TransactionConflict` if a key read has been written since,
+    /// the transaction is over either way.
+    pub fn commit(&mut self) -> Result<()> {
+        self.send(&Request::Commit)?;
+        self.recv_transaction()
+    }
+
+    pub fn abort(&mut self) -> Result<()> {
+        self.send(&Request::Abort)?;
+        self.recv_transaction()
+    }
+
+    fn recv_transaction(&mut self) -> Result<()> {
+        match TransactionResponse::deserialize(&mut self.reader)? {
+            TransactionResponse::Ok(_) => Ok(()),
+            TransactionResponse::Err(s) => Err(ServerErrorMessage(s)),
+            TransactionResponse::Conflict => Err(KvsError::TransactionConflict),
+            TransactionResponse::Redirect(leader) => Err(Redirect(leader)),
+        }
+    }
+
+    /// Move the keys in `ranges` from this server to `target`,
+    /// returning how many keys were moved.
+    pub fn migrate(&mut self, ranges: Vec<HashRange>, target: SocketAddr) -> Result<u64> {
+        self.send(&Request::Migrate { ranges, target })?;
+        match MigrateResponse::deserialize(&mut self.reader)? {
+            MigrateResponse::Ok(n) => Ok(n),
+            MigrateResponse::Err(s) => Err(ServerErrorMessage(s)),
+            MigrateResponse::Redirect(leader) => Err(Redirect(leader)),
+        }
+    }
+
+    /// List at most `limit` keys following `cursor`, in ascending order.
+    pub fn keys(&mut self, cursor: Option<String>, limit: usize) -> Result<Page<String>> {
+        let namespace = self.namespace.clone();
+        self.send(&Request::Keys {
+            cursor,
+            limit,
+            namespace,
+        })?;
+        match KeysResponse::deserialize(&mut self.reader)? {
+            KeysResponse::Ok(page) => Ok(page),
+            KeysResponse::Err(s) => Err(ServerErrorMessage(s)),
+        }
+    }
+
+    /// List at most `limit` key-value pairs following `cursor`,
+    /// in ascending key order.
+    pub fn scan(&mut self, cursor: Option<String>, limit: usize) -> Result<Page<(String, String)>> {
+        let namespace = self.namespace.clone();
+        self.send(&Request::Scan {
+            cursor,
+            limit,
+            namespace,
+        })?;
+        match ScanResponse::deserialize(&mut self.reader)? {
+            ScanResponse::Ok(page) => Ok(page),
+            ScanResponse::Err(s) => Err(ServerErrorMessage(s)),
+        }
+    }
+
+    /// Make the server write a snapshot of its store into `dest_dir`,
+    /// a path relative to the backup directory of the server.
+    pub fn snapshot(&mut self, dest_dir: String) -> Result<()> {
+        self.send(&Request::Snapshot { dest_dir })?;
+        self.recv_snapshot()
+    }
+
+    /// Make the server write a backup archive of its store to `archive`,
+    /// a path relative to the backup directory of the server.
+    pub fn backup(&mut self, archive: String) -> Result<()> {
+        self.send(&Request::Backup { archive })?;
+        self.recv_snapshot()
+    }
+
+    fn recv_snapshot(&mut self) -> Result<()> {
+        match SnapshotResponse::deserialize(&mut self.reader)? {
+            SnapshotResponse::Ok(_) => Ok(()),
+            SnapshotResponse::Err(s) => Err(ServerErrorMessage(s)),
+        }
+    }
+
+    fn call<T>(&mut self, request: &Request, recv: fn(&mut Self) -> Result<T>) -> Result<T> {
+        self.send(request)?;
+        follow_moved(recv(self), request, recv, KvsClient::connect)
+    }
+
+    /// Stop following the leader and start accepting writes.
+    pub fn promote(&mut self) -> Result<()> {
+        self.send(&Request::Promote)?;
+        match PromoteResponse::deserialize(&mut self.reader)? {
+            PromoteResponse::Ok(_) => Ok(()),
+            PromoteResponse::Err(s) => Err(ServerErrorMessage(s)),
+        }
+    }
+
+    /// Add a node to the raft cluster this server belongs to.
+    pub fn add_node(&mut self, id: NodeId, addr: String) -> Result<()> {
+        self.send(&Request::AddNode { id, addr })?;
+        self.recv_membership()
+    }
+
+    /// Remove a node from the raft cluster this server belongs to.
+    pub fn remove_node(&mut self, id: NodeId) -> Result<()> {
+        self.send(&Request::RemoveNode { id })?;
+        self.recv_membership()
+    }
+
+    pub(crate) fn raft(&mut self, msg: RaftMessage) -> Result<RaftReply> {
+        self.send(&Request::Raft(msg))?;
+        match RaftResponse::deserialize(&mut self.reader)? {
+            RaftResponse::Ok(reply) => Ok(reply),
+            RaftResponse::Err(s) => Err(ServerErrorMessage(s)),
+        }
+    }
+
+    fn recv_membership(&mut self) -> Result<()> {
+        match MembershipResponse::deserialize(&mut self.reader)? {
+            MembershipResponse::Ok(_) => Ok(()),
+            MembershipResponse::Err(s) => Err(ServerErrorMessage(s)),
+            MembershipResponse::Redirect(leader) => Err(Redirect(leader)),
+        }
+    }
+
+    /// Turn this connection into a stream of changes on keys
+    /// starting with `key_or_prefix`.
+    pub fn watch(mut self, key_or_prefix: String) -> Result<WatchStream> {
+        let namespace = self.namespace.clone();
+        self.send(&Request::Watch {
+            key_or_prefix,
+            namespace,
+        })?;
+        match WatchResponse::deserialize(&mut self.reader)? {
+            WatchResponse::Ok(_) => {
+                // events may be arbitrarily far apart
+                self.stream.set_read_timeout(None)?;
+                Ok(WatchStream(self.reader.into_iter()))
+            }
+            WatchResponse::Err(s) => Err(ServerErrorMessage(s)),
+        }
+    }
+
+    pub(crate) fn send(&mut self, request: &Request) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, request)?;
         self.writer.flush()?;
+        Ok(())
+    }
+
+    pub(crate) fn recv_set(&mut self) -> Result<()> {
         match SetResponse::deserialize(&mut self.reader)? {
             SetResponse::Ok(_) => Ok(()),
             SetResponse::Err(s) => Err(ServerErrorMessage(s)),
+            SetResponse::Redirect(leader) => Err(Redirect(leader)),
+            SetResponse::Moved(addr) => Err(Moved(addr)),
         }
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
+    pub(crate) fn recv_get(&mut self) -> Result<Option<String>> {
         match GetResponse::deserialize(&mut self.reader)? {
             GetResponse::Ok(s) => Ok(s),
             GetResponse::Err(s) => Err(ServerErrorMessage(s)),
+            GetResponse::Moved(addr) => Err(Moved(addr)),
         }
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
+    pub(crate) fn recv_remove(&mut self) -> Result<()> {
         match RemoveResponse::deserialize(&mut self.reader)? {
             RemoveResponse::Ok(_) => Ok(()),
             RemoveResponse::Err(s) => Err(ServerErrorMessage(s)),
+            RemoveResponse::Redirect(leader) => Err(Redirect(leader)),
+            RemoveResponse::Moved(addr) => Err(Moved(addr)),
         }
     }
 }
+
+/// Resend `request` to wherever its key has been moved,
+/// on a connection made by `connect`.
+pub(crate) fn follow_moved<T, F>(
+    mut result: Result<T>,
+    request: &Request,
+    recv: fn(&mut KvsClient) -> Result<T>,
+    connect: F,
+) -> Result<T>
+where
+    F: Fn(SocketAddr) -> Result<KvsClient>,
+{
+    for _ in 0..MAX_MOVES {
+        match result {
+            Err(KvsError::Moved(addr)) => {
+                debug!("Follow {:?} to {}", request, addr);
+                let mut client = connect(addr)?;
+                client.send(request)?;
+                result = recv(&mut client);
+            }
+            result => return result,
+        }
+    }
+    result
+}
+
+/// Change events pushed by the server, ending when the connection is closed.
+pub struct WatchStream(StreamDeserializer<'static, IoRead<BufReader<TcpStream>>, WatchEvent>);
+
+impl Iterator for WatchStream {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|event| Ok(event?))
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{client::follow_moved, common::Request, KvsClient, KvsError, Result};
+
+const DEFAULT_MAX_IDLE: usize = 8;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+pub struct PoolOption {
+    // max number of warm connections kept in the pool
+    pub max_idle: usize,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    // how many times an idempotent request is retried
+    pub max_retries: u32,
+    // first retry delay, doubled on every attempt
+    pub backoff: Duration,
+    // namespace of every request, the default one if `None`
+    pub namespace: Option<String>,
+}
+
+impl Default for PoolOption {
+    fn default() -> Self {
+        PoolOption {
+            max_idle: DEFAULT_MAX_IDLE,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            namespace: None,
+        }
+    }
+}
+
+/// A pool of warm connections to a single kvs server.
+///
+/// Idle connections the server closed are replaced before use. `get` is
+/// idempotent, so it is resent on a fresh connection when writing it fails,
+/// and retried with exponential backoff when the connection breaks before
+/// the response comes. Other requests may have reached the server in part
+/// when writing them fails, and fail instead. A request whose response
+/// does not come within `read_timeout` fails, as it may still be applied.
+/// Keys moved to another server are followed with the same timeouts.
+#[derive(Clone)]
+pub struct KvsClientPool {
+    addr: SocketAddr,
+    option: Arc<PoolOption>,
+    idle: Arc<Mutex<Vec<KvsClient>>>,
+}
+
+impl KvsClientPool {
+    pub fn new(addr: SocketAddr, option: PoolOption) -> Self {
+        KvsClientPool {
+            addr,
+            option: Arc::new(option),
+            idle: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        let request = Request::Set {
+            key,
+            value,
+            namespace: self.option.namespace.clone(),
+        };
+        self.call(&request, KvsClient::recv_set)
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let request = Request::Get {
+            key,
+            namespace: self.option.namespace.clone(),
+        };
+        let mut backoff = self.option.backoff;
+        for attempt in 0..self.option.max_retries {
+            match self.call(&request, KvsClient::recv_get) {
+                Err(e) if is_broken_connection(&e) => {
+                    warn!("Get failed on attempt {}, cause {}", attempt + 1, e);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+        self.call(&request, KvsClient::recv_get)
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        let request = Request::Remove {
+            key,
+            namespace: self.option.namespace.clone(),
+        };
+        self.call(&request, KvsClient::recv_remove)
+    }
+
+    fn call<T>(&self, request: &Request, recv: fn(&mut KvsClient) -> Result<T>) -> Result<T> {
+        let mut client = self.send(request)?;
+        let result = recv(&mut client);
+        match &result {
+            Err(e) if is_broken_connection(e) => self.clear(),
+            // the response may still come, the connection cannot be reused
+            Err(e) if is_timeout(e) => {}
+            _ => self.checkin(client),
+        }
+        follow_moved(result, request, recv, |addr| self.connect_to(addr))
+    }
+
+    // A request may reach the server in part even if writing it fails,
+    // only a `get` is safe to resend on a new connection.
+    fn send(&self, request: &Request) -> Result<KvsClient> {
+        let mut client = self.checkout()?;
+        match client.send(request) {
+            Err(e) if is_broken_connection(&e) && matches!(request, Request::Get { .. }) => {
+                debug!("Reconnect to {}, cause {}", self.addr, e);
+                self.clear();
+                let mut client = self.connect()?;
+                client.send(request)?;
+                Ok(client)
+            }
+            Err(e) => {
+                if is_broken_connection(&e) {
+                    self.clear();
+                }
+                Err(e)
+            }
+            Ok(()) => Ok(client),
+        }
+    }
+
+    fn connect(&self) -> Result<KvsClient> {
+        self.connect_to(self.addr)
+    }
+
+    fn connect_to(&self, addr: SocketAddr) -> Result<KvsClient> {
+        KvsClient::connect_timeout(&addr, self.option.connect_timeout, self.option.read_timeout)
+    }
+
+    fn checkout(&self) -> Result<KvsClient> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some(client) = idle.pop() {
+            if client.is_alive() {
+                return Ok(client);
+            }
+        }
+        drop(idle);
+        self.connect()
+    }
+
+    fn checkin(&self, client: KvsClient) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.option.max_idle {
+            idle.push(client);
+        }
+    }
+
+    // a broken connection usually means the server restarted,
+    // so the other idle connections are stale as well
+    fn clear(&self) {
+        self.idle.lock().unwrap().clear();
+    }
+}
+
+// A timeout is not a broken connection: the server may be slow
+// rather than gone, retrying could apply a request twice.
+fn is_broken_connection(err: &KvsError) -> bool {
+    match err {
+        KvsError::IOError(e) => matches!(
+            e.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::UnexpectedEof
+        ),
+        KvsError::SerdeError(e) => (e.is_io() && !is_timeout(err)) || e.is_eof(),
+        _ => false,
+    }
+}
+
+// A read timeout shows up as `WouldBlock` on some platforms.
+fn is_timeout(err: &KvsError) -> bool {
+    let kind = match err {
+        KvsError::IOError(e) => Some(e.kind()),
+        KvsError::SerdeError(e) => e.io_error_kind(),
+        _ => None,
+    };
+    matches!(
+        kind,
+        Some(io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+    )
+}
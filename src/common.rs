@@ -1,26 +1,253 @@
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
 
-#[derive(Debug, Deserialize, Serialize)]
+use crate::raft::{NodeId, RaftMessage, RaftReply};
+
+/// A page of a listing, `cursor` asks for the next one
+/// and is `None` on the last page.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Request {
-    Set { key: String, value: String },
-    Get { key: String },
-    Remove { key: String },
+    // requests on keys are served within a namespace
+    Set {
+        key: String,
+        value: String,
+        // the default namespace if missing
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Get {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Remove {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Watch {
+        key_or_prefix: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    // a page of keys, or of key-value pairs, following `cursor`
+    Keys {
+        cursor: Option<String>,
+        limit: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Scan {
+        cursor: Option<String>,
+        limit: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    // per namespace, changes after `since` if the leader still has them,
+    // a snapshot otherwise
+    Sync {
+        since: Vec<(Option<String>, u64)>,
+    },
+    Promote,
+    // between members of a raft cluster
+    Raft(RaftMessage),
+    AddNode {
+        id: NodeId,
+        addr: String,
+    },
+    RemoveNode {
+        id: NodeId,
+    },
+    // hand the keys in `ranges` over to `target`
+    Migrate {
+        ranges: Vec<HashRange>,
+        target: SocketAddr,
+    },
+    // write a snapshot of the engine into a directory on the server
+    Snapshot {
+        dest_dir: String,
+    },
+    // write a snapshot of the engine into an archive on the server
+    Backup {
+        archive: String,
+    },
+    // Requests on keys of the connection go into the transaction until it is
+    // committed or aborted, and must be in its namespace.
+    Begin {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Commit,
+    Abort,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum SetResponse {
     Ok(()),
     Err(String),
+    // writes go to the leader
+    Redirect(SocketAddr),
+    // the key has been migrated to another server
+    Moved(SocketAddr),
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum GetResponse {
     Ok(Option<String>),
     Err(String),
+    Moved(SocketAddr),
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum RemoveResponse {
     Ok(()),
     Err(String),
+    Redirect(SocketAddr),
+    Moved(SocketAddr),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum WatchResponse {
+    Ok(()),
+    Err(String),
+}
+
+/// A committed write, as streamed to watchers.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum WatchEvent {
+    Set {
+        key: String,
+        value: String,
+        timestamp: i64,
+    },
+    Remove {
+        key: String,
+        timestamp: i64,
+    },
+}
+
+impl WatchEvent {
+    pub fn key(&self) -> &str {
+        match self {
+            WatchEvent::Set { key, .. } | WatchEvent::Remove { key, .. } => key,
+        }
+    }
+}
+
+/// A mutation together with its position in the log.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Change {
+    pub seq: u64,
+    pub event: WatchEvent,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum SyncResponse {
+    Ok(()),
+    Err(String),
+}
+
+/// Messages streamed from a leader to a follower.
+/// All pairs of the snapshot come first, then the changes made since.
+/// Sequence numbers are missing if the leader's engine does not log mutations.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum SyncEvent {
+    // events are of a namespace, the default one if missing
+    Pair {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+        value: String,
+    },
+    // the snapshot is as of the mutation numbered `seq`
+    SnapshotDone {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        seq: Option<u64>,
+    },
+    Change {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        seq: Option<u64>,
+        event: WatchEvent,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum PromoteResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum RaftResponse {
+    Ok(RaftReply),
+    Err(String),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum MembershipResponse {
+    Ok(()),
+    Err(String),
+    Redirect(SocketAddr),
+}
+
+/// Keys whose hash is in `(start, end]`, wrapping around `u64::MAX`
+/// when `start >= end`, as owned by a virtual node of a sharded cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HashRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl HashRange {
+    pub fn contains(&self, hash: u64) -> bool {
+        if self.start < self.end {
+            self.start < hash && hash <= self.end
+        } else {
+            self.start < hash || hash <= self.end
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum MigrateResponse {
+    // number of keys moved
+    Ok(u64),
+    Err(String),
+    // keys are moved by the leader
+    Redirect(SocketAddr),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum SnapshotResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum KeysResponse {
+    Ok(Page<String>),
+    Err(String),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ScanResponse {
+    Ok(Page<(String, String)>),
+    Err(String),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum TransactionResponse {
+    Ok(()),
+    Err(String),
+    // a key read has been written since, the transaction is over
+    Conflict,
+    Redirect(SocketAddr),
 }
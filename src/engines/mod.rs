@@ -1,17 +1,182 @@
 pub mod sled_wrapper;
 pub mod toy_bitcask;
 
-use std::{fs, path::PathBuf};
+use std::{fs, iter, path::Path, sync::mpsc::RecvTimeoutError, time::Duration};
 
-use crate::{EngineType, KvsError, Result};
+use crate::{
+    common::{Change, Page, WatchEvent},
+    EngineType, KvsError, Result,
+};
+
+/// Committed writes on a watched key or key prefix.
+pub type Events = Box<dyn EventStream>;
+
+/// A subscription to committed writes, which ends once dropped.
+pub trait EventStream: Iterator<Item = WatchEvent> + Send {
+    /// The next event, or `Timeout` if none is committed within `timeout`.
+    fn next_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> std::result::Result<WatchEvent, RecvTimeoutError>;
+}
+
+/// Key-value pairs stored in an engine.
+pub type Pairs = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
+
+/// Keys stored in an engine.
+pub type Keys = Box<dyn Iterator<Item = Result<String>> + Send>;
+
+/// Mutations read back from an engine's log, in sequence order.
+pub type ChangeFeed = Box<dyn Iterator<Item = Result<Change>> + Send>;
+
+// a transaction losing every race gives up eventually
+const MAX_TRANSACTION_RETRIES: usize = 64;
+
+/// Reads and writes on several keys of a namespace, which take effect
+/// together at commit, or not at all.
+pub trait Transaction: Send + 'static {
+    /// The value of `key`, as written by this transaction or read first.
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+    fn remove(&mut self, key: String) -> Result<()>;
+    /// Apply the writes, failing with `TransactionConflict` if a key read
+    /// has been written by someone else since.
+    fn commit(self) -> Result<()>;
+}
 
 pub trait KvsEngine: Clone + Send + 'static {
+    type Transaction: Transaction;
+
     fn set(&self, key: String, value: String) -> Result<()>;
     fn get(&self, key: String) -> Result<Option<String>>;
     fn remove(&self, key: String) -> Result<()>;
+    fn watch(&self, key_or_prefix: String) -> Result<Events>;
+    /// Stream the keys in ascending order.
+    /// Writes made while iterating may or may not be seen.
+    fn keys(&self) -> Result<Keys>;
+    /// Stream the key-value pairs in ascending key order.
+    /// Writes made while iterating may or may not be seen.
+    fn iter(&self) -> Result<Pairs>;
+    /// A handle on the namespace `name`, created on first use. Namespaces
+    /// are separate keyspaces of the same store, every handle opens them
+    /// by the same names.
+    fn open_tree(&self, name: &str) -> Result<Self>;
+    /// Names of the namespaces in the store, besides the default one.
+    fn tree_names(&self) -> Result<Vec<String>>;
+    /// Write a copy of the store, with every namespace, into `dest_dir`,
+    /// which must be empty or missing. The copy can be opened as a store
+    /// of its own.
+    fn snapshot(&self, dest_dir: &Path) -> Result<()>;
+    /// Open the store in `dir` with the options of this one, a copy written
+    /// by `snapshot` or a new store if `dir` is empty or missing.
+    fn open_copy(&self, dir: &Path) -> Result<Self>;
+    /// Start a transaction on this namespace. Dropping it aborts it.
+    fn begin(&self) -> Result<Self::Transaction>;
+
+    /// Sequence number of the latest mutation of this namespace,
+    /// for engines which log mutations in order.
+    fn last_seq(&self) -> Option<u64> {
+        None
+    }
+
+    /// Mutations of this namespace numbered after `seq`, up to the latest
+    /// one. Once caught up the feed ends, and read again it goes on with
+    /// later mutations. Engines without a `last_seq` have none to give.
+    fn change_feed(&self, seq: u64) -> Result<ChangeFeed> {
+        let _ = seq;
+        Ok(Box::new(iter::empty()))
+    }
+
+    /// Run `f` in a transaction and commit it, running it again
+    /// from scratch as long as it conflicts with other writes.
+    fn transaction<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Self::Transaction) -> Result<T>,
+    {
+        for _ in 0..MAX_TRANSACTION_RETRIES {
+            let mut tx = self.begin()?;
+            let result = f(&mut tx).and_then(|result| tx.commit().map(|_| result));
+            match result {
+                Err(KvsError::TransactionConflict) => continue,
+                result => return result,
+            }
+        }
+        Err(KvsError::TransactionConflict)
+    }
+
+    /// At most `limit` keys following `cursor`, with the cursor of the next page.
+    fn list_keys(&self, cursor: Option<String>, limit: usize) -> Result<Page<String>> {
+        page(self.keys()?, |key| key, cursor, limit)
+    }
+
+    /// At most `limit` key-value pairs following `cursor`,
+    /// with the cursor of the next page.
+    fn scan(&self, cursor: Option<String>, limit: usize) -> Result<Page<(String, String)>> {
+        scan_keys(
+            cursor,
+            limit,
+            |after, n| Ok(self.list_keys(after.map(str::to_owned), n)?.items),
+            |key| self.get(key),
+        )
+    }
 }
 
-pub fn engine_type_of(path: &PathBuf) -> Result<Option<EngineType>> {
+// A cursor is the last key of the previous page.
+pub(crate) fn page<T>(
+    items: impl Iterator<Item = Result<T>>,
+    key_of: fn(&T) -> &String,
+    cursor: Option<String>,
+    limit: usize,
+) -> Result<Page<T>> {
+    let mut items = items.filter(|item| match (item, &cursor) {
+        (Ok(item), Some(cursor)) => key_of(item) > cursor,
+        _ => true,
+    });
+    let items = items.by_ref().take(limit).collect::<Result<Vec<T>>>()?;
+    let cursor = match items.last() {
+        Some(last) if items.len() == limit => Some(key_of(last).clone()),
+        _ => None,
+    };
+    Ok(Page { items, cursor })
+}
+
+// Pairs of the keys following `cursor`, `next_keys(after, n)` giving the
+// first `n` keys after `after`. Values are read for keys of the page only,
+// a key gone in the meantime is skipped.
+pub(crate) fn scan_keys<F, G>(
+    cursor: Option<String>,
+    limit: usize,
+    mut next_keys: F,
+    mut value_of: G,
+) -> Result<Page<(String, String)>>
+where
+    F: FnMut(Option<&str>, usize) -> Result<Vec<String>>,
+    G: FnMut(String) -> Result<Option<String>>,
+{
+    let mut items = Vec::with_capacity(limit);
+    let mut after = cursor;
+    while items.len() < limit {
+        let wanted = limit - items.len();
+        let keys = next_keys(after.as_deref(), wanted)?;
+        let exhausted = keys.len() < wanted;
+        for key in keys {
+            after = Some(key.clone());
+            if let Some(value) = value_of(key.clone())? {
+                items.push((key, value));
+            }
+        }
+        if exhausted {
+            break;
+        }
+    }
+    let cursor = match items.last() {
+        Some((key, _)) if items.len() == limit => Some(key.clone()),
+        _ => None,
+    };
+    Ok(Page { items, cursor })
+}
+
+pub fn engine_type_of(path: &Path) -> Result<Option<EngineType>> {
     let type_marker = path.join("engine");
     if !type_marker.exists() {
         info!("No engine marker");
@@ -24,7 +189,60 @@ pub fn engine_type_of(path: &PathBuf) -> Result<Option<EngineType>> {
     }
 }
 
-pub fn set_engine_type(path: &PathBuf, engine_type: &EngineType) -> Result<()> {
+pub fn set_engine_type(path: &Path, engine_type: &EngineType) -> Result<()> {
     fs::write(path.join("engine"), format!("{}", engine_type))?;
     Ok(())
 }
+
+// Replays and migrations may remove a key which is already gone.
+pub(crate) fn remove_if_exists<E: KvsEngine>(engine: &E, key: String) -> Result<()> {
+    match engine.remove(key) {
+        Ok(()) | Err(KvsError::KeyNotFound) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The namespace `name` of `engine`, or the default one.
+pub(crate) fn tree_of<E: KvsEngine>(engine: &E, name: Option<&str>) -> Result<E> {
+    match name {
+        Some(name) => engine.open_tree(name),
+        None => Ok(engine.clone()),
+    }
+}
+
+// Names end up in paths, and must not leave the namespace directory.
+pub(crate) fn check_tree_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    if valid {
+        Ok(())
+    } else {
+        Err(KvsError::InvalidNamespace(name.to_owned()))
+    }
+}
+
+// Snapshots and restores never overwrite existing data.
+pub(crate) fn ensure_empty_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(KvsError::DirectoryNotEmpty(dir.to_path_buf()));
+    }
+    Ok(())
+}
+
+// Files of a data directory which belong to the engine.
+pub(crate) fn is_engine_file(engine_type: EngineType, name: &str) -> bool {
+    match engine_type {
+        EngineType::kvs => {
+            name.ends_with(".log")
+                || name.ends_with(".history")
+                || name.ends_with(".index")
+                || ["floor", "ns", "MANIFEST", "LOCK"].contains(&name)
+        }
+        EngineType::sled => ["conf", "db", "blobs"].contains(&name) || name.starts_with("snap."),
+    }
+}
@@ -1,24 +1,51 @@
-use crate::{KvsEngine, KvsError, Result};
-use sled::Db;
+use crate::{
+    common::{Page, WatchEvent},
+    engines::{
+        check_tree_name, ensure_empty_dir, page, set_engine_type, EventStream, Events, Keys, Pairs,
+    },
+    EngineType, KvsEngine, KvsError, Result, Transaction,
+};
+use chrono::Utc;
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Db, Event, IVec, Iter, Subscriber, Tree,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    path::Path,
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, Instant},
+};
+
+// sled's own name of the default tree
+const DEFAULT_TREE: &[u8] = b"__sled__default";
 
 #[derive(Clone)]
-pub struct SledWrapper(Db);
+pub struct SledWrapper {
+    db: Db,
+    // the namespace this handle is on
+    tree: Tree,
+}
 
 impl SledWrapper {
     pub fn new(db: Db) -> Self {
-        SledWrapper(db)
+        let tree = Tree::clone(&db);
+        SledWrapper { db, tree }
     }
 }
 
 impl KvsEngine for SledWrapper {
+    type Transaction = SledTransaction;
+
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.0.insert(key.as_bytes(), value.as_bytes())?;
-        self.0.flush()?;
+        self.tree.insert(key.as_bytes(), value.as_bytes())?;
+        self.tree.flush()?;
         Ok(())
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(value) = self.0.get(key)? {
+        if let Some(value) = self.tree.get(key)? {
             Ok(Some(String::from_utf8(value.as_ref().to_vec())?))
         } else {
             Ok(None)
@@ -26,8 +53,201 @@ impl KvsEngine for SledWrapper {
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        self.0.remove(key)?.ok_or(KvsError::KeyNotFound)?;
-        self.0.flush()?;
+        self.tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        self.tree.flush()?;
         Ok(())
     }
+
+    fn watch(&self, key_or_prefix: String) -> Result<Events> {
+        Ok(Box::new(SledEvents(self.tree.watch_prefix(key_or_prefix))))
+    }
+
+    fn keys(&self) -> Result<Keys> {
+        Ok(Box::new(self.tree.iter().keys().map(key_of)))
+    }
+
+    fn iter(&self) -> Result<Pairs> {
+        Ok(Box::new(self.tree.iter().map(pair_of)))
+    }
+
+    // Pages start right after the cursor, nothing before it is read.
+    fn list_keys(&self, cursor: Option<String>, limit: usize) -> Result<Page<String>> {
+        let keys = self.iter_after(cursor.as_deref()).keys().map(key_of);
+        page(keys, |key| key, None, limit)
+    }
+
+    fn scan(&self, cursor: Option<String>, limit: usize) -> Result<Page<(String, String)>> {
+        let pairs = self.iter_after(cursor.as_deref()).map(pair_of);
+        page(pairs, |(key, _)| key, None, limit)
+    }
+
+    fn open_tree(&self, name: &str) -> Result<Self> {
+        check_tree_name(name)?;
+        Ok(SledWrapper {
+            db: self.db.clone(),
+            tree: self.db.open_tree(name)?,
+        })
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for name in self.db.tree_names() {
+            if name != DEFAULT_TREE {
+                names.push(String::from_utf8(name.to_vec())?);
+            }
+        }
+        Ok(names)
+    }
+
+    // sled cannot copy its files while open, the copy is rebuilt from an export
+    fn snapshot(&self, dest_dir: &Path) -> Result<()> {
+        ensure_empty_dir(dest_dir)?;
+        let db = sled::open(dest_dir)?;
+        db.import(self.db.export());
+        db.flush()?;
+        set_engine_type(dest_dir, &EngineType::sled)
+    }
+
+    fn open_copy(&self, dir: &Path) -> Result<Self> {
+        Ok(SledWrapper::new(sled::open(dir)?))
+    }
+
+    fn begin(&self) -> Result<SledTransaction> {
+        Ok(SledTransaction {
+            tree: self.tree.clone(),
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        })
+    }
+}
+
+/// A transaction on a `SledWrapper`. Reads are checked again and writes
+/// applied in a single sled transaction at commit.
+pub struct SledTransaction {
+    tree: Tree,
+    // key -> value when first read
+    reads: HashMap<String, Option<IVec>>,
+    // key -> value written, `None` if removed
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl Transaction for SledTransaction {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        let value = match self.writes.get(&key) {
+            Some(value) => return Ok(value.clone()),
+            None => match self.reads.get(&key) {
+                Some(value) => value.clone(),
+                None => {
+                    let value = self.tree.get(&key)?;
+                    self.reads.insert(key, value.clone());
+                    value
+                }
+            },
+        };
+        Ok(value.map(|v| String::from_utf8(v.to_vec())).transpose()?)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    fn commit(self) -> Result<()> {
+        let result = self.tree.transaction(|tx| {
+            for (key, value) in &self.reads {
+                if tx.get(key)? != *value {
+                    return Err(ConflictableTransactionError::Abort(
+                        KvsError::TransactionConflict,
+                    ));
+                }
+            }
+            for (key, value) in &self.writes {
+                match value {
+                    Some(value) => tx.insert(key.as_bytes(), value.as_bytes())?,
+                    None => tx.remove(key.as_bytes())?,
+                };
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => {}
+            Err(TransactionError::Abort(e)) => return Err(e),
+            Err(TransactionError::Storage(e)) => return Err(e.into()),
+        }
+        self.tree.flush()?;
+        Ok(())
+    }
+}
+
+impl SledWrapper {
+    fn iter_after(&self, after: Option<&str>) -> Iter {
+        match after {
+            Some(after) => self
+                .tree
+                .range::<&[u8], _>((Bound::Excluded(after.as_bytes()), Bound::Unbounded)),
+            None => self.tree.iter(),
+        }
+    }
+}
+
+fn key_of(key: sled::Result<IVec>) -> Result<String> {
+    Ok(String::from_utf8(key?.to_vec())?)
+}
+
+fn pair_of(pair: sled::Result<(IVec, IVec)>) -> Result<(String, String)> {
+    let (key, value) = pair?;
+    Ok((
+        String::from_utf8(key.to_vec())?,
+        String::from_utf8(value.to_vec())?,
+    ))
+}
+
+// sled unsubscribes when its `Subscriber` is dropped.
+struct SledEvents(Subscriber);
+
+// Events on keys or values that are not UTF-8 are skipped.
+fn watch_event(event: Event) -> Option<WatchEvent> {
+    let timestamp = Utc::now().timestamp();
+    match event {
+        Event::Insert { key, value } => Some(WatchEvent::Set {
+            key: String::from_utf8(key.to_vec()).ok()?,
+            value: String::from_utf8(value.to_vec()).ok()?,
+            timestamp,
+        }),
+        Event::Remove { key } => Some(WatchEvent::Remove {
+            key: String::from_utf8(key.to_vec()).ok()?,
+            timestamp,
+        }),
+    }
+}
+
+impl Iterator for SledEvents {
+    type Item = WatchEvent;
+
+    fn next(&mut self) -> Option<WatchEvent> {
+        self.0.by_ref().find_map(watch_event)
+    }
+}
+
+impl EventStream for SledEvents {
+    fn next_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> std::result::Result<WatchEvent, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if let Some(event) = watch_event(self.0.next_timeout(left)?) {
+                return Ok(event);
+            }
+        }
+    }
 }
@@ -0,0 +1,126 @@
+use lru::LruCache;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+/// Hit and miss counts of a value cache, and the bytes it holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub bytes: u64,
+}
+
+/// Values recently read, shared by every handle of a store, namespaces
+/// included, holding at most `capacity` bytes of keys and values.
+///
+/// A value is cached along with the sequence number of its version, and is
+/// only a hit for that version: compaction moves records but leaves their
+/// sequence numbers, so cached values stay valid through it.
+#[derive(Clone)]
+pub(crate) struct ValueCache {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    capacity: u64,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    // ids handed out to namespaces, which number their versions apart
+    next_space: AtomicU64,
+}
+
+struct Entries {
+    // (namespace id, key) -> (seq, value)
+    lru: LruCache<(u64, String), (u64, String)>,
+    bytes: u64,
+}
+
+impl ValueCache {
+    /// A cache of `capacity` bytes, which caches nothing if zero.
+    pub(crate) fn new(capacity: usize) -> Self {
+        ValueCache {
+            inner: Arc::new(Inner {
+                capacity: capacity as u64,
+                entries: Mutex::new(Entries {
+                    lru: LruCache::unbounded(),
+                    bytes: 0,
+                }),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+                next_space: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// An id for the keys of a namespace.
+    pub(crate) fn new_space(&self) -> u64 {
+        self.inner.next_space.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// The value of `key` as of the mutation numbered `seq`, if cached.
+    pub(crate) fn get(&self, space: u64, key: &str, seq: u64) -> Option<String> {
+        if self.inner.capacity == 0 {
+            return None;
+        }
+        let mut entries = self.inner.entries.lock().unwrap();
+        match entries.lru.get(&(space, key.to_owned())) {
+            Some((cached_seq, value)) if *cached_seq == seq => {
+                self.inner.hits.fetch_add(1, Ordering::Relaxed);
+                Some(value.clone())
+            }
+            _ => {
+                self.inner.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Cache `value` as the version `seq` of `key`, evicting the least
+    /// recently used values to make room. Values alone larger than the
+    /// cache are not cached.
+    pub(crate) fn insert(&self, space: u64, key: String, seq: u64, value: String) {
+        let size = (key.len() + value.len()) as u64;
+        if size > self.inner.capacity {
+            return;
+        }
+        let mut entries = self.inner.entries.lock().unwrap();
+        // a slow reader must not replace a newer version
+        if let Some((cached_seq, _)) = entries.lru.peek(&(space, key.clone())) {
+            if *cached_seq > seq {
+                return;
+            }
+        }
+        if let Some(((_, key), (_, value))) = entries.lru.push((space, key), (seq, value)) {
+            entries.bytes -= (key.len() + value.len()) as u64;
+        }
+        entries.bytes += size;
+        while entries.bytes > self.inner.capacity {
+            match entries.lru.pop_lru() {
+                Some(((_, key), (_, value))) => entries.bytes -= (key.len() + value.len()) as u64,
+                None => break,
+            }
+        }
+    }
+
+    /// Drop the cached value of `key`, which is changed.
+    pub(crate) fn invalidate(&self, space: u64, key: &str) {
+        if self.inner.capacity == 0 {
+            return;
+        }
+        let mut entries = self.inner.entries.lock().unwrap();
+        if let Some(((_, key), (_, value))) = entries.lru.pop_entry(&(space, key.to_owned())) {
+            entries.bytes -= (key.len() + value.len()) as u64;
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+            bytes: self.inner.entries.lock().unwrap().bytes,
+        }
+    }
+}
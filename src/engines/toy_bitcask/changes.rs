@@ -0,0 +1,162 @@
+use crate::{
+    common::Change,
+    engines::toy_bitcask::kv::{ActiveLog, Codec, Command},
+    KvsError, Result,
+};
+use serde_json::{de::IoRead, Deserializer, StreamDeserializer};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::BufReader,
+    sync::{Arc, Mutex, Weak},
+};
+
+type CommandStream = StreamDeserializer<'static, IoRead<BufReader<File>>, Command>;
+
+// Files written since a stream of changes last caught up, with their ids.
+pub(crate) struct Tail {
+    pub files: Vec<(u64, File)>,
+    // the latest sequence number
+    pub end_seq: u64,
+    // mutations up to this sequence number may have been compacted away
+    pub seq_floor: u64,
+}
+
+/// Mutations read back from log and history files in sequence order.
+///
+/// Files are visited in id order. A record whose sequence number is not
+/// beyond the last yielded one is either older than requested or a copy
+/// made by compaction, and is skipped, as are tombstones. Commands of a
+/// transaction are only yielded once its last one is read: one cut short
+/// by a crash never took effect.
+///
+/// The stream ends once caught up with the store. Read again, it goes on
+/// from the file and offset it stopped at, with files written since.
+pub struct Changes {
+    // files to read, each with the sequence number the stream
+    // must have reached before reading it
+    files: VecDeque<(File, u64)>,
+    current: Option<CommandStream>,
+    // id of the latest file queued
+    last_id: u64,
+    // commands of a transaction read in part
+    txn: Vec<Command>,
+    // commands read in full, not yielded yet
+    ready: VecDeque<Command>,
+    // sequence number of the last change read
+    last_seq: u64,
+    // the latest change committed when the stream last caught up
+    end_seq: u64,
+    // to read values back in the clear
+    codec: Arc<Codec>,
+    // to look for later changes, the stream does not keep the store open
+    log: Weak<Mutex<ActiveLog>>,
+}
+
+impl Changes {
+    pub(crate) fn new(
+        files: Vec<(u64, File)>,
+        last_id: u64,
+        since: u64,
+        end_seq: u64,
+        codec: Arc<Codec>,
+        log: Weak<Mutex<ActiveLog>>,
+    ) -> Self {
+        Changes {
+            files: files.into_iter().map(|(_, file)| (file, since)).collect(),
+            current: None,
+            last_id,
+            txn: Vec::new(),
+            ready: VecDeque::new(),
+            last_seq: since,
+            end_seq,
+            codec,
+            log,
+        }
+    }
+
+    fn read(&mut self) -> Option<Result<Change>> {
+        loop {
+            if let Some(cmd) = self.ready.pop_front() {
+                let seq = cmd.seq();
+                return Some(cmd.decode(&self.codec).map(|cmd| Change {
+                    seq,
+                    event: cmd.into(),
+                }));
+            }
+            // never read past `end_seq`, a later record may be half written
+            if self.last_seq >= self.end_seq {
+                return None;
+            }
+            let current = match &mut self.current {
+                Some(current) => current,
+                None => {
+                    let (file, floor) = self.files.pop_front()?;
+                    // Files written after the previous ones were read may have
+                    // been compacted away before they could be opened.
+                    if self.last_seq < floor {
+                        return Some(Err(KvsError::HistoryTruncated(floor)));
+                    }
+                    self.current
+                        .insert(Deserializer::from_reader(BufReader::new(file)).into_iter())
+                }
+            };
+            match current.next() {
+                // the latest file is kept, more may be appended to it
+                None if self.files.is_empty() => return None,
+                None => {
+                    self.current = None;
+                    self.txn.clear();
+                }
+                Some(Err(e)) => return Some(Err(e.into())),
+                // tombstones are copies of removes made by merges
+                Some(Ok(Command::Tombstone { .. })) => {}
+                Some(Ok(cmd)) => {
+                    let complete = cmd.txn_left() == 0;
+                    self.txn.push(cmd);
+                    if !complete {
+                        continue;
+                    }
+                    for cmd in self.txn.drain(..) {
+                        let seq = cmd.seq();
+                        if seq > self.last_seq && seq <= self.end_seq {
+                            self.last_seq = seq;
+                            self.ready.push_back(cmd);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Catch up with the store as it is now.
+    fn refresh(&mut self) -> Result<()> {
+        let log = match self.log.upgrade() {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let tail = log.lock().unwrap().tail(self.last_id)?;
+        if let Some(&(id, _)) = tail.files.last() {
+            self.last_id = id;
+        }
+        let floor = tail.seq_floor;
+        self.files
+            .extend(tail.files.into_iter().map(|(_, file)| (file, floor)));
+        self.end_seq = tail.end_seq;
+        Ok(())
+    }
+}
+
+impl Iterator for Changes {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(change) = self.read() {
+            return Some(change);
+        }
+        if let Err(e) = self.refresh() {
+            return Some(Err(e));
+        }
+        self.read()
+    }
+}
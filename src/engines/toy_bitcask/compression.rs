@@ -0,0 +1,53 @@
+use crate::Result;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// How values are compressed in the log.
+///
+/// Compressed values are stored base64 encoded, and only when that is still
+/// shorter than the value itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    pub(crate) fn is_none(&self) -> bool {
+        *self == Compression::None
+    }
+
+    pub(crate) fn compress(self, value: &str) -> Result<String> {
+        let compressed = match self {
+            Compression::None => return Ok(value.to_owned()),
+            Compression::Lz4 => lz4_flex::compress_prepend_size(value.as_bytes()),
+            Compression::Zstd => zstd::encode_all(value.as_bytes(), 0)?,
+        };
+        Ok(STANDARD.encode(compressed))
+    }
+
+    pub(crate) fn decompress(self, value: &str) -> Result<String> {
+        if self.is_none() {
+            return Ok(value.to_owned());
+        }
+        let compressed = STANDARD.decode(value).map_err(invalid_data)?;
+        let value = match self {
+            Compression::None => unreachable!(),
+            Compression::Lz4 => {
+                lz4_flex::decompress_size_prepended(&compressed).map_err(invalid_data)?
+            }
+            Compression::Zstd => zstd::decode_all(compressed.as_slice())?,
+        };
+        Ok(String::from_utf8(value)?)
+    }
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
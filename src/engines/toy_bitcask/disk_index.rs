@@ -0,0 +1,653 @@
+use crate::{
+    engines::toy_bitcask::kv::{list_file_in, log_file_of, CommandMeta},
+    Result,
+};
+use dashmap::DashMap;
+use memmap2::Mmap;
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    iter::Peekable,
+    mem::size_of,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    vec,
+};
+
+const INDEX_MAGIC: &[u8; 8] = b"KVSIDX1\n";
+const SEGMENT_MAGIC: &[u8; 8] = b"KVSSEG1\n";
+
+// magic and number of entries
+const INDEX_HEADER_SIZE: usize = 16;
+// then the id of the index file below, and the number of keys
+// of the index up to the segment
+const SEGMENT_HEADER_SIZE: usize = 32;
+
+// index being written, until a compaction or a flush is complete
+const TMP_INDEX_FILE: &str = "index.tmp";
+
+// file id, position, size and sequence number of an entry,
+// all zero for a key removed
+const META_SIZE: usize = 32;
+
+// one key in this many is kept in memory to start lookups from
+const FENCE_INTERVAL: u64 = 64;
+
+// segments piled up since the latest compaction, at most
+const MAX_SEGMENTS: usize = 8;
+
+pub(crate) fn index_file_of(id: u64) -> String {
+    format!("{}.index", id)
+}
+
+/// Keys of every file up to the latest compaction in a sorted index file,
+/// keys of the files sealed since in sorted segments on top of it, each
+/// looked up through its map, and keys written since then in memory.
+pub(crate) struct DiskIndex {
+    dir: PathBuf,
+    // keys changed since the latest index file was written, `None` if removed
+    recent: DashMap<String, Option<CommandMeta>>,
+    // the index of the latest compaction, then the segments written since
+    sorted: RwLock<Vec<Arc<SortedFile>>>,
+    // index written by `relocate`, in use once committed
+    pending: Mutex<Option<SortedFile>>,
+    len: AtomicU64,
+}
+
+impl DiskIndex {
+    /// The index of the latest compaction in `dir`, if any,
+    /// and the segments written on top of it.
+    pub(crate) fn open(dir: &Path) -> Result<DiskIndex> {
+        let mut files = Vec::new();
+        for id in list_file_in(dir, "index")? {
+            // an index outliving the last file it covers is stale
+            if dir.join(log_file_of(id)).exists() {
+                files.push(SortedFile::open(&dir.join(index_file_of(id)), id)?);
+            }
+        }
+        let base = files.iter().rposition(|file| file.below.is_none());
+        let mut sorted = vec![match base {
+            Some(i) => files.swap_remove(i),
+            None => SortedFile::empty(),
+        }];
+        // segments left over from before the index are stale too
+        files.sort_unstable_by_key(|file| file.id);
+        for file in files {
+            if file.below == Some(sorted.last().unwrap().id) {
+                sorted.push(file);
+            }
+        }
+        let len = sorted.last().unwrap().len;
+        Ok(DiskIndex {
+            dir: dir.to_path_buf(),
+            recent: DashMap::new(),
+            len: AtomicU64::new(len),
+            sorted: RwLock::new(sorted.into_iter().map(Arc::new).collect()),
+            pending: Mutex::new(None),
+        })
+    }
+
+    /// Id of the last file indexed.
+    pub(crate) fn indexed(&self) -> Option<u64> {
+        let sorted = self.sorted.read().unwrap();
+        let id = sorted.last().unwrap().id;
+        (id > 0).then_some(id)
+    }
+
+    pub(crate) fn read<F, R>(&self, key: &str, f: F) -> Result<Option<R>>
+    where
+        F: FnOnce(&CommandMeta) -> Result<R>,
+    {
+        // the entry, or the index, stays locked until `f` returns
+        if let Some(entry) = self.recent.get(key) {
+            return entry.value().as_ref().map(f).transpose();
+        }
+        let sorted = self.sorted.read().unwrap();
+        // the latest segment holding the key tells where it is
+        for file in sorted.iter().rev() {
+            if let Some(meta) = file.get(key.as_bytes())? {
+                return meta.as_ref().map(f).transpose();
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn insert(&self, key: String, meta: CommandMeta) -> Result<Option<CommandMeta>> {
+        let old = self.get(&key)?;
+        if old.is_none() {
+            self.len.fetch_add(1, Ordering::SeqCst);
+        }
+        self.recent.insert(key, Some(meta));
+        Ok(old)
+    }
+
+    pub(crate) fn remove(&self, key: &str) -> Result<Option<CommandMeta>> {
+        let old = self.get(key)?;
+        if old.is_some() {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+            self.recent.insert(key.to_owned(), None);
+        }
+        Ok(old)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<CommandMeta>> {
+        self.read(key, |meta| Ok(meta.clone()))
+    }
+
+    /// Keys following `after`, or every key, in ascending order, as of now.
+    /// Keys of the index files are read from their maps as the iterator goes,
+    /// merged with the recent ones.
+    pub(crate) fn keys(&self, after: Option<&str>) -> Result<DiskKeys> {
+        // a relocation or a flush committed meanwhile would clear recent keys
+        let sorted = self.sorted.read().unwrap();
+        let mut recent: Vec<_> = self
+            .recent
+            .iter()
+            .filter(|entry| after.is_none_or(|after| entry.key().as_str() > after))
+            .map(|entry| (entry.key().clone(), entry.value().is_some()))
+            .collect();
+        recent.sort_unstable();
+        Ok(DiskKeys {
+            entries: Entries::new(sorted.clone(), after.map(str::as_bytes))?,
+            head: None,
+            recent: recent.into_iter().peekable(),
+            failed: false,
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst) as usize
+    }
+
+    /// Keys written since the latest index file, held in memory.
+    pub(crate) fn recent_len(&self) -> usize {
+        self.recent.len()
+    }
+
+    // Recent keys in order, `None` if removed.
+    fn sorted_recent(&self) -> vec::IntoIter<(String, Option<CommandMeta>)> {
+        let mut recent: Vec<_> = self
+            .recent
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        recent.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        recent.into_iter()
+    }
+
+    /// Write a new index, merging the current one and its segments with
+    /// recent keys, in key order, each at the location `f` moves its record
+    /// to. The current index stays in use until `commit_relocation`, as the
+    /// files records are moved to are not flushed yet.
+    pub(crate) fn relocate<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&CommandMeta) -> Result<CommandMeta>,
+    {
+        let old = self.sorted.read().unwrap().clone();
+        let mut writer = SortedWriter::create(&self.dir, INDEX_HEADER_SIZE)?;
+        merge(
+            Entries::new(old, None)?,
+            self.sorted_recent(),
+            |key, meta| match meta {
+                Some(meta) => writer.push(key, Some(&f(&meta)?)),
+                None => Ok(()),
+            },
+        )?;
+        *self.pending.lock().unwrap() = Some(writer.finish(None, 0)?);
+        Ok(())
+    }
+
+    /// Put the index written by `relocate` in use, as that of every file
+    /// up to `id`, recent keys and segments being part of it.
+    pub(crate) fn commit_relocation(&self, id: u64) -> Result<()> {
+        let mut new = match self.pending.lock().unwrap().take() {
+            Some(new) => new,
+            None => return Ok(()),
+        };
+        new.len = new.count;
+        new.publish(&self.dir, id)?;
+        let mut sorted = self.sorted.write().unwrap();
+        self.len.store(new.count, Ordering::SeqCst);
+        let old = std::mem::replace(&mut *sorted, vec![Arc::new(new)]);
+        self.recent.clear();
+        drop(sorted);
+        self.remove_files(&old);
+        Ok(())
+    }
+
+    /// Write recent keys into a segment on top of the index, as that of
+    /// every file up to `id`, and drop them from memory. Writes must wait
+    /// meanwhile. Once `MAX_SEGMENTS` pile up, they are merged into one.
+    pub(crate) fn flush(&self, id: u64) -> Result<()> {
+        let current = self.sorted.read().unwrap().clone();
+        let merged = match current.len() > MAX_SEGMENTS {
+            true => current[1..].to_vec(),
+            false => Vec::new(),
+        };
+        let below = current[current.len() - merged.len() - 1].id;
+        let mut writer = SortedWriter::create(&self.dir, SEGMENT_HEADER_SIZE)?;
+        // removed keys are kept, the index below may still hold them
+        merge(
+            Entries::new(merged.clone(), None)?,
+            self.sorted_recent(),
+            |key, meta| writer.push(key, meta.as_ref()),
+        )?;
+        let mut new = writer.finish(Some(below), self.len() as u64)?;
+        new.publish(&self.dir, id)?;
+
+        let mut sorted = self.sorted.write().unwrap();
+        let kept = sorted.len() - merged.len();
+        sorted.truncate(kept);
+        sorted.push(Arc::new(new));
+        self.recent.clear();
+        drop(sorted);
+        self.remove_files(&merged);
+        Ok(())
+    }
+
+    fn remove_files(&self, files: &[Arc<SortedFile>]) {
+        for file in files.iter().filter(|file| file.id > 0) {
+            let path = self.dir.join(index_file_of(file.id));
+            if let Err(e) = fs::remove_file(&path) {
+                error!("{:?} cannot be removed, cause {}", path, e);
+            }
+        }
+    }
+
+    pub(crate) fn memory(&self) -> usize {
+        let keys: usize = self.recent.iter().map(|e| e.key().capacity()).sum();
+        let recent =
+            self.recent.capacity() * (size_of::<(String, Option<CommandMeta>)>() + 1) + keys;
+        let sorted = self.sorted.read().unwrap();
+        let fences: usize = sorted
+            .iter()
+            .map(|file| {
+                let keys: usize = file.fences.iter().map(|(key, _)| key.len()).sum();
+                file.fences.capacity() * size_of::<(Box<[u8]>, usize)>() + keys
+            })
+            .sum();
+        recent + fences
+    }
+}
+
+// Entries of index files merged with recent keys in key order, recent keys
+// shadowing those of the files. `f` is given removed keys too.
+fn merge<F>(
+    mut entries: Entries,
+    mut recent: vec::IntoIter<(String, Option<CommandMeta>)>,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(&[u8], Option<CommandMeta>) -> Result<()>,
+{
+    let mut old_next = entries.next()?;
+    let mut recent_next = recent.next();
+    loop {
+        let take_recent = match (&old_next, &recent_next) {
+            (None, None) => return Ok(()),
+            (None, Some(_)) => true,
+            (Some(_), None) => false,
+            (Some((old_key, _)), Some((key, _))) => key.as_bytes() <= &**old_key,
+        };
+        if take_recent {
+            let (key, meta) = recent_next.take().unwrap();
+            if matches!(&old_next, Some((old_key, _)) if **old_key == *key.as_bytes()) {
+                old_next = entries.next()?;
+            }
+            f(key.as_bytes(), meta)?;
+            recent_next = recent.next();
+        } else {
+            let (key, meta) = old_next.take().unwrap();
+            f(&key, meta)?;
+            old_next = entries.next()?;
+        }
+    }
+}
+
+// a key of an index file and its location, `None` if removed
+type Entry = (Box<[u8]>, Option<CommandMeta>);
+
+// Entries of index files in key order, those of a file shadowing
+// the same keys in the files before it.
+struct Entries {
+    files: Vec<Arc<SortedFile>>,
+    // offset of the next entry of each file
+    at: Vec<usize>,
+}
+
+impl Entries {
+    // Entries following `after`, or every entry.
+    fn new(files: Vec<Arc<SortedFile>>, after: Option<&[u8]>) -> Result<Entries> {
+        let at = files
+            .iter()
+            .map(|file| match after {
+                Some(after) => file.seek(after),
+                None => Ok(file.start),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Entries { files, at })
+    }
+
+    // The least key left, `None` as its location if removed.
+    fn next(&mut self) -> Result<Option<Entry>> {
+        let Entries { files, at } = self;
+        let mut next = Vec::with_capacity(files.len());
+        let mut least: Option<(&[u8], Option<CommandMeta>)> = None;
+        for (file, &file_at) in files.iter().zip(at.iter()) {
+            let mut entry_at = file_at;
+            let entry = file.next_entry(&mut entry_at)?;
+            if let Some((key, meta)) = &entry {
+                if least.as_ref().is_none_or(|(least_key, _)| key <= least_key) {
+                    least = Some((key, meta.clone()));
+                }
+            }
+            next.push(entry.map(|(key, _)| (key, entry_at)));
+        }
+        let (key, meta) = match least {
+            Some((key, meta)) => (Box::<[u8]>::from(key), meta),
+            None => return Ok(None),
+        };
+        for (file_at, next) in at.iter_mut().zip(next) {
+            if let Some((entry_key, entry_at)) = next {
+                if entry_key == &*key {
+                    *file_at = entry_at;
+                }
+            }
+        }
+        Ok(Some((key, meta)))
+    }
+}
+
+/// Keys of a `DiskIndex`, its files being kept mapped until dropped.
+pub(crate) struct DiskKeys {
+    entries: Entries,
+    // the next key of the index files, `false` if removed
+    head: Option<(Box<[u8]>, bool)>,
+    // keys changed since the index was written, in order, `false` if removed
+    recent: Peekable<vec::IntoIter<(String, bool)>>,
+    // a corrupted index is not read any further
+    failed: bool,
+}
+
+impl DiskKeys {
+    fn next_key(&mut self) -> Result<Option<String>> {
+        loop {
+            if self.head.is_none() {
+                self.head = self
+                    .entries
+                    .next()?
+                    .map(|(key, meta)| (key, meta.is_some()));
+            }
+            let take_recent = match (&self.head, self.recent.peek()) {
+                (None, None) => return Ok(None),
+                (None, Some(_)) => true,
+                (Some(_), None) => false,
+                (Some((key, _)), Some((recent_key, _))) => recent_key.as_bytes() <= &**key,
+            };
+            if !take_recent {
+                let (key, live) = self.head.take().unwrap();
+                if live {
+                    return Ok(Some(String::from_utf8(key.into())?));
+                }
+                continue;
+            }
+            let (key, live) = self.recent.next().unwrap();
+            // recent keys shadow those of the index
+            if matches!(&self.head, Some((head, _)) if **head == *key.as_bytes()) {
+                self.head = None;
+            }
+            if live {
+                return Ok(Some(key));
+            }
+        }
+    }
+}
+
+impl Iterator for DiskKeys {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        if self.failed {
+            return None;
+        }
+        let key = self.next_key();
+        self.failed = key.is_err();
+        key.transpose()
+    }
+}
+
+// An index file: a header, then entries sorted by key, each of them the key
+// prefixed by its length as a varint, and the location as u64s. The index
+// of a compaction holds every key, a segment the keys changed in the files
+// it covers, removed ones included.
+struct SortedFile {
+    // id of the last file indexed, 0 if none
+    id: u64,
+    // for a segment, the id of the index file below it
+    below: Option<u64>,
+    map: Option<Mmap>,
+    // offset of the first entry
+    start: usize,
+    count: u64,
+    // live keys of the index, this segment and those below included
+    len: u64,
+    // every FENCE_INTERVAL-th key, and the offset of its entry
+    fences: Vec<(Box<[u8]>, usize)>,
+}
+
+impl SortedFile {
+    fn empty() -> SortedFile {
+        SortedFile {
+            id: 0,
+            below: None,
+            map: None,
+            start: INDEX_HEADER_SIZE,
+            count: 0,
+            len: 0,
+            fences: Vec::new(),
+        }
+    }
+
+    fn open(path: &Path, id: u64) -> Result<SortedFile> {
+        let file = File::open(path)?;
+        // Index files are never written again once renamed in place.
+        let map = unsafe { Mmap::map(&file)? };
+        let magic = map.get(..8).ok_or_else(corrupted)?;
+        let (below, start, len) = if magic == INDEX_MAGIC {
+            (None, INDEX_HEADER_SIZE, read_u64(&map, 8)?)
+        } else if magic == SEGMENT_MAGIC {
+            let below = read_u64(&map, 16)?;
+            (Some(below), SEGMENT_HEADER_SIZE, read_u64(&map, 24)?)
+        } else {
+            return Err(corrupted().into());
+        };
+        if map.len() < start {
+            return Err(corrupted().into());
+        }
+        let mut sorted = SortedFile {
+            id,
+            below,
+            count: read_u64(&map, 8)?,
+            map: Some(map),
+            start,
+            len,
+            fences: Vec::new(),
+        };
+        let mut at = start;
+        let mut fences = Vec::new();
+        for i in 0.. {
+            let entry_at = at;
+            match sorted.next_entry(&mut at)? {
+                Some((key, _)) if i % FENCE_INTERVAL == 0 => fences.push((key.into(), entry_at)),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        sorted.fences = fences;
+        Ok(sorted)
+    }
+
+    // The entry of `key`, if the file has one, `None` inside if removed.
+    fn get(&self, key: &[u8]) -> Result<Option<Option<CommandMeta>>> {
+        // the last fence not after the key
+        let i = self
+            .fences
+            .partition_point(|(fence, _)| fence.as_ref() <= key);
+        if i == 0 {
+            return Ok(None);
+        }
+        let mut at = self.fences[i - 1].1;
+        for _ in 0..FENCE_INTERVAL {
+            match self.next_entry(&mut at)? {
+                Some((entry_key, meta)) if entry_key == key => return Ok(Some(meta)),
+                Some((entry_key, _)) if entry_key < key => {}
+                _ => break,
+            }
+        }
+        Ok(None)
+    }
+
+    // Offset of the first entry after `key`, starting from the last fence
+    // not after it.
+    fn seek(&self, key: &[u8]) -> Result<usize> {
+        let i = self
+            .fences
+            .partition_point(|(fence, _)| fence.as_ref() <= key);
+        let mut at = match i {
+            0 => self.start,
+            _ => self.fences[i - 1].1,
+        };
+        loop {
+            let mut next = at;
+            match self.next_entry(&mut next)? {
+                Some((entry_key, _)) if entry_key <= key => at = next,
+                _ => return Ok(at),
+            }
+        }
+    }
+
+    // The entry at `at`, moving it past the entry.
+    fn next_entry(&self, at: &mut usize) -> Result<Option<(&[u8], Option<CommandMeta>)>> {
+        let map = match &self.map {
+            Some(map) if *at < map.len() => map,
+            _ => return Ok(None),
+        };
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            let byte = *map.get(*at).ok_or_else(corrupted)?;
+            *at += 1;
+            len |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        let key = map.get(*at..*at + len).ok_or_else(corrupted)?;
+        *at += len;
+        let meta: CommandMeta = (
+            read_u64(map, *at)?,
+            read_u64(map, *at + 8)?,
+            read_u64(map, *at + 16)?,
+            read_u64(map, *at + 24)?,
+        )
+            .into();
+        *at += META_SIZE;
+        // log file ids start at 1
+        Ok(Some((key, (meta.file_id > 0).then_some(meta))))
+    }
+
+    // Rename the written file in place, now that the files it points into are complete.
+    fn publish(&mut self, dir: &Path, id: u64) -> Result<()> {
+        let tmp_path = dir.join(TMP_INDEX_FILE);
+        let mut file = fs::OpenOptions::new().write(true).open(&tmp_path)?;
+        match self.below {
+            None => file.write_all(INDEX_MAGIC)?,
+            Some(_) => file.write_all(SEGMENT_MAGIC)?,
+        }
+        file.write_all(&self.count.to_le_bytes())?;
+        if let Some(below) = self.below {
+            file.write_all(&below.to_le_bytes())?;
+            file.write_all(&self.len.to_le_bytes())?;
+        }
+        file.sync_all()?;
+        let path = dir.join(index_file_of(id));
+        fs::rename(tmp_path, &path)?;
+        self.id = id;
+        self.map = Some(unsafe { Mmap::map(&File::open(path)?)? });
+        Ok(())
+    }
+}
+
+struct SortedWriter {
+    writer: BufWriter<File>,
+    start: usize,
+    at: usize,
+    count: u64,
+    fences: Vec<(Box<[u8]>, usize)>,
+}
+
+impl SortedWriter {
+    // The header is left blank until the file is published.
+    fn create(dir: &Path, header_size: usize) -> Result<SortedWriter> {
+        let mut writer = BufWriter::new(File::create(dir.join(TMP_INDEX_FILE))?);
+        writer.write_all(&vec![0; header_size])?;
+        Ok(SortedWriter {
+            writer,
+            start: header_size,
+            at: header_size,
+            count: 0,
+            fences: Vec::new(),
+        })
+    }
+
+    // `None` for a removed key.
+    fn push(&mut self, key: &[u8], meta: Option<&CommandMeta>) -> Result<()> {
+        if self.count.is_multiple_of(FENCE_INTERVAL) {
+            self.fences.push((key.into(), self.at));
+        }
+        let mut len = key.len();
+        while len >= 0x80 {
+            self.writer.write_all(&[len as u8 | 0x80])?;
+            self.at += 1;
+            len >>= 7;
+        }
+        self.writer.write_all(&[len as u8])?;
+        self.writer.write_all(key)?;
+        let location = meta.map_or([0; 4], |meta| {
+            [meta.file_id, meta.position, meta.size, meta.seq]
+        });
+        for n in location {
+            self.writer.write_all(&n.to_le_bytes())?;
+        }
+        self.at += 1 + key.len() + META_SIZE;
+        self.count += 1;
+        Ok(())
+    }
+
+    // `below` is the index file a segment sits on, `len` the live keys
+    // of the index up to it.
+    fn finish(mut self, below: Option<u64>, len: u64) -> Result<SortedFile> {
+        self.writer.flush()?;
+        Ok(SortedFile {
+            id: 0,
+            below,
+            map: None,
+            start: self.start,
+            count: self.count,
+            len,
+            fences: self.fences,
+        })
+    }
+}
+
+fn read_u64(map: &[u8], at: usize) -> Result<u64> {
+    let bytes = map.get(at..at + 8).ok_or_else(corrupted)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn corrupted() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupted index file")
+}
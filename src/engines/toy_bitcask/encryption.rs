@@ -0,0 +1,113 @@
+use crate::{KvsError, Result};
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use std::{fmt, fs, io, path::Path};
+
+/// Environment variable `kvs` and `kvs-server` take the key from
+/// when no key file is given.
+pub const ENCRYPTION_KEY_ENV: &str = "KVS_ENCRYPTION_KEY";
+
+// AES-GCM nonces are 96 bits, and prefix each sealed value
+const NONCE_LEN: usize = 12;
+
+/// A 256-bit AES-GCM key encrypting values in the log,
+/// written as 64 hex digits in key files and environment variables.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: Aes256Gcm,
+    // tells which key a value is sealed with, without revealing the key
+    id: String,
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl EncryptionKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        let digest = Sha256::new()
+            .chain_update(b"kvs key id")
+            .chain_update(key)
+            .finalize();
+        EncryptionKey {
+            cipher: Aes256Gcm::new(&key.into()),
+            id: hex::encode(&digest[..8]),
+        }
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let mut key = [0; 32];
+        hex::decode_to_slice(hex.trim(), &mut key).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "encryption key must be 64 hex digits",
+            )
+        })?;
+        Ok(Self::new(key))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Self::from_hex(&fs::read_to_string(path)?)
+    }
+
+    /// The key in `ENCRYPTION_KEY_ENV`, if set.
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var(ENCRYPTION_KEY_ENV) {
+            Ok(hex) => Ok(Some(Self::from_hex(&hex)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+
+    // `aad` binds the value to where it is written: opened with anything
+    // else, it fails as if tampered with.
+    pub(crate) fn seal(&self, value: &str, aad: &[u8]) -> Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        let payload = Payload {
+            msg: value.as_bytes(),
+            aad,
+        };
+        sealed.extend(
+            self.cipher
+                .encrypt(&nonce, payload)
+                .map_err(|_| io::Error::other("encryption failed"))?,
+        );
+        Ok(STANDARD.encode(sealed))
+    }
+
+    // Tampered values fail the same way as values sealed with another key,
+    // and so do values moved to another record.
+    pub(crate) fn open(&self, sealed: &str, aad: &[u8]) -> Result<String> {
+        let sealed = STANDARD
+            .decode(sealed)
+            .map_err(|_| KvsError::WrongEncryptionKey)?;
+        if sealed.len() < NONCE_LEN {
+            return Err(KvsError::WrongEncryptionKey);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::from_slice(nonce);
+        let value = self
+            .cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| KvsError::WrongEncryptionKey)?;
+        Ok(String::from_utf8(value)?)
+    }
+}
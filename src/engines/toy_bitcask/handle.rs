@@ -1,8 +1,11 @@
 use crate::Result;
+use lru::LruCache;
 use std::{
     fs::{File, OpenOptions},
     io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
-    path::Path,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
 pub(crate) struct ReadHandle<R>
@@ -66,6 +69,14 @@ where
     }
 }
 
+impl WriteHandle<File> {
+    // Flushed and on disk.
+    pub(crate) fn sync(&mut self) -> io::Result<()> {
+        self.buf_writer.flush()?;
+        self.buf_writer.get_ref().sync_all()
+    }
+}
+
 impl<W> Write for WriteHandle<W>
 where
     W: Seek + Write,
@@ -91,19 +102,59 @@ where
 }
 
 pub(crate) fn reader_of(path: &Path) -> Result<ReadHandle<File>> {
-    Ok(ReadHandle::new(File::open(&path)?))
+    Ok(ReadHandle::new(File::open(path)?))
 }
 
 pub(crate) fn writer_of(path: &Path) -> Result<WriteHandle<File>> {
     Ok(WriteHandle::new(
-        OpenOptions::new()
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(&path)?,
+        OpenOptions::new().append(true).create(true).open(path)?,
     ))
 }
 
-pub(crate) fn open(path: &Path) -> Result<(WriteHandle<File>, ReadHandle<File>)> {
-    Ok((writer_of(&path)?, reader_of(&path)?))
+/// Read handles shared by every handle of a store, namespaces included,
+/// with at most `capacity` files open at once.
+///
+/// A handle is lent out rather than borrowed from the cache: evicting it
+/// only closes the file once the last reader is done with it.
+#[derive(Clone)]
+pub(crate) struct ReadHandleCache {
+    // (directory, file id) -> reader handle
+    handles: Arc<Mutex<LruCache<(PathBuf, u64), SharedReadHandle>>>,
+}
+
+pub(crate) type SharedReadHandle = Arc<Mutex<ReadHandle<File>>>;
+
+impl ReadHandleCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        ReadHandleCache {
+            handles: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    /// The handle of `path`, the file numbered `id` in `dir`, opened if need be.
+    pub(crate) fn get(&self, dir: &Path, id: u64, path: &Path) -> Result<SharedReadHandle> {
+        let mut handles = self.handles.lock().unwrap();
+        let key = (dir.to_path_buf(), id);
+        if let Some(handle) = handles.get(&key) {
+            return Ok(Arc::clone(handle));
+        }
+        let handle = Arc::new(Mutex::new(reader_of(path)?));
+        handles.put(key, Arc::clone(&handle));
+        Ok(handle)
+    }
+
+    /// Close the handles of files in `dir` numbered up to `id`.
+    pub(crate) fn evict_up_to(&self, dir: &Path, id: u64) {
+        let mut handles = self.handles.lock().unwrap();
+        let evicted: Vec<_> = handles
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|(handle_dir, handle_id)| handle_dir == dir && *handle_id <= id)
+            .cloned()
+            .collect();
+        for key in evicted {
+            handles.pop(&key);
+        }
+    }
 }
@@ -0,0 +1,531 @@
+use crate::{
+    engines::{
+        toy_bitcask::{disk_index::DiskIndex, kv::CommandMeta},
+        Keys,
+    },
+    Result,
+};
+use dashmap::DashMap;
+use hashbrown::HashTable;
+use std::{
+    collections::{hash_map::RandomState, BinaryHeap},
+    hash::BuildHasher,
+    io,
+    mem::size_of,
+    ops::Range,
+    path::Path,
+    str,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+// keys are spread over as many independently locked shards
+const SHARD_BITS: u32 = 5;
+
+// arenas are repacked once removed keys take up more than half of them
+const MIN_REPACKED_ARENA: usize = 4096;
+
+/// How the key dir holds keys and record locations in memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexMode {
+    /// Every key owned on its own, along with its location.
+    #[default]
+    Standard,
+    /// Keys packed back to back in arenas, locations in smaller integers.
+    Compact,
+    /// A hash of each key only, checked against the key in the log.
+    /// Every read and write of a key reads its record once more.
+    HashOnly,
+    /// Keys as of the latest compaction in a sorted file next to the log,
+    /// and keys written since in sorted segments on top of it, only the
+    /// latest ones in memory. Reads of older keys search the files.
+    OnDisk,
+}
+
+/// Reads the key of the record at a location.
+pub(crate) type KeyReader = Box<dyn Fn(&CommandMeta) -> Result<String> + Send + Sync>;
+
+/// Key -> location of the latest record setting it.
+pub(crate) enum KeyDir {
+    Standard(DashMap<String, CommandMeta>),
+    Compact(Shards<CompactShard>),
+    HashOnly(Shards<HashShard>, KeyReader),
+    OnDisk(DiskIndex),
+}
+
+impl KeyDir {
+    pub(crate) fn new(mode: IndexMode, dir: &Path, key_reader: KeyReader) -> Result<KeyDir> {
+        Ok(match mode {
+            IndexMode::Standard => KeyDir::Standard(DashMap::new()),
+            IndexMode::Compact => KeyDir::Compact(Shards::new()),
+            IndexMode::HashOnly => KeyDir::HashOnly(Shards::new(), key_reader),
+            IndexMode::OnDisk => KeyDir::OnDisk(DiskIndex::open(dir)?),
+        })
+    }
+
+    /// Id of the last file whose keys are indexed on disk. Keys of
+    /// files up to it are all indexed, those of later files are to be loaded.
+    pub(crate) fn indexed(&self) -> Option<u64> {
+        match self {
+            KeyDir::OnDisk(index) => index.indexed(),
+            _ => None,
+        }
+    }
+
+    /// Keys held in memory by an index on disk, written since its latest file.
+    pub(crate) fn unindexed(&self) -> usize {
+        match self {
+            KeyDir::OnDisk(index) => index.recent_len(),
+            _ => 0,
+        }
+    }
+
+    /// Index the keys held in memory on disk, as those of every file up to `id`.
+    pub(crate) fn flush(&self, id: u64) -> Result<()> {
+        match self {
+            KeyDir::OnDisk(index) => index.flush(id),
+            _ => Ok(()),
+        }
+    }
+
+    /// Call `f` with the location of `key`, which is not relocated meanwhile.
+    pub(crate) fn read<F, R>(&self, key: &str, f: F) -> Result<Option<R>>
+    where
+        F: FnOnce(&CommandMeta) -> Result<R>,
+    {
+        // the shard stays locked until `f` returns
+        match self {
+            KeyDir::Standard(map) => map.get(key).map(|meta| f(meta.value())).transpose(),
+            KeyDir::Compact(shards) => {
+                let (hash, shard) = shards.read(key);
+                shard.get(hash, key).map(|meta| f(&meta)).transpose()
+            }
+            KeyDir::HashOnly(shards, key_reader) => {
+                let (hash, shard) = shards.read(key);
+                let meta = shard.get(hash, key, key_reader)?;
+                meta.map(|meta| f(&meta)).transpose()
+            }
+            KeyDir::OnDisk(index) => index.read(key, f),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Result<Option<CommandMeta>> {
+        self.read(key, |meta| Ok(meta.clone()))
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Point `key` at `meta`, returning its former location.
+    pub(crate) fn insert(&self, key: String, meta: CommandMeta) -> Result<Option<CommandMeta>> {
+        match self {
+            KeyDir::Standard(map) => Ok(map.insert(key, meta)),
+            KeyDir::Compact(shards) => {
+                let (hash, mut shard) = shards.write(&key);
+                shard.insert(hash, &key, &meta, &shards.state)
+            }
+            KeyDir::HashOnly(shards, key_reader) => {
+                let (hash, mut shard) = shards.write(&key);
+                shard.insert(hash, &key, &meta, key_reader)
+            }
+            KeyDir::OnDisk(index) => index.insert(key, meta),
+        }
+    }
+
+    pub(crate) fn remove(&self, key: &str) -> Result<Option<CommandMeta>> {
+        match self {
+            KeyDir::Standard(map) => Ok(map.remove(key).map(|(_, meta)| meta)),
+            KeyDir::Compact(shards) => {
+                let (hash, mut shard) = shards.write(key);
+                Ok(shard.remove(hash, key))
+            }
+            KeyDir::HashOnly(shards, key_reader) => {
+                let (hash, mut shard) = shards.write(key);
+                shard.remove(hash, key, key_reader)
+            }
+            KeyDir::OnDisk(index) => index.remove(key),
+        }
+    }
+
+    /// Keys following `after`, or every key, in ascending order. Keys in
+    /// memory are taken up front and sorted, those indexed on disk are read
+    /// as the iterator goes.
+    pub(crate) fn keys(&self, after: Option<&str>) -> Result<Keys> {
+        if let KeyDir::OnDisk(index) = self {
+            return Ok(Box::new(index.keys(after)?));
+        }
+        let mut keys = Vec::new();
+        self.for_each_key(|key| {
+            if after.is_none_or(|after| key > after) {
+                keys.push(key.to_owned());
+            }
+        })?;
+        keys.sort_unstable();
+        Ok(Box::new(keys.into_iter().map(Ok)))
+    }
+
+    /// The first `limit` keys following `after`, in ascending order.
+    /// Of the keys in memory, only the least ones are kept and sorted.
+    pub(crate) fn first_keys(&self, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        if let KeyDir::OnDisk(index) = self {
+            return index.keys(after)?.take(limit).collect();
+        }
+        // the greatest key kept on top
+        let mut least = BinaryHeap::with_capacity(limit + 1);
+        self.for_each_key(|key| {
+            if after.is_some_and(|after| key <= after) {
+                return;
+            }
+            if least.len() < limit {
+                least.push(key.to_owned());
+            } else if least.peek().is_some_and(|max: &String| key < max.as_str()) {
+                least.pop();
+                least.push(key.to_owned());
+            }
+        })?;
+        Ok(least.into_sorted_vec())
+    }
+
+    // Every key held in memory, in no particular order.
+    fn for_each_key<F: FnMut(&str)>(&self, mut f: F) -> Result<()> {
+        match self {
+            KeyDir::Standard(map) => map.iter().for_each(|e| f(e.key())),
+            KeyDir::Compact(shards) => {
+                for shard in shards.iter() {
+                    shard.for_each_key(&mut f)?;
+                }
+            }
+            KeyDir::HashOnly(shards, key_reader) => {
+                for shard in shards.iter() {
+                    for entry in shard.table.iter() {
+                        f(&key_reader(&entry.meta.unpack())?);
+                    }
+                }
+            }
+            KeyDir::OnDisk(_) => unreachable!("keys on disk are iterated in order"),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            KeyDir::Standard(map) => map.len(),
+            KeyDir::Compact(shards) => shards.iter().map(|shard| shard.table.len()).sum(),
+            KeyDir::HashOnly(shards, _) => shards.iter().map(|shard| shard.table.len()).sum(),
+            KeyDir::OnDisk(index) => index.len(),
+        }
+    }
+
+    /// Replace every location by the one `f` moves its record to.
+    /// A shard is locked while its records are moved, as a read
+    /// holding it may be reading from the former location.
+    /// Locations indexed on disk change in `commit_relocation` only.
+    pub(crate) fn relocate<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&CommandMeta) -> Result<CommandMeta>,
+    {
+        match self {
+            KeyDir::Standard(map) => {
+                for mut entry in map.iter_mut() {
+                    *entry = f(entry.value())?;
+                }
+            }
+            KeyDir::Compact(shards) => {
+                for shard in shards.shards.iter() {
+                    for entry in shard.write().unwrap().table.iter_mut() {
+                        entry.meta = Packed::pack(&f(&entry.meta.unpack())?)?;
+                    }
+                }
+            }
+            KeyDir::HashOnly(shards, _) => {
+                for shard in shards.shards.iter() {
+                    for entry in shard.write().unwrap().table.iter_mut() {
+                        entry.meta = Packed::pack(&f(&entry.meta.unpack())?)?;
+                    }
+                }
+            }
+            KeyDir::OnDisk(index) => index.relocate(f)?,
+        }
+        Ok(())
+    }
+
+    /// Called once the files records are moved to are complete, `id` being
+    /// the last file written.
+    pub(crate) fn commit_relocation(&self, id: u64) -> Result<()> {
+        match self {
+            KeyDir::OnDisk(index) => index.commit_relocation(id),
+            _ => Ok(()),
+        }
+    }
+
+    /// An estimate of the bytes held, allocator overhead aside.
+    pub(crate) fn memory(&self) -> usize {
+        match self {
+            KeyDir::Standard(map) => {
+                let keys: usize = map.iter().map(|e| e.key().capacity()).sum();
+                map.capacity() * (size_of::<(String, CommandMeta)>() + 1) + keys
+            }
+            KeyDir::Compact(shards) => shards
+                .iter()
+                .map(|shard| shard.arena.capacity() + shard.table.allocation_size())
+                .sum(),
+            KeyDir::HashOnly(shards, _) => shards
+                .iter()
+                .map(|shard| shard.table.allocation_size())
+                .sum(),
+            KeyDir::OnDisk(index) => index.memory(),
+        }
+    }
+}
+
+pub(crate) struct Shards<S> {
+    shards: Box<[RwLock<S>]>,
+    state: RandomState,
+}
+
+impl<S: Default> Shards<S> {
+    fn new() -> Self {
+        Shards {
+            shards: (0..1 << SHARD_BITS).map(|_| RwLock::default()).collect(),
+            state: RandomState::new(),
+        }
+    }
+
+    // The top bits are the tag of the entry in its table, and the bottom
+    // ones its bucket, the shard is chosen by the bits in between.
+    fn shard_of(&self, key: &str) -> (u64, &RwLock<S>) {
+        let hash = self.state.hash_one(key.as_bytes());
+        let index = (hash << 7) >> (64 - SHARD_BITS);
+        (hash, &self.shards[index as usize])
+    }
+
+    fn read(&self, key: &str) -> (u64, RwLockReadGuard<'_, S>) {
+        let (hash, shard) = self.shard_of(key);
+        (hash, shard.read().unwrap())
+    }
+
+    fn write(&self, key: &str) -> (u64, RwLockWriteGuard<'_, S>) {
+        let (hash, shard) = self.shard_of(key);
+        (hash, shard.write().unwrap())
+    }
+
+    fn iter(&self) -> impl Iterator<Item = RwLockReadGuard<'_, S>> {
+        self.shards.iter().map(|shard| shard.read().unwrap())
+    }
+}
+
+// A location in 24 bytes rather than 32, aligned to 4 so that
+// it does not pad the entries it is part of.
+#[derive(Clone, Copy)]
+#[repr(C, packed(4))]
+struct Packed {
+    position: u64,
+    seq: u64,
+    file_id: u32,
+    size: u32,
+}
+
+impl Packed {
+    fn pack(meta: &CommandMeta) -> Result<Packed> {
+        Ok(Packed {
+            position: meta.position,
+            seq: meta.seq,
+            file_id: narrow(meta.file_id)?,
+            size: narrow(meta.size)?,
+        })
+    }
+
+    fn unpack(self) -> CommandMeta {
+        (
+            self.file_id as u64,
+            self.position,
+            self.size as u64,
+            self.seq,
+        )
+            .into()
+    }
+}
+
+fn narrow<T: TryInto<u32>>(n: T) -> Result<u32> {
+    n.try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "out of range of the index").into())
+}
+
+#[derive(Default)]
+pub(crate) struct CompactShard {
+    // keys, each prefixed by its length as a varint
+    arena: Vec<u8>,
+    table: HashTable<CompactEntry>,
+    // bytes of the arena taken by removed keys
+    garbage: usize,
+}
+
+struct CompactEntry {
+    // offset of the key in the arena
+    key_at: u32,
+    meta: Packed,
+}
+
+impl CompactShard {
+    fn get(&self, hash: u64, key: &str) -> Option<CommandMeta> {
+        let arena = &self.arena;
+        self.table
+            .find(hash, |entry| key_in(arena, entry.key_at) == key.as_bytes())
+            .map(|entry| entry.meta.unpack())
+    }
+
+    fn insert(
+        &mut self,
+        hash: u64,
+        key: &str,
+        meta: &CommandMeta,
+        state: &RandomState,
+    ) -> Result<Option<CommandMeta>> {
+        let packed = Packed::pack(meta)?;
+        let arena = &mut self.arena;
+        if let Some(entry) = self
+            .table
+            .find_mut(hash, |entry| key_in(arena, entry.key_at) == key.as_bytes())
+        {
+            let old = entry.meta.unpack();
+            entry.meta = packed;
+            return Ok(Some(old));
+        }
+        let key_at = push_key(arena, key.as_bytes())?;
+        let entry = CompactEntry {
+            key_at,
+            meta: packed,
+        };
+        self.table.insert_unique(hash, entry, |entry| {
+            state.hash_one(key_in(arena, entry.key_at))
+        });
+        Ok(None)
+    }
+
+    fn remove(&mut self, hash: u64, key: &str) -> Option<CommandMeta> {
+        let arena = &self.arena;
+        let entry = self
+            .table
+            .find_entry(hash, |entry| key_in(arena, entry.key_at) == key.as_bytes())
+            .ok()?;
+        let (entry, _) = entry.remove();
+        // the key and its length prefix
+        self.garbage += key_span(arena, entry.key_at).end - entry.key_at as usize;
+        if self.garbage > MIN_REPACKED_ARENA && self.garbage > self.arena.len() / 2 {
+            self.repack();
+        }
+        Some(entry.meta.unpack())
+    }
+
+    // Copy the keys left into a new arena, their hashes are unchanged.
+    fn repack(&mut self) {
+        let mut arena = Vec::with_capacity(self.arena.len() - self.garbage);
+        for entry in self.table.iter_mut() {
+            // no larger than the former arena
+            entry.key_at = push_key(&mut arena, key_in(&self.arena, entry.key_at)).unwrap();
+        }
+        self.arena = arena;
+        self.garbage = 0;
+    }
+
+    fn for_each_key<F: FnMut(&str)>(&self, f: &mut F) -> Result<()> {
+        for entry in self.table.iter() {
+            let key = str::from_utf8(key_in(&self.arena, entry.key_at))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            f(key);
+        }
+        Ok(())
+    }
+}
+
+fn push_key(arena: &mut Vec<u8>, key: &[u8]) -> Result<u32> {
+    let at = narrow(arena.len())?;
+    let mut len = key.len();
+    while len >= 0x80 {
+        arena.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    arena.push(len as u8);
+    arena.extend_from_slice(key);
+    Ok(at)
+}
+
+fn key_in(arena: &[u8], at: u32) -> &[u8] {
+    &arena[key_span(arena, at)]
+}
+
+fn key_span(arena: &[u8], at: u32) -> Range<usize> {
+    let (mut i, mut len, mut shift) = (at as usize, 0, 0);
+    loop {
+        let byte = arena[i];
+        i += 1;
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    i..i + len
+}
+
+#[derive(Default)]
+pub(crate) struct HashShard {
+    table: HashTable<HashEntry>,
+}
+
+// Keys of the same hash, if any, have entries of their own.
+struct HashEntry {
+    hash: u64,
+    meta: Packed,
+}
+
+impl HashShard {
+    fn get(&self, hash: u64, key: &str, key_reader: &KeyReader) -> Result<Option<CommandMeta>> {
+        for entry in self.table.iter_hash(hash) {
+            let meta = entry.meta.unpack();
+            if entry.hash == hash && key_reader(&meta)? == key {
+                return Ok(Some(meta));
+            }
+        }
+        Ok(None)
+    }
+
+    fn insert(
+        &mut self,
+        hash: u64,
+        key: &str,
+        meta: &CommandMeta,
+        key_reader: &KeyReader,
+    ) -> Result<Option<CommandMeta>> {
+        let packed = Packed::pack(meta)?;
+        for entry in self.table.iter_hash_mut(hash) {
+            let old = entry.meta.unpack();
+            if entry.hash == hash && key_reader(&old)? == key {
+                entry.meta = packed;
+                return Ok(Some(old));
+            }
+        }
+        let entry = HashEntry { hash, meta: packed };
+        self.table.insert_unique(hash, entry, |entry| entry.hash);
+        Ok(None)
+    }
+
+    fn remove(
+        &mut self,
+        hash: u64,
+        key: &str,
+        key_reader: &KeyReader,
+    ) -> Result<Option<CommandMeta>> {
+        let meta = match self.get(hash, key, key_reader)? {
+            Some(meta) => meta,
+            None => return Ok(None),
+        };
+        let same = |entry: &HashEntry| {
+            entry.meta.file_id as u64 == meta.file_id && entry.meta.position == meta.position
+        };
+        if let Ok(entry) = self.table.find_entry(hash, same) {
+            entry.remove();
+        }
+        Ok(Some(meta))
+    }
+}
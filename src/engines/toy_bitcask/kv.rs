@@ -1,6 +1,23 @@
 use crate::{
-    engines::toy_bitcask::handle::{open, reader_of, writer_of, ReadHandle, WriteHandle},
-    KvsEngine, KvsError, Result,
+    common::{Page, WatchEvent},
+    engines::{
+        check_tree_name, ensure_empty_dir, scan_keys, set_engine_type,
+        toy_bitcask::{
+            cache::{CacheStats, ValueCache},
+            changes::{Changes, Tail},
+            compression::Compression,
+            disk_index::index_file_of,
+            encryption::EncryptionKey,
+            handle::{reader_of, writer_of, ReadHandle, ReadHandleCache, WriteHandle},
+            key_dir::{IndexMode, KeyDir},
+            manifest::{read_manifest, write_manifest},
+            mapped::MappedFiles,
+            publisher::Publisher,
+            usage::{LogFileStats, Usage},
+        },
+        ChangeFeed, Events, Keys, Pairs,
+    },
+    EngineType, KvsEngine, KvsError, Result, Transaction,
 };
 use chrono::Utc;
 use dashmap::DashMap;
@@ -8,61 +25,423 @@ use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 use std::{
     cell::RefCell,
-    collections::BTreeMap,
+    collections::{btree_map, hash_map, BTreeMap, BTreeSet, HashMap, HashSet},
     ffi::OsStr,
-    fs::{self, File},
+    fs::{self, File, OpenOptions, TryLockError},
     io::{self, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc, Mutex,
+        Arc, Mutex, Weak,
     },
 };
 
+// dead bytes of the files worth merging, beyond those a merge
+// left behind, from which they are merged
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024; // 1MB
 
+const SEQ_FLOOR_FILE: &str = "floor";
+
+// held locked by every store open on the directory
+const LOCK_FILE: &str = "LOCK";
+
+// namespaces are stores of their own in its subdirectories
+const NAMESPACE_DIR: &str = "ns";
+
+// values shorter than this are not worth compressing by default
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+const DEFAULT_MAX_OPEN_FILES: usize = 64;
+
+const DEFAULT_MERGE_GARBAGE_RATIO: f64 = 0.5;
+
+const DEFAULT_MERGE_FILE_SIZE: u64 = 16 * 1024 * 1024; // 16MB
+
+const DEFAULT_MAX_UNINDEXED_KEYS: usize = 100_000;
+
+#[derive(Debug, Clone)]
+pub struct StoreOption {
+    // number of latest mutations that compaction keeps available to `changes_since`
+    pub history_retention: u64,
+    // codec of values written from now on, compaction recompresses older ones
+    pub compression: Compression,
+    // values shorter than this many bytes are stored as they are
+    pub compression_threshold: usize,
+    // key encrypting values written from now on, compaction re-encrypts older ones
+    pub encryption_key: Option<EncryptionKey>,
+    // former keys, needed to read values until compaction re-encrypts them
+    pub old_encryption_keys: Vec<EncryptionKey>,
+    // read handles kept open at most, by every handle of the store together
+    pub max_open_files: usize,
+    // bytes of recently read keys and values kept in memory, none if zero
+    pub value_cache_size: usize,
+    // how keys are held in memory
+    pub index_mode: IndexMode,
+    // share of dead bytes from which a log file is merged, other files are left in place
+    pub merge_garbage_ratio: f64,
+    // files written by a merge are cut once this many bytes long
+    pub merge_file_size: u64,
+    // with keys on disk, keys written since they were last indexed kept in
+    // memory at most, the others are written to a new index segment
+    pub max_unindexed_keys: usize,
+    // nothing is written, and other read-only handles may share the directory
+    pub read_only: bool,
+}
+
+impl Default for StoreOption {
+    fn default() -> Self {
+        StoreOption {
+            history_retention: 0,
+            compression: Compression::None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            encryption_key: None,
+            old_encryption_keys: Vec::new(),
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            value_cache_size: 0,
+            index_mode: IndexMode::Standard,
+            merge_garbage_ratio: DEFAULT_MERGE_GARBAGE_RATIO,
+            merge_file_size: DEFAULT_MERGE_FILE_SIZE,
+            max_unindexed_keys: DEFAULT_MAX_UNINDEXED_KEYS,
+            read_only: false,
+        }
+    }
+}
+
+/// Figures about a namespace of a store.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StoreStats {
+    /// Number of live keys.
+    pub keys: u64,
+    /// Estimated bytes held in memory by the key dir.
+    pub index_bytes: u64,
+    /// Number of log files.
+    pub log_files: u64,
+    /// Bytes of the log files.
+    pub log_bytes: u64,
+    /// Bytes of the records live keys point at.
+    pub live_bytes: u64,
+    /// Bytes of every other record, removes included.
+    pub dead_bytes: u64,
+    /// Dead bytes of the files the next compaction merges.
+    pub reclaimable_bytes: u64,
+    /// Usage of every log file, in id order.
+    pub files: Vec<LogFileStats>,
+    /// Compactions run since the store was opened.
+    pub compactions: u64,
+    /// Bytes written by those compactions.
+    pub compacted_bytes: u64,
+}
+
+impl StoreStats {
+    pub fn index_bytes_per_key(&self) -> f64 {
+        if self.keys == 0 {
+            return 0.0;
+        }
+        self.index_bytes as f64 / self.keys as f64
+    }
+}
+
+// How values are written in the log: compressed, then encrypted.
+// Keys are left in the clear, the key dir is built from them.
+pub(crate) struct Codec {
+    compression: Compression,
+    compression_threshold: usize,
+    key: Option<EncryptionKey>,
+    // id -> every key values may be sealed with
+    keys: HashMap<String, EncryptionKey>,
+}
+
+impl Codec {
+    fn new(option: &StoreOption) -> Codec {
+        let keys = option
+            .encryption_key
+            .iter()
+            .chain(&option.old_encryption_keys)
+            .map(|key| (key.id().to_owned(), key.clone()))
+            .collect();
+        Codec {
+            compression: option.compression,
+            compression_threshold: option.compression_threshold,
+            key: option.encryption_key.clone(),
+            keys,
+        }
+    }
+
+    // Checked for every record on open, so that a missing key fails early.
+    fn key_of(&self, id: &str) -> Result<&EncryptionKey> {
+        self.keys.get(id).ok_or(KvsError::WrongEncryptionKey)
+    }
+
+    fn check(&self, cmd: &Command) -> Result<()> {
+        if let Command::Set {
+            key_id: Some(id), ..
+        } = cmd
+        {
+            self.key_of(id)?;
+        }
+        Ok(())
+    }
+
+    // Whether a record is worth merging for the current codec to apply
+    // to it: encrypted with another key, or compressed another way.
+    // Values left uncompressed are compressed whenever merged anyway.
+    fn is_outdated(&self, cmd: &Command) -> bool {
+        match cmd {
+            Command::Set {
+                compression,
+                key_id,
+                ..
+            } => {
+                (!compression.is_none() && *compression != self.compression)
+                    || key_id.as_deref() != self.key.as_ref().map(EncryptionKey::id)
+            }
+            Command::Remove { .. } | Command::Tombstone { .. } => false,
+        }
+    }
+
+    // whether a record is written the way it would be now
+    fn is_current(&self, cmd: &Command) -> bool {
+        match cmd {
+            Command::Set {
+                compression,
+                key_id,
+                ..
+            } => {
+                *compression == self.compression
+                    && key_id.as_deref() == self.key.as_ref().map(EncryptionKey::id)
+            }
+            Command::Remove { .. } | Command::Tombstone { .. } => true,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) enum Command {
     Set {
+        // logs written before sequence numbers were introduced carry none
+        #[serde(default)]
+        seq: u64,
         timestamp: i64,
         key: String,
         value: String,
+        // how `value` is compressed, records of any kind mix in a file
+        #[serde(default, skip_serializing_if = "Compression::is_none")]
+        compression: Compression,
+        // id of the key `value` is encrypted with, if any
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_id: Option<String>,
+        // commands of the same transaction logged right after this one,
+        // a transaction takes effect once its last command is logged
+        #[serde(default, skip_serializing_if = "is_zero")]
+        txn_left: u64,
     },
     Remove {
+        #[serde(default)]
+        seq: u64,
+        timestamp: i64,
+        key: String,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        txn_left: u64,
+    },
+    // A remove kept by merges for as long as a file left in place may hold
+    // an older record setting its key. Not a mutation of its own.
+    Tombstone {
+        seq: u64,
         timestamp: i64,
         key: String,
     },
 }
 
+// What an encrypted value is bound to: a value copied into the record of
+// another key, or of another mutation, no longer opens.
+fn associated_data(key: &str, seq: u64) -> Vec<u8> {
+    let mut aad = seq.to_le_bytes().to_vec();
+    aad.extend_from_slice(key.as_bytes());
+    aad
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
 impl Command {
-    pub(crate) fn set(key: String, value: String) -> Command {
-        return Self::Set {
+    pub(crate) fn set(seq: u64, key: String, value: String) -> Command {
+        Self::Set {
+            seq,
             timestamp: Utc::now().timestamp(),
             key,
             value,
-        };
+            compression: Compression::None,
+            key_id: None,
+            txn_left: 0,
+        }
     }
-    pub(crate) fn remove(key: String) -> Command {
-        return Self::Remove {
+    pub(crate) fn remove(seq: u64, key: String) -> Command {
+        Self::Remove {
+            seq,
             timestamp: Utc::now().timestamp(),
             key,
+            txn_left: 0,
+        }
+    }
+    pub(crate) fn seq(&self) -> u64 {
+        match self {
+            Command::Set { seq, .. }
+            | Command::Remove { seq, .. }
+            | Command::Tombstone { seq, .. } => *seq,
+        }
+    }
+    pub(crate) fn txn_left(&self) -> u64 {
+        match self {
+            Command::Set { txn_left, .. } | Command::Remove { txn_left, .. } => *txn_left,
+            Command::Tombstone { .. } => 0,
+        }
+    }
+    fn set_txn_left(&mut self, left: u64) {
+        match self {
+            Command::Set { txn_left, .. } | Command::Remove { txn_left, .. } => *txn_left = left,
+            Command::Tombstone { .. } => {}
+        }
+    }
+
+    // The tombstone of a remove, or a copy of a tombstone.
+    fn to_tombstone(&self) -> Option<Command> {
+        match self {
+            Command::Set { .. } => None,
+            Command::Remove {
+                seq,
+                timestamp,
+                key,
+                ..
+            }
+            | Command::Tombstone {
+                seq,
+                timestamp,
+                key,
+            } => Some(Command::Tombstone {
+                seq: *seq,
+                timestamp: *timestamp,
+                key: key.clone(),
+            }),
+        }
+    }
+
+    // A copy with the value encoded by `codec`, if it changes anything.
+    // Values are compressed if at least the threshold long and it makes them
+    // shorter.
+    fn encode(&self, codec: &Codec) -> Result<Option<Command>> {
+        let Command::Set {
+            seq,
+            timestamp,
+            key,
+            value,
+            compression: Compression::None,
+            key_id: None,
+            txn_left,
+        } = self
+        else {
+            return Ok(None);
+        };
+        let mut encoded = None;
+        let mut compression = Compression::None;
+        if !codec.compression.is_none() && value.len() >= codec.compression_threshold {
+            let compressed = codec.compression.compress(value)?;
+            if compressed.len() < value.len() {
+                encoded = Some(compressed);
+                compression = codec.compression;
+            }
+        }
+        let key_id = match &codec.key {
+            Some(encryption_key) => {
+                let sealed = encryption_key.seal(
+                    encoded.as_deref().unwrap_or(value),
+                    &associated_data(key, *seq),
+                )?;
+                encoded = Some(sealed);
+                Some(encryption_key.id().to_owned())
+            }
+            None => None,
         };
+        Ok(encoded.map(|value| Command::Set {
+            seq: *seq,
+            timestamp: *timestamp,
+            key: key.clone(),
+            value,
+            compression,
+            key_id,
+            txn_left: *txn_left,
+        }))
+    }
+
+    pub(crate) fn decode(self, codec: &Codec) -> Result<Command> {
+        match self {
+            Command::Set {
+                seq,
+                timestamp,
+                key,
+                mut value,
+                compression,
+                key_id,
+                txn_left,
+            } => {
+                if let Some(id) = key_id {
+                    value = codec
+                        .key_of(&id)?
+                        .open(&value, &associated_data(&key, seq))?;
+                }
+                if !compression.is_none() {
+                    value = compression.decompress(&value)?;
+                }
+                Ok(Command::Set {
+                    seq,
+                    timestamp,
+                    key,
+                    value,
+                    compression: Compression::None,
+                    key_id: None,
+                    txn_left,
+                })
+            }
+            cmd => Ok(cmd),
+        }
+    }
+}
+
+impl From<Command> for WatchEvent {
+    fn from(cmd: Command) -> Self {
+        match cmd {
+            Command::Set {
+                timestamp,
+                key,
+                value,
+                ..
+            } => WatchEvent::Set {
+                key,
+                value,
+                timestamp,
+            },
+            Command::Remove { timestamp, key, .. } | Command::Tombstone { timestamp, key, .. } => {
+                WatchEvent::Remove { key, timestamp }
+            }
+        }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct CommandMeta {
     pub file_id: u64,  // id of log file where command is saved
     pub position: u64, // position of command in log file
     pub size: u64,     // size of command
+    pub seq: u64,      // sequence number of command, the version of the key
 }
 
-impl From<(u64, u64, u64)> for CommandMeta {
-    fn from((file_id, value_pos, value_size): (u64, u64, u64)) -> Self {
+impl From<(u64, u64, u64, u64)> for CommandMeta {
+    fn from((file_id, value_pos, value_size, seq): (u64, u64, u64, u64)) -> Self {
         CommandMeta {
             file_id,
             position: value_pos,
             size: value_size,
+            seq,
         }
     }
 }
@@ -72,11 +451,45 @@ impl From<(u64, u64, u64)> for CommandMeta {
 pub struct KvStore {
     dir: Arc<PathBuf>,
     // key dir
-    key_dir: Arc<DashMap<String, CommandMeta>>,
+    key_dir: Arc<KeyDir>,
     // log
     stable_log: StableLog,
     // writer
     active_log: Arc<Mutex<ActiveLog>>,
+    // watchers
+    publisher: Publisher,
+    // namespaces of the store, the same for every handle
+    namespaces: Option<Arc<Namespaces>>,
+    // versions replaced while read views are open
+    undo: Arc<DashMap<String, Vec<Version>>>,
+}
+
+// A version of a key, current until the mutation numbered `until`.
+#[derive(Debug, Clone)]
+struct Version {
+    until: u64,
+    // `None` if the key was missing
+    meta: Option<CommandMeta>,
+}
+
+// Stores kept here have no namespaces of their own, so that they do not
+// keep themselves alive.
+struct Namespaces {
+    // directory of the default namespace
+    dir: PathBuf,
+    root: Mutex<KvStore>,
+    option: StoreOption,
+    trees: Mutex<HashMap<String, KvStore>>,
+    caches: Caches,
+    // unlocked once the last handle is dropped
+    _lock: File,
+}
+
+// Caches shared by every namespace of a store.
+#[derive(Clone)]
+struct Caches {
+    read_handles: ReadHandleCache,
+    values: ValueCache,
 }
 
 impl KvStore {
@@ -84,90 +497,425 @@ impl KvStore {
     where
         T: Into<PathBuf>,
     {
-        let dir = Arc::new(dir.into());
-        fs::create_dir_all(dir.as_ref())?;
+        Self::open_with(dir, StoreOption::default())
+    }
+
+    pub fn open_with<T>(dir: T, option: StoreOption) -> Result<KvStore>
+    where
+        T: Into<PathBuf>,
+    {
+        let dir = dir.into();
+        let lock = lock_dir(&dir, option.read_only)?;
+        let caches = Caches {
+            read_handles: ReadHandleCache::new(option.max_open_files),
+            values: ValueCache::new(option.value_cache_size),
+        };
+        let mut store = Self::load(dir, &option, &caches)?;
+        store.namespaces = Some(Arc::new(Namespaces {
+            dir: store.dir.to_path_buf(),
+            root: Mutex::new(store.clone()),
+            option,
+            trees: Mutex::new(HashMap::new()),
+            caches,
+            _lock: lock,
+        }));
+        Ok(store)
+    }
+
+    fn load(dir: PathBuf, option: &StoreOption, caches: &Caches) -> Result<KvStore> {
+        let dir = Arc::new(dir);
+        let codec = Arc::new(Codec::new(option));
+        if !option.read_only {
+            fs::create_dir_all(dir.as_ref())?;
+        }
+
+        let seq_floor = read_seq_floor(&dir)?;
+        let mut seq = seq_floor;
+        let mut file_seqs = BTreeMap::new();
+        let file_ids = live_log_files(&dir, option.read_only)?;
+        for id in list_history_file_in(&dir)? {
+            let reader = reader_of(&dir.join(history_file_of(id)))?;
+            let mut max_seq = 0;
+            for cmd in Deserializer::from_reader(reader).into_iter::<Command>() {
+                let cmd = cmd?;
+                codec.check(&cmd)?;
+                // the last command of a transaction numbers it up to the end
+                if cmd.txn_left() == 0 {
+                    max_seq = max_seq.max(cmd.seq());
+                }
+            }
+            file_seqs.insert(id, max_seq);
+            seq = seq.max(max_seq);
+        }
 
-        let file_ids = list_log_file_in(&dir)?;
-        let key_dir: DashMap<String, CommandMeta> = DashMap::new();
         let active_file_id = file_ids.last().unwrap_or(&0) + 1;
-        let mut read_handles = BTreeMap::new();
-        let (write_handle, read_handle) = open(&dir.join(log_file_of(active_file_id)))?;
-        read_handles.insert(active_file_id.clone(), read_handle);
-        let mut uncompacted = 0;
+        let write_handle = if option.read_only {
+            None
+        } else {
+            let write_handle = writer_of(&dir.join(log_file_of(active_file_id)))?;
+            write_manifest(&dir, file_ids.iter().copied().chain([active_file_id]))?;
+            Some(write_handle)
+        };
+        let stable_log = StableLog {
+            dir: Arc::clone(&dir),
+            read_handles: caches.read_handles.clone(),
+            values: caches.values.clone(),
+            space: caches.values.new_space(),
+            sealed_id: Arc::new(AtomicU64::new(active_file_id - 1)),
+            mapped: MappedFiles::default(),
+            codec: Arc::clone(&codec),
+        };
+        let key_reader = stable_log.clone();
+        let key_dir = KeyDir::new(
+            option.index_mode,
+            &dir,
+            Box::new(move |meta| key_reader.key_at(meta)),
+        )?;
+        let indexed = key_dir.indexed();
+        let mut usage = Usage::new(option.merge_garbage_ratio);
+        // key -> sequence number of its latest remove, for keys missing
+        let mut removed: HashMap<String, u64> = HashMap::new();
         for &id in &file_ids {
+            // keys of files up to the indexed one are looked up rather than loaded
+            let is_indexed = indexed.is_some_and(|indexed| id <= indexed);
             let mut read_handle = reader_of(&dir.join(log_file_of(id)))?;
             let mut pos = read_handle.seek(SeekFrom::Start(0))?;
             let mut iter = Deserializer::from_reader(&mut read_handle).into_iter::<Command>();
+            let mut max_seq = 0;
+            // commands of a transaction not logged in full yet
+            let mut txn = Vec::new();
             while let Some(cmd) = iter.next() {
                 let new_pos = iter.byte_offset() as u64;
-                match cmd? {
-                    Command::Set { key, .. } => {
-                        let meta = (id.clone(), pos, new_pos - pos).into();
-                        if let Some(old_meta) = key_dir.insert(key, meta) {
-                            uncompacted += old_meta.size;
+                let cmd = cmd?;
+                codec.check(&cmd)?;
+                if codec.is_outdated(&cmd) {
+                    usage.mark_outdated(id);
+                }
+                // a transaction cut short by a crash numbers nothing
+                if cmd.txn_left() == 0 {
+                    max_seq = max_seq.max(cmd.seq());
+                }
+                usage.add_bytes(id, new_pos - pos);
+                if is_indexed {
+                    let is_live = match &cmd {
+                        Command::Set { key, .. } => key_dir
+                            .get(key)?
+                            .is_some_and(|meta| meta.file_id == id && meta.position == pos),
+                        Command::Remove { key, seq, .. } | Command::Tombstone { key, seq, .. } => {
+                            // for records of later files to be checked against
+                            if !key_dir.contains_key(key)? {
+                                let removed_seq = removed.entry(key.clone()).or_default();
+                                *removed_seq = (*removed_seq).max(*seq);
+                            }
+                            false
                         }
+                    };
+                    if !is_live {
+                        usage.add_dead(id, new_pos - pos);
                     }
-                    Command::Remove { key, .. } => {
-                        if let Some((_, old_meta)) = key_dir.remove(&key) {
-                            uncompacted += old_meta.size;
+                    pos = new_pos;
+                    continue;
+                }
+                let txn_left = cmd.txn_left();
+                txn.push((cmd, pos, new_pos - pos));
+                pos = new_pos;
+                if txn_left > 0 {
+                    continue;
+                }
+                // Records apply in sequence order whatever file they are in,
+                // a leftover of an interrupted merge changes nothing.
+                for (cmd, pos, len) in txn.drain(..) {
+                    match cmd {
+                        Command::Set { key, seq, .. } => {
+                            if !is_latest(&key_dir, &removed, &key, seq)? {
+                                usage.add_dead(id, len);
+                                continue;
+                            }
+                            removed.remove(&key);
+                            let meta = (id, pos, len, seq).into();
+                            if let Some(old_meta) = key_dir.insert(key, meta)? {
+                                usage.add_dead(old_meta.file_id, old_meta.size);
+                            }
+                        }
+                        Command::Remove { key, seq, .. } | Command::Tombstone { key, seq, .. } => {
+                            // removes hold no value, and are dead from the start
+                            usage.add_dead(id, len);
+                            if !is_latest(&key_dir, &removed, &key, seq)? {
+                                continue;
+                            }
+                            if let Some(old_meta) = key_dir.remove(&key)? {
+                                usage.add_dead(old_meta.file_id, old_meta.size);
+                            }
+                            removed.insert(key, seq);
                         }
-                        uncompacted += new_pos - pos; // add 'remove' cmd itself which will be compacted next time
                     }
                 }
-                pos = new_pos;
             }
-            read_handles.insert(id, read_handle);
+            // a transaction cut short by a crash never happened
+            usage.add_dead(id, txn.iter().map(|(_, _, len)| len).sum());
+            file_seqs.insert(id, max_seq);
+            seq = seq.max(max_seq);
         }
         let key_dir = Arc::new(key_dir);
-        let stable_log = StableLog {
-            dir: Arc::clone(&dir),
-            read_handles: RefCell::new(read_handles),
-            compacted_id: Arc::new(AtomicU64::new(0)),
-        };
+        let undo = Arc::new(DashMap::new());
+        let publisher = Publisher::default();
         let active_log = ActiveLog {
             dir: Arc::clone(&dir),
             file_id: active_file_id,
             write_handle,
             key_dir: Arc::clone(&key_dir),
-            uncompacted,
+            usage,
+            merge_file_size: option.merge_file_size,
+            max_unindexed_keys: option.max_unindexed_keys,
+            compactions: 0,
+            compacted_bytes: 0,
+            unreclaimed: 0,
             stable_log: stable_log.clone(),
+            publisher: publisher.clone(),
+            seq,
+            seq_floor,
+            file_seqs,
+            history_retention: option.history_retention,
+            undo: Arc::clone(&undo),
+            views: BTreeMap::new(),
+            doomed: Vec::new(),
         };
 
-        // read_handles.insert(active_file_id, read_handle);
         Ok(KvStore {
             dir: Arc::clone(&dir),
             key_dir: Arc::clone(&key_dir),
             stable_log,
             active_log: Arc::new(Mutex::new(active_log)),
+            publisher,
+            namespaces: None,
+            undo,
         })
     }
+
+    // Every store handed out has them.
+    fn namespaces(&self) -> &Arc<Namespaces> {
+        self.namespaces.as_ref().expect("store without namespaces")
+    }
+
+    /// A consistent view of the store as of now, which later writes and
+    /// compactions do not change. Files it reads from are kept until it is dropped.
+    pub fn read_view(&self) -> ReadView {
+        let mut active_log = self.active_log.lock().unwrap();
+        let seq = active_log.seq;
+        *active_log.views.entry(seq).or_insert(0) += 1;
+        ReadView {
+            store: self.clone(),
+            seq,
+            read_handles: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Figures about this namespace, as of now.
+    pub fn stats(&self) -> StoreStats {
+        let mut stats = StoreStats {
+            keys: self.key_dir.len() as u64,
+            index_bytes: self.key_dir.memory() as u64,
+            ..StoreStats::default()
+        };
+        let active_log = self.active_log.lock().unwrap();
+        stats.files = active_log.usage.stats();
+        for file in &stats.files {
+            stats.log_files += 1;
+            stats.log_bytes += file.bytes;
+            stats.live_bytes += file.live_bytes;
+            stats.dead_bytes += file.dead_bytes;
+        }
+        stats.reclaimable_bytes = active_log.usage.reclaimable();
+        stats.compactions = active_log.compactions;
+        stats.compacted_bytes = active_log.compacted_bytes;
+        stats
+    }
+
+    /// Hits and misses of the value cache, and its size, for the whole store.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.namespaces().caches.values.stats()
+    }
+
+    /// Stream every mutation with a sequence number greater than `seq`,
+    /// up to the latest one committed. Once caught up, the stream ends,
+    /// and goes on from there with later mutations if read again.
+    pub fn changes_since(&self, seq: u64) -> Result<Changes> {
+        let log = Arc::downgrade(&self.active_log);
+        self.active_log.lock().unwrap().changes_since(seq, log)
+    }
 }
 
-fn log_file_of(id: u64) -> String {
+pub(crate) fn log_file_of(id: u64) -> String {
     format!("{}.log", id)
 }
 
+// stale log file kept around for `changes_since`
+fn history_file_of(id: u64) -> String {
+    format!("{}.history", id)
+}
+
 fn list_log_file_in(dir: &Path) -> Result<Vec<u64>> {
-    let mut log_file_ids: Vec<_> = fs::read_dir(&dir)?
+    list_file_in(dir, "log")
+}
+
+fn list_history_file_in(dir: &Path) -> Result<Vec<u64>> {
+    list_file_in(dir, "history")
+}
+
+pub(crate) fn list_file_in(dir: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut file_ids: Vec<_> = fs::read_dir(dir)?
         .flat_map(|entry| -> Result<PathBuf> { Ok(entry?.path()) })
         .filter(|file_path| -> bool {
-            file_path.is_file() && file_path.extension() == Some("log".as_ref())
+            file_path.is_file() && file_path.extension() == Some(extension.as_ref())
         })
         .filter_map(|file_path| -> Option<Option<u64>> {
             file_path
-                .file_name()
+                .file_stem()
                 .and_then(OsStr::to_str)
-                .map(|str| -> Option<u64> { str.trim_end_matches(".log").parse::<u64>().ok() })
+                .map(|str| -> Option<u64> { str.parse::<u64>().ok() })
         })
         .flatten()
         .collect();
 
-    log_file_ids.sort_unstable();
+    file_ids.sort_unstable();
+
+    Ok(file_ids)
+}
+
+// Whether a record numbered `seq` is no older than what is known of its key.
+// Records of logs written before sequence numbers all have 0, and apply in order.
+fn is_latest(
+    key_dir: &KeyDir,
+    removed: &HashMap<String, u64>,
+    key: &str,
+    seq: u64,
+) -> Result<bool> {
+    Ok(match key_dir.get(key)? {
+        Some(meta) => meta.seq <= seq,
+        None => removed
+            .get(key)
+            .is_none_or(|&removed_seq| removed_seq <= seq),
+    })
+}
+
+// Links share the data without copying it, which only works on the same file system.
+fn link_or_copy(src_dir: &Path, dest_dir: &Path, name: &str) -> Result<()> {
+    let (src, dest) = (src_dir.join(name), dest_dir.join(name));
+    if fs::hard_link(&src, &dest).is_err() {
+        fs::copy(&src, &dest)?;
+    }
+    Ok(())
+}
+
+// Values are encoded on their way to the file only.
+fn write_record<W: Write>(writer: W, cmd: &Command, codec: &Codec) -> Result<()> {
+    match cmd.encode(codec)? {
+        Some(encoded) => serde_json::to_writer(writer, &encoded)?,
+        None => serde_json::to_writer(writer, cmd)?,
+    }
+    Ok(())
+}
 
-    Ok(log_file_ids)
+fn value_of(cmd: Command, codec: &Codec) -> Result<Option<String>> {
+    if let Command::Set { value, .. } = cmd.decode(codec)? {
+        Ok(Some(value))
+    } else {
+        Err(KvsError::UnknownCommand)
+    }
+}
+
+fn remove_file(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        error!("{:?} cannot be removed, cause {}", path, e);
+    }
+}
+
+// Log files listed in the manifest. Unless read only, the others are cleaned up:
+// those numbered after every listed one were written by a compaction that did
+// not commit, and are removed. The others were merged by one that did, and
+// are left to history like any merged file.
+fn live_log_files(dir: &Path, read_only: bool) -> Result<Vec<u64>> {
+    let file_ids = list_log_file_in(dir)?;
+    let live = match read_manifest(dir)? {
+        Some(live) => live,
+        None => return Ok(file_ids),
+    };
+    if read_only {
+        return Ok(file_ids
+            .into_iter()
+            .filter(|id| live.contains(id))
+            .collect());
+    }
+    let last_live = live.last().copied().unwrap_or(0);
+    for &id in &file_ids {
+        let log_file_path = dir.join(log_file_of(id));
+        if live.contains(&id) {
+            continue;
+        } else if id < last_live {
+            fs::rename(log_file_path, dir.join(history_file_of(id)))?;
+        } else {
+            remove_file(&log_file_path);
+        }
+    }
+    // an index is only as good as the files its keys point into
+    for id in list_file_in(dir, "index")? {
+        if !live.contains(&id) {
+            remove_file(&dir.join(index_file_of(id)));
+        }
+    }
+    Ok(file_ids
+        .into_iter()
+        .filter(|id| live.contains(id))
+        .collect())
+}
+
+// Lock the directory for as long as the file returned is open: shared by
+// read-only stores, exclusive otherwise.
+fn lock_dir(dir: &Path, read_only: bool) -> Result<File> {
+    if !read_only {
+        fs::create_dir_all(dir)?;
+    }
+    let path = dir.join(LOCK_FILE);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            OpenOptions::new().create(true).append(true).open(&path)?
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let locked = if read_only {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+    match locked {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(KvsError::DirectoryLocked(dir.to_path_buf())),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+fn read_seq_floor(dir: &Path) -> Result<u64> {
+    let path = dir.join(SEQ_FLOOR_FILE);
+    if !path.exists() {
+        return Ok(0);
+    }
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+}
+
+fn write_seq_floor(dir: &Path, floor: u64) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", SEQ_FLOOR_FILE));
+    fs::write(&tmp_path, floor.to_string())?;
+    fs::rename(tmp_path, dir.join(SEQ_FLOOR_FILE))?;
+    Ok(())
 }
 
 impl KvsEngine for KvStore {
+    type Transaction = KvTransaction;
+
     // The user invokes kvs set mykey myvalue
     // kvs creates a value representing the "set" command, containing its key and value
     // It then serializes that command to a String
@@ -188,11 +936,10 @@ impl KvsEngine for KvStore {
     // It prints the value to stdout and exits with exit code 0
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_meta) = self.key_dir.get(&key).as_deref() {
-            self.stable_log.get_value(cmd_meta)
-        } else {
-            Ok(None)
-        }
+        let value = self
+            .key_dir
+            .read(&key, |cmd_meta| self.stable_log.get_value(&key, cmd_meta))?;
+        Ok(value.flatten())
     }
 
     // The user invokes kvs rm mykey
@@ -207,161 +954,864 @@ impl KvsEngine for KvStore {
     fn remove(&self, key: String) -> Result<()> {
         self.active_log.lock().unwrap().remove(key)
     }
+
+    fn watch(&self, key_or_prefix: String) -> Result<Events> {
+        Ok(Box::new(self.publisher.subscribe(key_or_prefix)))
+    }
+
+    fn keys(&self) -> Result<Keys> {
+        self.key_dir.keys(None)
+    }
+
+    // Values are read lazily, a key removed in the meantime is skipped.
+    // Reads follow the key dir, which compaction keeps pointing at live files.
+    fn iter(&self) -> Result<Pairs> {
+        let keys = self.keys()?;
+        let store = self.clone();
+        Ok(Box::new(keys.filter_map(move |key| {
+            let key = match key {
+                Ok(key) => key,
+                Err(e) => return Some(Err(e)),
+            };
+            match store.get(key.clone()) {
+                Ok(Some(value)) => Some(Ok((key, value))),
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            }
+        })))
+    }
+
+    // Only the keys of the page are sorted.
+    fn list_keys(&self, cursor: Option<String>, limit: usize) -> Result<Page<String>> {
+        let items = self.key_dir.first_keys(cursor.as_deref(), limit)?;
+        let cursor = match items.last() {
+            Some(last) if items.len() == limit => Some(last.clone()),
+            _ => None,
+        };
+        Ok(Page { items, cursor })
+    }
+
+    fn open_tree(&self, name: &str) -> Result<Self> {
+        check_tree_name(name)?;
+        let namespaces = self.namespaces();
+        let mut trees = namespaces.trees.lock().unwrap();
+        let tree = match trees.entry(name.to_owned()) {
+            hash_map::Entry::Occupied(e) => e.get().clone(),
+            hash_map::Entry::Vacant(e) => {
+                let dir = namespaces.dir.join(NAMESPACE_DIR).join(name);
+                let tree = Self::load(dir, &namespaces.option, &namespaces.caches)?;
+                e.insert(tree).clone()
+            }
+        };
+        Ok(KvStore {
+            namespaces: Some(Arc::clone(namespaces)),
+            ..tree
+        })
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        let dir = self.namespaces().dir.join(NAMESPACE_DIR);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    // Each namespace is copied as of a different moment.
+    fn snapshot(&self, dest_dir: &Path) -> Result<()> {
+        let root = self.namespaces().root.lock().unwrap().clone();
+        root.active_log.lock().unwrap().snapshot(dest_dir)?;
+        for name in self.tree_names()? {
+            let tree_dir = dest_dir.join(NAMESPACE_DIR).join(&name);
+            let tree = self.open_tree(&name)?;
+            let mut active_log = tree.active_log.lock().unwrap();
+            active_log.snapshot(&tree_dir)?;
+        }
+        set_engine_type(dest_dir, &EngineType::kvs)
+    }
+
+    fn open_copy(&self, dir: &Path) -> Result<Self> {
+        KvStore::open_with(dir, self.namespaces().option.clone())
+    }
+
+    fn begin(&self) -> Result<KvTransaction> {
+        Ok(KvTransaction {
+            store: self.clone(),
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+            values: HashMap::new(),
+        })
+    }
+
+    fn last_seq(&self) -> Option<u64> {
+        Some(self.active_log.lock().unwrap().seq)
+    }
+
+    fn change_feed(&self, seq: u64) -> Result<ChangeFeed> {
+        Ok(Box::new(self.changes_since(seq)?))
+    }
 }
 
-struct ActiveLog {
+/// A transaction on a `KvStore`. Keys are versioned by the sequence number
+/// of their latest mutation, which must be unchanged at commit for every key read.
+pub struct KvTransaction {
+    store: KvStore,
+    // key -> version when first read, `None` if missing
+    reads: HashMap<String, Option<u64>>,
+    // key -> value written, `None` if removed
+    writes: BTreeMap<String, Option<String>>,
+    // values read, so that reading a key again gives the same value
+    values: HashMap<String, Option<String>>,
+}
+
+impl Transaction for KvTransaction {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.values.get(&key) {
+            return Ok(value.clone());
+        }
+        // the version is read along with the value, under the same guard
+        let read = self.store.key_dir.read(&key, |meta| {
+            Ok((self.store.stable_log.get_value(&key, meta)?, meta.seq))
+        })?;
+        let (value, version) = match read {
+            Some((value, seq)) => (value, Some(seq)),
+            None => (None, None),
+        };
+        self.reads.insert(key.clone(), version);
+        self.values.insert(key, value.clone());
+        Ok(value)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    fn commit(self) -> Result<()> {
+        let mut active_log = self.store.active_log.lock().unwrap();
+        active_log.commit(&self.reads, self.writes)
+    }
+}
+
+/// The state of a `KvStore` as of the mutation numbered `seq`.
+///
+/// The latest version of a key is read from the key dir, as long as it is not
+/// newer than the view. Otherwise the version replaced right after `seq` is.
+pub struct ReadView {
+    store: KvStore,
+    seq: u64,
+    // file id -> reader handle, of files which may be stale by now
+    read_handles: RefCell<HashMap<u64, ReadHandle<File>>>,
+}
+
+impl ReadView {
+    /// Sequence number of the latest mutation seen by the view.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.meta_of(&key)? {
+            Some(meta) => self.read_value(&meta),
+            None => Ok(None),
+        }
+    }
+
+    /// The keys of the view, in ascending order.
+    pub fn keys(&self) -> Result<Keys> {
+        let mut keys = self.store.key_dir.keys(None)?.collect::<Result<Vec<_>>>()?;
+        keys.extend(self.store.undo.iter().map(|e| e.key().clone()));
+        keys.sort_unstable();
+        keys.dedup();
+        let mut visible = Vec::with_capacity(keys.len());
+        for key in keys {
+            if self.meta_of(&key)?.is_some() {
+                visible.push(key);
+            }
+        }
+        Ok(Box::new(visible.into_iter().map(Ok)))
+    }
+
+    /// At most `limit` key-value pairs of the view following `cursor`,
+    /// with the cursor of the next page.
+    pub fn scan(&self, cursor: Option<String>, limit: usize) -> Result<Page<(String, String)>> {
+        scan_keys(
+            cursor,
+            limit,
+            |after, n| self.first_keys(after, n),
+            |key| self.get(key),
+        )
+    }
+
+    // The first `n` keys following `after` of the key dir, or of versions
+    // kept for views, which may not be visible to this one.
+    fn first_keys(&self, after: Option<&str>, n: usize) -> Result<Vec<String>> {
+        let mut keys = self.store.key_dir.first_keys(after, n)?;
+        keys.extend(
+            self.store
+                .undo
+                .iter()
+                .map(|e| e.key().clone())
+                .filter(|key| after.is_none_or(|after| key.as_str() > after)),
+        );
+        keys.sort_unstable();
+        keys.dedup();
+        keys.truncate(n);
+        Ok(keys)
+    }
+
+    fn meta_of(&self, key: &str) -> Result<Option<CommandMeta>> {
+        if let Some(meta) = self.store.key_dir.get(key)? {
+            if meta.seq <= self.seq {
+                return Ok(Some(meta));
+            }
+        }
+        // The key has been changed since: the first version replaced
+        // after the view is the one it sees.
+        let versions = match self.store.undo.get(key) {
+            Some(versions) => versions,
+            None => return Ok(None),
+        };
+        let version = versions.iter().find(|version| version.until > self.seq);
+        Ok(version.and_then(|version| version.meta.clone()))
+    }
+
+    fn read_value(&self, meta: &CommandMeta) -> Result<Option<String>> {
+        let mut read_handles = self.read_handles.borrow_mut();
+        let handle = match read_handles.entry(meta.file_id) {
+            hash_map::Entry::Occupied(e) => e.into_mut(),
+            hash_map::Entry::Vacant(e) => {
+                // compaction may have turned it into a history file
+                let log_file_path = self.store.dir.join(log_file_of(meta.file_id));
+                let handle = match reader_of(&log_file_path) {
+                    Ok(handle) => handle,
+                    Err(_) => reader_of(&self.store.dir.join(history_file_of(meta.file_id)))?,
+                };
+                e.insert(handle)
+            }
+        };
+        handle.seek(SeekFrom::Start(meta.position))?;
+        let cmd = serde_json::from_reader(handle.take(meta.size))?;
+        value_of(cmd, &self.store.stable_log.codec)
+    }
+}
+
+impl Drop for ReadView {
+    fn drop(&mut self) {
+        if let Ok(mut active_log) = self.store.active_log.lock() {
+            active_log.close_view(self.seq);
+        }
+    }
+}
+
+pub(crate) struct ActiveLog {
     // active log file id
     pub file_id: u64,
     // log directory
     dir: Arc<PathBuf>,
-    // write handle of active log file
-    write_handle: WriteHandle<File>,
+    // write handle of active log file, none if read only
+    write_handle: Option<WriteHandle<File>>,
     // in-memory key dir
-    key_dir: Arc<DashMap<String, CommandMeta>>,
-    // uncompacted log length
-    uncompacted: u64,
+    key_dir: Arc<KeyDir>,
+    // bytes and dead bytes of every log file
+    usage: Usage,
+    // files written by a merge are cut once this many bytes long
+    merge_file_size: u64,
+    // keys an index on disk holds in memory at most
+    max_unindexed_keys: usize,
+    // compactions run since the store was opened, and bytes they wrote
+    compactions: u64,
+    compacted_bytes: u64,
+    // dead bytes the latest compaction could not reclaim, removes it kept
+    unreclaimed: u64,
     // stable log
     stable_log: StableLog,
+    // watchers, notified after every successful append
+    publisher: Publisher,
+    // sequence number of the latest mutation
+    seq: u64,
+    // mutations up to this sequence number may have been compacted away
+    seq_floor: u64,
+    // file id -> max sequence number in it, for both log and history files
+    file_seqs: BTreeMap<u64, u64>,
+    // number of latest mutations kept available through compaction
+    history_retention: u64,
+    // versions replaced while read views are open
+    undo: Arc<DashMap<String, Vec<Version>>>,
+    // sequence number -> number of open read views pinned to it
+    views: BTreeMap<u64, usize>,
+    // stale files to remove once no read view is open
+    doomed: Vec<PathBuf>,
 }
 
 impl ActiveLog {
     fn set(&mut self, key: String, value: String) -> Result<()> {
         // write in active log file
-        let prev_pos = self.write_handle.pos;
-        let new_cmd = Command::set(key.clone(), value);
-        serde_json::to_writer(&mut self.write_handle, &new_cmd)?;
-        self.write_handle.flush()?;
+        let prev_pos = self.writer()?.pos;
+        let new_cmd = Command::set(self.seq + 1, key.clone(), value);
+        self.append(&new_cmd)?;
+        self.writer()?.flush()?;
+        self.advance_seq();
         // insert <key, meta> pair in keydir
-        let meta: CommandMeta = (self.file_id, prev_pos, self.write_handle.pos - prev_pos).into();
-        if let Some(old_meta) = self.key_dir.insert(key, meta) {
-            self.uncompacted += old_meta.size;
-        }
-        // compaction
-        if self.uncompacted >= COMPACTION_THRESHOLD {
-            self.compact()?;
+        let size = self.writer()?.pos - prev_pos;
+        let meta: CommandMeta = (self.file_id, prev_pos, size, self.seq).into();
+        self.keep_version(&key)?;
+        self.stable_log.forget_value(&key);
+        self.usage.add_bytes(self.file_id, size);
+        if let Some(old_meta) = self.key_dir.insert(key.clone(), meta)? {
+            self.usage.add_dead(old_meta.file_id, old_meta.size);
         }
-        Ok(())
+        // once readable, so that a watcher reads what it is told of
+        self.publisher.publish(&key, || new_cmd.into());
+        self.maintain()
     }
 
     fn remove(&mut self, key: String) -> Result<()> {
         // check
-        if self.key_dir.contains_key(&key) {
+        if self.key_dir.contains_key(&key)? {
             // write in active log file
-            let new_cmd = Command::remove(key.clone());
-            serde_json::to_writer(&mut self.write_handle, &new_cmd)?;
-            self.write_handle.flush()?;
+            let prev_pos = self.writer()?.pos;
+            let new_cmd = Command::remove(self.seq + 1, key.clone());
+            self.append(&new_cmd)?;
+            self.writer()?.flush()?;
+            self.advance_seq();
             // remove <key, meta> pair from keydir
-            if let Some((_, old_meta)) = self.key_dir.remove(&key) {
-                self.uncompacted += old_meta.size;
-            }
-            // compaction
-            if self.uncompacted >= COMPACTION_THRESHOLD {
-                self.compact()?;
+            self.keep_version(&key)?;
+            self.stable_log.forget_value(&key);
+            let size = self.writer()?.pos - prev_pos;
+            self.usage.add_bytes(self.file_id, size);
+            self.usage.add_dead(self.file_id, size);
+            if let Some(old_meta) = self.key_dir.remove(&key)? {
+                self.usage.add_dead(old_meta.file_id, old_meta.size);
             }
-            Ok(())
+            self.publisher.publish(&key, || new_cmd.into());
+            self.maintain()
         } else {
             Err(KvsError::KeyNotFound)
         }
     }
 
+    // Commands of a transaction are flushed together, the last one marking
+    // the transaction as complete. Removing a key which is missing by then
+    // is a no-op.
+    fn commit(
+        &mut self,
+        reads: &HashMap<String, Option<u64>>,
+        writes: BTreeMap<String, Option<String>>,
+    ) -> Result<()> {
+        for (key, version) in reads {
+            if self.key_dir.get(key)?.map(|meta| meta.seq) != *version {
+                return Err(KvsError::TransactionConflict);
+            }
+        }
+        let mut kept = Vec::with_capacity(writes.len());
+        for (key, value) in writes {
+            if value.is_some() || self.key_dir.contains_key(&key)? {
+                kept.push((key, value));
+            }
+        }
+        let writes = kept;
+        let count = writes.len();
+        let mut logged = Vec::with_capacity(count);
+        for (i, (key, value)) in writes.into_iter().enumerate() {
+            let seq = self.seq + 1 + i as u64;
+            let mut cmd = match value {
+                Some(value) => Command::set(seq, key.clone(), value),
+                None => Command::remove(seq, key.clone()),
+            };
+            cmd.set_txn_left((count - i - 1) as u64);
+            let prev_pos = self.writer()?.pos;
+            self.append(&cmd)?;
+            logged.push((key, cmd, prev_pos, self.writer()?.pos - prev_pos));
+        }
+        self.writer()?.flush()?;
+
+        for (key, cmd, pos, size) in logged {
+            self.advance_seq();
+            self.keep_version(&key)?;
+            self.stable_log.forget_value(&key);
+            self.usage.add_bytes(self.file_id, size);
+            let old_meta = match cmd {
+                Command::Set { .. } => {
+                    let meta: CommandMeta = (self.file_id, pos, size, self.seq).into();
+                    self.key_dir.insert(key.clone(), meta)?
+                }
+                Command::Remove { .. } | Command::Tombstone { .. } => {
+                    self.usage.add_dead(self.file_id, size);
+                    self.key_dir.remove(&key)?
+                }
+            };
+            if let Some(old_meta) = old_meta {
+                self.usage.add_dead(old_meta.file_id, old_meta.size);
+            }
+            self.publisher.publish(&key, || cmd.into());
+        }
+        self.maintain()
+    }
+
+    // Run after every write. A compaction indexes every key on disk,
+    // so the index is flushed only if there is none.
+    fn maintain(&mut self) -> Result<()> {
+        if self.should_compact() {
+            self.compact()
+        } else if self.key_dir.unindexed() > self.max_unindexed_keys {
+            self.flush_index()
+        } else {
+            Ok(())
+        }
+    }
+
+    // The active file is sealed first, for the segment to cover it in full.
+    fn flush_index(&mut self) -> Result<()> {
+        self.rotate()?;
+        self.key_dir.flush(self.file_id - 1)
+    }
+
+    // Seal the active file, writes going on in a new one.
+    fn rotate(&mut self) -> Result<()> {
+        self.writer()?.sync()?;
+        self.file_id += 1;
+        self.write_handle = Some(writer_of(&self.dir.join(log_file_of(self.file_id)))?);
+        self.stable_log.seal(self.file_id - 1);
+        self.commit_manifest()
+    }
+
+    // Removes a merge keeps are dead, but compacting again
+    // for them alone would only copy them once more.
+    fn should_compact(&self) -> bool {
+        self.usage.reclaimable().saturating_sub(self.unreclaimed) >= COMPACTION_THRESHOLD
+    }
+
+    fn writer(&mut self) -> Result<&mut WriteHandle<File>> {
+        self.write_handle.as_mut().ok_or(KvsError::ReadOnly)
+    }
+
+    fn append(&mut self, cmd: &Command) -> Result<()> {
+        let writer = self.write_handle.as_mut().ok_or(KvsError::ReadOnly)?;
+        write_record(writer, cmd, &self.stable_log.codec)
+    }
+
+    fn advance_seq(&mut self) {
+        self.seq += 1;
+        self.file_seqs.insert(self.file_id, self.seq);
+    }
+
+    // Called before `key` is changed by the latest mutation. Recorded first,
+    // so that a view never misses the version it reads.
+    fn keep_version(&self, key: &str) -> Result<()> {
+        if self.views.is_empty() {
+            return Ok(());
+        }
+        let meta = self.key_dir.get(key)?;
+        let version = Version {
+            until: self.seq,
+            meta,
+        };
+        self.undo.entry(key.to_owned()).or_default().push(version);
+        Ok(())
+    }
+
+    // Versions older than every remaining view are of no use any more.
+    fn close_view(&mut self, seq: u64) {
+        if let btree_map::Entry::Occupied(mut e) = self.views.entry(seq) {
+            *e.get_mut() -= 1;
+            if *e.get() == 0 {
+                e.remove();
+            }
+        }
+        match self.views.keys().next() {
+            Some(&oldest) => self.undo.retain(|_, versions| {
+                versions.retain(|version| version.until > oldest);
+                !versions.is_empty()
+            }),
+            None => {
+                self.undo.clear();
+                for path in self.doomed.drain(..) {
+                    remove_file(&path);
+                }
+            }
+        }
+    }
+
+    fn changes_since(&self, seq: u64, log: Weak<Mutex<ActiveLog>>) -> Result<Changes> {
+        if seq < self.seq_floor {
+            return Err(KvsError::HistoryTruncated(self.seq_floor));
+        }
+        // Files holding nothing after `seq` are skipped, but the active
+        // file, which may hold more later.
+        let ids = self
+            .file_seqs
+            .iter()
+            .filter(|&(&id, &max_seq)| max_seq > seq || id == self.file_id)
+            .map(|(&id, _)| id);
+        let files = self.open_files(ids)?;
+        let last_id = self.file_seqs.keys().next_back().copied().unwrap_or(0);
+        let codec = Arc::clone(&self.stable_log.codec);
+        Ok(Changes::new(files, last_id, seq, self.seq, codec, log))
+    }
+
+    // Files written after `last_id`, for a stream of changes to go on.
+    pub(crate) fn tail(&self, last_id: u64) -> Result<Tail> {
+        let ids = self.file_seqs.range(last_id + 1..).map(|(&id, _)| id);
+        Ok(Tail {
+            files: self.open_files(ids)?,
+            end_seq: self.seq,
+            seq_floor: self.seq_floor,
+        })
+    }
+
+    // An opened file stays readable even if compaction renames
+    // or removes it in the meantime.
+    fn open_files(&self, ids: impl Iterator<Item = u64>) -> Result<Vec<(u64, File)>> {
+        let mut files = Vec::new();
+        for id in ids {
+            let log_file_path = self.dir.join(log_file_of(id));
+            if log_file_path.exists() {
+                files.push((id, File::open(log_file_path)?));
+            } else {
+                files.push((id, File::open(self.dir.join(history_file_of(id)))?));
+            }
+        }
+        Ok(files)
+    }
+
+    // Sealing the active file leaves only immutable files to link, and holding
+    // the lock keeps compaction from removing any of them in the meantime.
+    fn snapshot(&mut self, dest_dir: &Path) -> Result<()> {
+        ensure_empty_dir(dest_dir)?;
+        if self
+            .write_handle
+            .as_ref()
+            .is_some_and(|writer| writer.pos > 0)
+        {
+            self.rotate()?;
+        }
+        let mut file_ids = Vec::new();
+        for id in list_log_file_in(&self.dir)? {
+            if id < self.file_id {
+                link_or_copy(&self.dir, dest_dir, &log_file_of(id))?;
+                file_ids.push(id);
+            }
+        }
+        write_manifest(dest_dir, file_ids)?;
+        for id in list_history_file_in(&self.dir)? {
+            link_or_copy(&self.dir, dest_dir, &history_file_of(id))?;
+        }
+        for id in list_file_in(&self.dir, "index")? {
+            link_or_copy(&self.dir, dest_dir, &index_file_of(id))?;
+        }
+        if self.dir.join(SEQ_FLOOR_FILE).exists() {
+            fs::copy(self.dir.join(SEQ_FLOOR_FILE), dest_dir.join(SEQ_FLOOR_FILE))?;
+        }
+        Ok(())
+    }
+
+    // Files worth merging, the active one included, have their live records
+    // rewritten into new files numbered after the active one. Other files are
+    // left in place.
     fn compact(&mut self) -> Result<()> {
-        let compaction_id = self.file_id + 1;
-        self.file_id += 2;
-        self.write_handle = writer_of(&self.dir.join(log_file_of(self.file_id)))?;
-        let mut compaction_writer = writer_of(&self.dir.join(log_file_of(compaction_id)))?;
-
-        let mut compacted_pos: u64 = 0;
-        for mut entry in self.key_dir.iter_mut() {
-            let len = self.stable_log.locate_and(entry.value(), |mut handle| {
-                Ok(io::copy(&mut handle, &mut compaction_writer)?)
-            })?;
-            *entry = (compaction_id, compacted_pos, len).into();
-            // self.key_dir.insert(
-            //     entry.key().clone(),
-            //     (compaction_id, compacted_pos, len).into(),
-            // );
-            compacted_pos += len;
-        }
-        compaction_writer.flush()?;
-
-        self.stable_log
-            .compacted_id
-            .store(compaction_id, Ordering::SeqCst);
-        self.stable_log.remove_stale_log();
-
-        let stale_log_file_ids: Vec<_> = list_log_file_in(self.dir.as_ref())?
-            .into_iter()
-            .filter(|&id| -> bool { id < compaction_id })
+        let merged: BTreeSet<u64> = self.usage.mergeable().into_iter().collect();
+        if merged.is_empty() {
+            return Ok(());
+        }
+        let sealed_id = self.file_id;
+        let mut output =
+            MergeOutput::new(Arc::clone(&self.dir), sealed_id + 1, self.merge_file_size);
+        let codec = &self.stable_log.codec;
+
+        // Removes are kept as tombstones for as long as an older file
+        // left in place may set their key, in the files merged or not.
+        let oldest_kept = self
+            .usage
+            .files()
+            .map(|(&id, _)| id)
+            .find(|id| !merged.contains(id));
+        let mut carried = HashSet::new();
+        let mut carried_metas = Vec::new();
+        for &id in &merged {
+            if oldest_kept.is_none_or(|kept| kept >= id) {
+                continue;
+            }
+            let reader = reader_of(&self.dir.join(log_file_of(id)))?;
+            for cmd in Deserializer::from_reader(reader).into_iter::<Command>() {
+                if let Some(tombstone) = cmd?.to_tombstone() {
+                    let Command::Tombstone { key, .. } = &tombstone else {
+                        unreachable!()
+                    };
+                    if !self.key_dir.contains_key(key)? && carried.insert(key.clone()) {
+                        carried_metas.push(output.write(&tombstone, codec)?);
+                    }
+                }
+            }
+        }
+
+        // Commands are rewritten rather than copied, as on their own
+        // they are no longer part of a transaction.
+        let stable_log = &self.stable_log;
+        self.key_dir.relocate(|meta| {
+            if !merged.contains(&meta.file_id) {
+                return Ok(meta.clone());
+            }
+            let mut cmd = stable_log.read_record(meta)?;
+            cmd.set_txn_left(0);
+            // recompressed and re-encrypted only if the codec has changed
+            if !codec.is_current(&cmd) {
+                cmd = cmd.decode(codec)?;
+            }
+            output.write(&cmd, codec)
+        })?;
+        let outputs = output.finish()?;
+        let last_id = outputs.last().map_or(sealed_id, |output| output.id);
+        self.key_dir.commit_relocation(last_id)?;
+        self.file_id = last_id + 1;
+        self.write_handle = Some(writer_of(&self.dir.join(log_file_of(self.file_id)))?);
+        // the former active file and the merge outputs are complete
+        self.stable_log.seal(last_id);
+        // Until here a crash leaves the store as it was before the merge,
+        // from here on as it is after.
+        let merged: Vec<_> = merged.into_iter().collect();
+        write_manifest(
+            &self.dir,
+            list_log_file_in(&self.dir)?
+                .into_iter()
+                .filter(|id| merged.binary_search(id).is_err()),
+        )?;
+
+        self.stable_log.mapped.retire(&merged);
+        for &id in &merged {
+            self.usage.remove(id);
+        }
+        for output in &outputs {
+            self.usage.add_bytes(output.id, output.bytes);
+            self.file_seqs.insert(output.id, output.max_seq);
+            self.compacted_bytes += output.bytes;
+        }
+        for meta in carried_metas {
+            self.usage.add_dead(meta.file_id, meta.size);
+        }
+        self.unreclaimed = self.usage.reclaimable();
+        self.compactions += 1;
+        self.retain_history()
+    }
+
+    // Every log file is live, the active one included.
+    fn commit_manifest(&self) -> Result<()> {
+        write_manifest(&self.dir, list_log_file_in(&self.dir)?)
+    }
+
+    // Stale files still holding one of the latest `history_retention` mutations
+    // are kept as history files, the others are removed.
+    // A file whose sequence numbers do not go beyond those of the previous files
+    // is the output of an earlier merge and holds no history.
+    fn retain_history(&mut self) -> Result<()> {
+        let retained_seq = self.seq.saturating_sub(self.history_retention);
+        let file_seqs: Vec<_> = self
+            .file_seqs
+            .iter()
+            .map(|(&id, &max_seq)| (id, max_seq))
             .collect();
 
-        for id in stale_log_file_ids {
+        let mut prev_max_seq = 0;
+        for (id, max_seq) in file_seqs {
+            let is_merge_output = max_seq <= prev_max_seq;
+            prev_max_seq = prev_max_seq.max(max_seq);
+            // log files left in place are not stale
+            if self.usage.contains(id) {
+                continue;
+            }
             let log_file_path = self.dir.join(log_file_of(id));
-            if let Err(e) = fs::remove_file(&log_file_path) {
-                error!("{:?} cannot be removed, cause {}", log_file_path, e);
+            let history_file_path = self.dir.join(history_file_of(id));
+            if max_seq > retained_seq && !is_merge_output {
+                if log_file_path.exists() {
+                    fs::rename(&log_file_path, &history_file_path)?;
+                }
+            } else {
+                self.file_seqs.remove(&id);
+                if !is_merge_output {
+                    self.seq_floor = self.seq_floor.max(max_seq);
+                }
+                if self.views.is_empty() {
+                    for path in [log_file_path, history_file_path] {
+                        if path.exists() {
+                            remove_file(&path);
+                        }
+                    }
+                } else {
+                    // read views may still read from it, but a stale log
+                    // must not be replayed should the store crash meanwhile
+                    if log_file_path.exists() {
+                        fs::rename(&log_file_path, &history_file_path)?;
+                    }
+                    if history_file_path.exists() {
+                        self.doomed.push(history_file_path);
+                    }
+                }
             }
         }
+        write_seq_floor(&self.dir, self.seq_floor)
+    }
+}
+
+// Files a merge writes records to, numbered on from `next_id`,
+// each cut once `max_size` bytes long.
+struct MergeOutput {
+    dir: Arc<PathBuf>,
+    next_id: u64,
+    max_size: u64,
+    current: Option<(WriteHandle<File>, MergedFile)>,
+    done: Vec<MergedFile>,
+}
+
+// A file written by a merge.
+struct MergedFile {
+    id: u64,
+    bytes: u64,
+    max_seq: u64,
+}
+
+impl MergeOutput {
+    fn new(dir: Arc<PathBuf>, next_id: u64, max_size: u64) -> MergeOutput {
+        MergeOutput {
+            dir,
+            next_id,
+            max_size,
+            current: None,
+            done: Vec::new(),
+        }
+    }
+
+    // The location `cmd` is written at.
+    fn write(&mut self, cmd: &Command, codec: &Codec) -> Result<CommandMeta> {
+        if matches!(&self.current, Some((writer, _)) if writer.pos >= self.max_size) {
+            self.cut()?;
+        }
+        let (writer, file) = match &mut self.current {
+            Some(current) => current,
+            None => {
+                let id = self.next_id;
+                self.next_id += 1;
+                let writer = writer_of(&self.dir.join(log_file_of(id)))?;
+                let file = MergedFile {
+                    id,
+                    bytes: 0,
+                    max_seq: 0,
+                };
+                self.current.insert((writer, file))
+            }
+        };
+        let pos = writer.pos;
+        write_record(&mut *writer, cmd, codec)?;
+        file.bytes = writer.pos;
+        file.max_seq = file.max_seq.max(cmd.seq());
+        Ok((file.id, pos, writer.pos - pos, cmd.seq()).into())
+    }
 
-        self.uncompacted = 0;
+    fn cut(&mut self) -> Result<()> {
+        if let Some((mut writer, file)) = self.current.take() {
+            // on disk before the manifest lists it
+            writer.sync()?;
+            self.done.push(file);
+        }
         Ok(())
     }
+
+    // Every file written, synced.
+    fn finish(mut self) -> Result<Vec<MergedFile>> {
+        self.cut()?;
+        Ok(self.done)
+    }
 }
 
+#[derive(Clone)]
 struct StableLog {
     // directory
     dir: Arc<PathBuf>,
 
-    // file id -> reader handle
-    // Because our log file name is in ascending order, here we use BTreeMap instead.
-    read_handles: RefCell<BTreeMap<u64, ReadHandle<File>>>,
+    // reader handles of files that may still be written to
+    read_handles: ReadHandleCache,
+
+    // values recently read, and the id of this namespace's keys in it
+    values: ValueCache,
+    space: u64,
+
+    // files up to this id are complete and never written again
+    sealed_id: Arc<AtomicU64>,
+
+    // maps of sealed files, shared by every clone
+    mapped: MappedFiles,
 
-    // compacted
-    compacted_id: Arc<AtomicU64>,
+    // how values are written
+    codec: Arc<Codec>,
 }
 
 impl StableLog {
-    // get the value of the given meta
-    fn get_value(&self, meta: &CommandMeta) -> Result<Option<String>> {
-        self.locate_and(meta, |handle| {
-            if let Command::Set { value, .. } = serde_json::from_reader(handle)? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnknownCommand)
-            }
-        })
-    }
-    fn locate_and<F, R>(&self, meta: &CommandMeta, f: F) -> Result<R>
-    where
-        F: FnOnce(io::Take<&mut ReadHandle<File>>) -> Result<R>,
-    {
-        self.remove_stale_log();
-        let mut read_handles = self.read_handles.borrow_mut();
-        if !read_handles.contains_key(&meta.file_id) {
-            let read_handle = reader_of(self.dir.join(log_file_of(meta.file_id)).as_path())?;
-            read_handles.insert(meta.file_id, read_handle);
+    // get the value of `key` as of the given meta
+    fn get_value(&self, key: &str, meta: &CommandMeta) -> Result<Option<String>> {
+        if let Some(value) = self.values.get(self.space, key, meta.seq) {
+            return Ok(Some(value));
         }
-        let handle = read_handles
-            .get_mut(&meta.file_id)
-            .ok_or(KvsError::LogFileNotFound)?;
-        handle.seek(SeekFrom::Start(meta.position))?;
-        f(handle.take(meta.size))
+        let value = value_of(self.read_record(meta)?, &self.codec)?;
+        if let Some(value) = &value {
+            self.values
+                .insert(self.space, key.to_owned(), meta.seq, value.clone());
+        }
+        Ok(value)
     }
-    fn remove_stale_log(&self) {
-        let mut read_handles = self.read_handles.borrow_mut();
-        while let Some(&id) = read_handles.keys().next() {
-            if id >= self.compacted_id.load(Ordering::SeqCst) {
-                break;
-            }
-            read_handles.remove(&id);
+
+    fn key_at(&self, meta: &CommandMeta) -> Result<String> {
+        match self.read_record(meta)? {
+            Command::Set { key, .. }
+            | Command::Remove { key, .. }
+            | Command::Tombstone { key, .. } => Ok(key),
         }
     }
-}
 
-impl Clone for StableLog {
-    fn clone(&self) -> StableLog {
-        StableLog {
-            dir: Arc::clone(&self.dir),
-            compacted_id: Arc::clone(&self.compacted_id),
-            read_handles: RefCell::new(BTreeMap::new()),
+    // Called whenever `key` is changed.
+    fn forget_value(&self, key: &str) {
+        self.values.invalidate(self.space, key);
+    }
+
+    // Sealed files are read through their maps, the others through a reader.
+    fn read_record(&self, meta: &CommandMeta) -> Result<Command> {
+        let path = self.dir.join(log_file_of(meta.file_id));
+        let (start, end) = (meta.position as usize, (meta.position + meta.size) as usize);
+        if meta.file_id <= self.sealed_id.load(Ordering::SeqCst) {
+            let map = self.mapped.get(meta.file_id, &path)?;
+            let record = map
+                .get(start..end)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            return Ok(serde_json::from_slice(record)?);
         }
+        let handle = self.read_handles.get(&self.dir, meta.file_id, &path)?;
+        let mut handle = handle.lock().unwrap();
+        handle.seek(SeekFrom::Start(meta.position))?;
+        let mut record = vec![0; end - start];
+        handle.read_exact(&mut record)?;
+        Ok(serde_json::from_slice(&record)?)
+    }
+
+    // Files sealed from now on are read through their maps only.
+    fn seal(&self, id: u64) {
+        self.sealed_id.fetch_max(id, Ordering::SeqCst);
+        self.read_handles.evict_up_to(&self.dir, id);
     }
 }
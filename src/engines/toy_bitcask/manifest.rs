@@ -0,0 +1,41 @@
+use crate::Result;
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Ids of the log files of a namespace, one per line. Any other log file is
+/// the leftover of a compaction, or of a crash before it committed.
+/// `None` for a directory written before there was a manifest.
+pub(crate) fn read_manifest(dir: &Path) -> Result<Option<BTreeSet<u64>>> {
+    let path = dir.join(MANIFEST_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    fs::read_to_string(path)?
+        .lines()
+        .map(|line| {
+            line.parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+        })
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+/// Replace the manifest at once: written aside, synced, then renamed over it.
+pub(crate) fn write_manifest(dir: &Path, ids: impl IntoIterator<Item = u64>) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", MANIFEST_FILE));
+    let mut file = File::create(&tmp_path)?;
+    for id in ids {
+        writeln!(file, "{}", id)?;
+    }
+    file.sync_all()?;
+    fs::rename(tmp_path, dir.join(MANIFEST_FILE))?;
+    // the rename is only durable once the directory is
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use crate::Result;
+use memmap2::Mmap;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::Path,
+    sync::{Arc, RwLock, Weak},
+};
+
+/// Memory maps of sealed log files, shared by every handle of a store.
+///
+/// A sealed file is never written again, so its map stays valid until the
+/// file is merged away. Maps in use outlive their removal from here.
+#[derive(Clone, Default)]
+pub(crate) struct MappedFiles {
+    maps: Arc<RwLock<Maps>>,
+}
+
+#[derive(Default)]
+struct Maps {
+    // file id -> map of the whole file
+    maps: BTreeMap<u64, Arc<Mmap>>,
+    // files merged away, which late readers map without keeping the map,
+    // sharing it while one of them holds it
+    retired: BTreeMap<u64, Weak<Mmap>>,
+}
+
+impl MappedFiles {
+    /// The map of `path`, the file numbered `id`, mapped on first use.
+    pub(crate) fn get(&self, id: u64, path: &Path) -> Result<Arc<Mmap>> {
+        if let Some(map) = self.maps.read().unwrap().maps.get(&id) {
+            return Ok(Arc::clone(map));
+        }
+        let mut maps = self.maps.write().unwrap();
+        if let Some(map) = maps.maps.get(&id) {
+            return Ok(Arc::clone(map));
+        }
+        if let Some(map) = maps.retired.get(&id).and_then(Weak::upgrade) {
+            return Ok(map);
+        }
+        let file = File::open(path)?;
+        // Safe as long as nothing else truncates the file, which is the
+        // store's own and no longer written to.
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        match maps.retired.get_mut(&id) {
+            Some(retired) => *retired = Arc::downgrade(&map),
+            None => {
+                maps.maps.insert(id, Arc::clone(&map));
+            }
+        }
+        Ok(map)
+    }
+
+    /// Forget the maps of files merged away.
+    /// Files of earlier merges are gone by now and cannot be mapped again,
+    /// they are forgotten for good once no reader holds their map.
+    pub(crate) fn retire(&self, ids: &[u64]) {
+        let mut maps = self.maps.write().unwrap();
+        maps.retired.retain(|_, map| map.strong_count() > 0);
+        for id in ids {
+            let map = maps.maps.remove(id);
+            let map = map.as_ref().map_or_else(Weak::new, Arc::downgrade);
+            maps.retired.insert(*id, map);
+        }
+    }
+}
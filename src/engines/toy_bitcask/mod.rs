@@ -1,4 +1,20 @@
-pub use kv::KvStore;
+pub use cache::CacheStats;
+pub use changes::Changes;
+pub use compression::Compression;
+pub use encryption::{EncryptionKey, ENCRYPTION_KEY_ENV};
+pub use key_dir::IndexMode;
+pub use kv::{KvStore, KvTransaction, ReadView, StoreOption, StoreStats};
+pub use usage::LogFileStats;
 
+mod cache;
+mod changes;
+mod compression;
+mod disk_index;
+mod encryption;
 mod handle;
+mod key_dir;
 mod kv;
+mod manifest;
+mod mapped;
+mod publisher;
+mod usage;
@@ -0,0 +1,103 @@
+use crate::{common::WatchEvent, engines::EventStream};
+use crossbeam::channel::{
+    self, Receiver, RecvTimeoutError as ChannelTimeoutError, Sender, TrySendError,
+};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc::RecvTimeoutError,
+    Arc, Mutex,
+};
+use std::time::Duration;
+
+// events waiting for a watcher, past which it is dropped
+const MAX_PENDING_EVENTS: usize = 1024;
+
+// subscription id, key or key prefix -> channel to the watcher
+type Subscriber = (u64, String, Sender<WatchEvent>);
+
+// Fans committed writes out to watchers of a key or key prefix.
+#[derive(Clone, Default)]
+pub(crate) struct Publisher {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Publisher {
+    pub(crate) fn subscribe(&self, key_or_prefix: String) -> Subscription {
+        let (s, r) = channel::bounded(MAX_PENDING_EVENTS);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push((id, key_or_prefix, s));
+        Subscription {
+            id,
+            receiver: r,
+            subscribers: self.subscribers.clone(),
+        }
+    }
+
+    // Never waits for a watcher, as writers call it under the log lock:
+    // watchers too slow to keep up are dropped, and their streams end once
+    // the events already queued are read. Subscribers whose receiver is gone
+    // are dropped as well.
+    pub(crate) fn publish<F>(&self, key: &str, event: F)
+    where
+        F: FnOnce() -> WatchEvent,
+    {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if !subscribers
+            .iter()
+            .any(|(_, prefix, _)| key.starts_with(prefix))
+        {
+            return;
+        }
+        let event = event();
+        subscribers.retain(|(id, prefix, s)| {
+            if !key.starts_with(prefix) {
+                return true;
+            }
+            match s.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Watcher {} of {:?} falls behind, drop it", id, prefix);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+// Unsubscribes when dropped, rather than on the next matching write.
+pub(crate) struct Subscription {
+    id: u64,
+    receiver: Receiver<WatchEvent>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl Iterator for Subscription {
+    type Item = WatchEvent;
+
+    fn next(&mut self) -> Option<WatchEvent> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl EventStream for Subscription {
+    fn next_timeout(&mut self, timeout: Duration) -> Result<WatchEvent, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout).map_err(|e| match e {
+            ChannelTimeoutError::Timeout => RecvTimeoutError::Timeout,
+            ChannelTimeoutError::Disconnected => RecvTimeoutError::Disconnected,
+        })
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|(id, _, _)| *id != self.id);
+    }
+}
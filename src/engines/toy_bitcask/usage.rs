@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+
+/// Usage of a log file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogFileStats {
+    pub id: u64,
+    pub bytes: u64,
+    /// Bytes of the records live keys point at.
+    pub live_bytes: u64,
+    /// Bytes of every other record, removes included.
+    pub dead_bytes: u64,
+    /// Whether the next compaction merges the file.
+    pub mergeable: bool,
+}
+
+/// Bytes of a log file, and how many of them no live key points at.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct FileUsage {
+    pub bytes: u64,
+    pub dead: u64,
+    // holds records written with another codec than the current one
+    pub outdated: bool,
+}
+
+/// Usage of every log file of a namespace, and the dead bytes a merge of
+/// the files worth merging would reclaim.
+pub(crate) struct Usage {
+    files: BTreeMap<u64, FileUsage>,
+    // files with at least this share of dead bytes are worth merging
+    garbage_ratio: f64,
+    reclaimable: u64,
+}
+
+impl Usage {
+    pub(crate) fn new(garbage_ratio: f64) -> Usage {
+        Usage {
+            files: BTreeMap::new(),
+            garbage_ratio,
+            reclaimable: 0,
+        }
+    }
+
+    pub(crate) fn add_bytes(&mut self, id: u64, bytes: u64) {
+        self.update(id, |usage| usage.bytes += bytes);
+    }
+
+    pub(crate) fn add_dead(&mut self, id: u64, dead: u64) {
+        self.update(id, |usage| usage.dead += dead);
+    }
+
+    pub(crate) fn mark_outdated(&mut self, id: u64) {
+        self.update(id, |usage| usage.outdated = true);
+    }
+
+    pub(crate) fn remove(&mut self, id: u64) {
+        if let Some(usage) = self.files.remove(&id) {
+            if self.is_mergeable(&usage) {
+                self.reclaimable -= usage.dead;
+            }
+        }
+    }
+
+    pub(crate) fn contains(&self, id: u64) -> bool {
+        self.files.contains_key(&id)
+    }
+
+    /// Dead bytes of the files worth merging.
+    pub(crate) fn reclaimable(&self) -> u64 {
+        self.reclaimable
+    }
+
+    /// Files worth merging, in id order.
+    pub(crate) fn mergeable(&self) -> Vec<u64> {
+        self.files
+            .iter()
+            .filter(|(_, usage)| self.is_mergeable(usage))
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    pub(crate) fn files(&self) -> impl Iterator<Item = (&u64, &FileUsage)> {
+        self.files.iter()
+    }
+
+    pub(crate) fn stats(&self) -> Vec<LogFileStats> {
+        self.files
+            .iter()
+            .map(|(&id, usage)| LogFileStats {
+                id,
+                bytes: usage.bytes,
+                live_bytes: usage.bytes - usage.dead,
+                dead_bytes: usage.dead,
+                mergeable: self.is_mergeable(usage),
+            })
+            .collect()
+    }
+
+    fn is_mergeable(&self, usage: &FileUsage) -> bool {
+        usage.outdated
+            || (usage.bytes > 0 && usage.dead as f64 >= usage.bytes as f64 * self.garbage_ratio)
+    }
+
+    // Keep `reclaimable` in step with whether the file is worth merging.
+    fn update<F: FnOnce(&mut FileUsage)>(&mut self, id: u64, f: F) {
+        let mut usage = self.files.get(&id).copied().unwrap_or_default();
+        if self.is_mergeable(&usage) {
+            self.reclaimable -= usage.dead;
+        }
+        f(&mut usage);
+        if self.is_mergeable(&usage) {
+            self.reclaimable += usage.dead;
+        }
+        self.files.insert(id, usage);
+    }
+}
@@ -1,7 +1,8 @@
+// `failure`'s derive expands into non-local impls
+#![allow(non_local_definitions)]
+
 use failure::Fail;
-use rayon;
-use sled;
-use std::{io, string};
+use std::{io, net::SocketAddr, path::PathBuf, string};
 
 #[derive(Debug, Fail)]
 pub enum KvsError {
@@ -14,6 +15,9 @@ pub enum KvsError {
     #[fail(display = "serde error: {}", _0)]
     SerdeError(#[cause] serde_json::Error),
 
+    #[fail(display = "csv error: {}", _0)]
+    CsvError(#[cause] csv::Error),
+
     #[fail(display = "rayon error: {}", _0)]
     RayonError(#[cause] rayon::ThreadPoolBuildError),
 
@@ -35,6 +39,36 @@ pub enum KvsError {
     #[fail(display = "Imcompatible engin type")]
     ImcompatibleEngineType,
 
+    #[fail(display = "Invalid namespace name {:?}", _0)]
+    InvalidNamespace(String),
+
+    #[fail(display = "Directory {:?} is not empty", _0)]
+    DirectoryNotEmpty(PathBuf),
+
+    #[fail(display = "Directory {:?} is locked by another store", _0)]
+    DirectoryLocked(PathBuf),
+
+    #[fail(display = "Store is opened read-only")]
+    ReadOnly,
+
+    #[fail(display = "toy bitcask error: History up to seq {} is compacted", _0)]
+    HistoryTruncated(u64),
+
+    #[fail(display = "Data is encrypted with another key")]
+    WrongEncryptionKey,
+
+    #[fail(display = "Transaction conflicts with a concurrent write")]
+    TransactionConflict,
+
+    #[fail(display = "Not the leader, redirect to {}", _0)]
+    Redirect(SocketAddr),
+
+    #[fail(display = "Key is moved to {}", _0)]
+    Moved(SocketAddr),
+
+    #[fail(display = "No leader is elected yet")]
+    NoLeader,
+
     #[fail(display = "server error: {}", _0)]
     ServerErrorMessage(String),
 }
@@ -51,6 +85,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<csv::Error> for KvsError {
+    fn from(err: csv::Error) -> Self {
+        KvsError::CsvError(err)
+    }
+}
+
 impl From<sled::Error> for KvsError {
     fn from(err: sled::Error) -> Self {
         KvsError::SledError(err)
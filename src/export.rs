@@ -0,0 +1,219 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    engines::{engine_type_of, is_engine_file, set_engine_type, tree_of},
+    EncryptionKey, EngineType, ExportFormat, KvStore, KvsEngine, KvsError, Result, SledWrapper,
+    StoreOption,
+};
+
+#[derive(Serialize, Deserialize)]
+struct Pair {
+    key: String,
+    value: String,
+    // the default namespace if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+}
+
+/// Write the key/value pairs of the namespace `namespace` of `engine` to
+/// `writer`, or of every namespace if `None`, returning how many pairs were
+/// written. Pairs are written along with the name of their namespace.
+pub fn export<E: KvsEngine, W: Write>(
+    engine: &E,
+    format: ExportFormat,
+    namespace: Option<&str>,
+    writer: W,
+) -> Result<u64> {
+    let namespaces = match namespace {
+        Some(name) => vec![Some(name.to_owned())],
+        None => {
+            let mut namespaces = vec![None];
+            namespaces.extend(engine.tree_names()?.into_iter().map(Some));
+            namespaces
+        }
+    };
+    let mut exported = 0;
+    match format {
+        ExportFormat::jsonl => {
+            let mut writer = writer;
+            for namespace in namespaces {
+                for pair in tree_of(engine, namespace.as_deref())?.iter()? {
+                    let (key, value) = pair?;
+                    let namespace = namespace.clone();
+                    let pair = Pair {
+                        key,
+                        value,
+                        namespace,
+                    };
+                    serde_json::to_writer(&mut writer, &pair)?;
+                    writer.write_all(b"\n")?;
+                    exported += 1;
+                }
+            }
+            writer.flush()?;
+        }
+        ExportFormat::csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            // an empty export still has the header,
+            // an empty namespace is the default one
+            writer.write_record(["key", "value", "namespace"])?;
+            for namespace in namespaces {
+                for pair in tree_of(engine, namespace.as_deref())?.iter()? {
+                    let (key, value) = pair?;
+                    writer.write_record([&key, &value, namespace.as_deref().unwrap_or("")])?;
+                    exported += 1;
+                }
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(exported)
+}
+
+/// Set every key/value pair read from `reader` in `engine`, in the namespace
+/// it was exported from, or in the namespace `namespace` if given,
+/// returning how many pairs were set.
+pub fn import<E: KvsEngine, R: Read>(
+    engine: &E,
+    format: ExportFormat,
+    namespace: Option<&str>,
+    reader: R,
+) -> Result<u64> {
+    let set = |pair: Pair| -> Result<()> {
+        let name = namespace.or(pair.namespace.as_deref());
+        tree_of(engine, name)?.set(pair.key, pair.value)
+    };
+    let mut imported = 0;
+    match format {
+        ExportFormat::jsonl => {
+            for line in BufReader::new(reader).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                set(serde_json::from_str(&line)?)?;
+                imported += 1;
+            }
+        }
+        ExportFormat::csv => {
+            for pair in csv::Reader::from_reader(reader).deserialize() {
+                let mut pair: Pair = pair?;
+                pair.namespace = pair.namespace.filter(|name| !name.is_empty());
+                set(pair)?;
+                imported += 1;
+            }
+        }
+    }
+    Ok(imported)
+}
+
+/// Convert the data directory `dir` from engine `from` to engine `to` in place,
+/// returning how many pairs were converted, in every namespace.
+/// Files which do not belong to the engine are left alone.
+/// Values of the kvs engine are encrypted with the key in `ENCRYPTION_KEY_ENV`,
+/// if set, as the `kvs` tool does.
+pub fn convert(dir: &Path, from: EngineType, to: EngineType) -> Result<u64> {
+    if let Some(engine_type) = engine_type_of(dir)? {
+        if engine_type != from {
+            return Err(KvsError::ImcompatibleEngineType);
+        }
+    }
+    if from == to {
+        return Ok(0);
+    }
+
+    // the new store is built aside, so that a failed conversion loses nothing
+    let converted_dir = tempfile::Builder::new()
+        .prefix(".convert")
+        .tempdir_in(dir)?;
+    let converted = match from {
+        EngineType::kvs => copy_into(&open_store(dir)?, converted_dir.path(), to)?,
+        EngineType::sled => copy_into(
+            &SledWrapper::new(sled::open(dir)?),
+            converted_dir.path(),
+            to,
+        )?,
+    };
+
+    // the old files are moved aside and only removed once the new ones are
+    // in place, a crash in between leaves them in `old_dir`
+    let old_dir = tempfile::Builder::new()
+        .prefix(".convert-old")
+        .tempdir_in(dir)?
+        .keep();
+    if let Err(e) = swap(dir, from, &old_dir, converted_dir.path()) {
+        error!(
+            "Fail to convert {}, the {} files are kept in {}",
+            dir.display(),
+            from,
+            old_dir.display()
+        );
+        return Err(e);
+    }
+    set_engine_type(dir, &to)?;
+    fs::remove_dir_all(old_dir)?;
+    info!(
+        "Converted {} pairs in {} from {} to {}",
+        converted,
+        dir.display(),
+        from,
+        to
+    );
+    Ok(converted)
+}
+
+// Move the files of engine `from` in `dir` into `old_dir`,
+// then the files in `converted_dir` into `dir`.
+fn swap(dir: &Path, from: EngineType, old_dir: &Path, converted_dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if is_engine_file(from, &name.to_string_lossy()) {
+            fs::rename(entry.path(), old_dir.join(name))?;
+        }
+    }
+    for entry in fs::read_dir(converted_dir)? {
+        let entry = entry?;
+        fs::rename(entry.path(), dir.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+fn open_store(dir: &Path) -> Result<KvStore> {
+    let option = StoreOption {
+        encryption_key: EncryptionKey::from_env()?,
+        ..StoreOption::default()
+    };
+    KvStore::open_with(dir, option)
+}
+
+fn copy_into<E: KvsEngine>(source: &E, dest_dir: &Path, engine_type: EngineType) -> Result<u64> {
+    match engine_type {
+        EngineType::kvs => copy(source, &open_store(dest_dir)?),
+        EngineType::sled => copy(source, &SledWrapper::new(sled::open(dest_dir)?)),
+    }
+}
+
+fn copy<E: KvsEngine, F: KvsEngine>(source: &E, dest: &F) -> Result<u64> {
+    let mut copied = copy_tree(source, dest)?;
+    for name in source.tree_names()? {
+        copied += copy_tree(&source.open_tree(&name)?, &dest.open_tree(&name)?)?;
+    }
+    Ok(copied)
+}
+
+fn copy_tree<E: KvsEngine, F: KvsEngine>(source: &E, dest: &F) -> Result<u64> {
+    let mut copied = 0;
+    for pair in source.iter()? {
+        let (key, value) = pair?;
+        dest.set(key, value)?;
+        copied += 1;
+    }
+    Ok(copied)
+}
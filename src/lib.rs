@@ -1,20 +1,38 @@
+pub use backup::{backup, restore};
 pub use cli_common::{
-    ClientCommand, ClientOption, Command, EngineType, KvsCliOption, ServerOption,
+    ClientCommand, ClientOption, Command, EngineType, ExportFormat, KvsCliOption, ServerOption,
 };
-pub use client::KvsClient;
+pub use client::{KvsClient, WatchStream};
+pub use client_pool::{KvsClientPool, PoolOption};
+pub use common::{Change, HashRange, Page, WatchEvent};
 pub use engines::{
-    engine_type_of, set_engine_type, sled_wrapper::SledWrapper, toy_bitcask::KvStore, KvsEngine,
+    engine_type_of, set_engine_type,
+    sled_wrapper::{SledTransaction, SledWrapper},
+    toy_bitcask::{
+        CacheStats, Changes, Compression, EncryptionKey, IndexMode, KvStore, KvTransaction,
+        LogFileStats, ReadView, StoreOption, StoreStats, ENCRYPTION_KEY_ENV,
+    },
+    ChangeFeed, EventStream, Events, Keys, KvsEngine, Pairs, Transaction,
 };
 pub use errors::{KvsError, Result};
+pub use export::{convert, export, import};
 pub use server::KvsServer;
+pub use sharded_client::ShardedKvsClient;
+pub mod raft;
 pub mod thread_pool;
 
+mod backup;
 mod cli_common;
 mod client;
+mod client_pool;
 mod common;
 mod engines;
 mod errors;
+mod export;
+mod migration;
+mod replication;
 mod server;
+mod sharded_client;
 
 #[macro_use]
 extern crate log;
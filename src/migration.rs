@@ -0,0 +1,238 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    common::HashRange,
+    engines::{remove_if_exists, tree_of},
+    sharded_client::key_hash,
+    KvsClient, KvsEngine, KvsError, Result,
+};
+
+/// Where requests on a key are served while hash ranges move away.
+pub(crate) enum Route {
+    Local,
+    // being copied to the target, the key is on either server
+    Moving(SocketAddr),
+    Moved(SocketAddr),
+}
+
+const MIGRATIONS_FILE: &str = "migrations";
+
+#[derive(Deserialize, Serialize)]
+struct Migration {
+    range: HashRange,
+    target: SocketAddr,
+    done: bool,
+}
+
+/// Hash ranges migrated away from a server.
+///
+/// A key in a moving range is either still here, or already on the target.
+/// Reads look here first and then on the target, writes go to the target
+/// and drop the local copy. Once the whole range is copied, clients are
+/// told where the key has moved.
+/// Migrations opened in a data directory are kept there across restarts,
+/// others live in memory only.
+#[derive(Default)]
+pub(crate) struct Migrations {
+    migrations: Mutex<Vec<Migration>>,
+    // namespace and key of the moving keys being copied or written, each
+    // one at a time so that a stale copy never overwrites a newer write
+    // on the target
+    busy: Mutex<HashSet<(Option<String>, String)>>,
+    // notified whenever a key is done with
+    released: Condvar,
+    // file the migrations are saved to on every change, if any
+    path: Option<PathBuf>,
+}
+
+impl Migrations {
+    /// The migrations saved in `dir` by a previous run, if any.
+    pub(crate) fn open(dir: &Path) -> Result<Self> {
+        let path = dir.join(MIGRATIONS_FILE);
+        let migrations = if path.exists() {
+            serde_json::from_reader(BufReader::new(File::open(&path)?))?
+        } else {
+            Vec::new()
+        };
+        Ok(Migrations {
+            migrations: Mutex::new(migrations),
+            busy: Mutex::default(),
+            released: Condvar::new(),
+            path: Some(path),
+        })
+    }
+
+    pub(crate) fn route(&self, key: &str) -> Route {
+        let hash = key_hash(key.as_bytes());
+        let migrations = self.migrations.lock().unwrap();
+        match migrations.iter().find(|m| m.range.contains(hash)) {
+            Some(m) if m.done => Route::Moved(m.target),
+            Some(m) => Route::Moving(m.target),
+            None => Route::Local,
+        }
+    }
+
+    // Held while the key is copied or written, other keys move meanwhile.
+    fn lock_key(&self, namespace: &Option<String>, key: &str) -> KeyGuard<'_> {
+        let id = (namespace.clone(), key.to_owned());
+        let mut busy = self.busy.lock().unwrap();
+        while busy.contains(&id) {
+            busy = self.released.wait(busy).unwrap();
+        }
+        busy.insert(id.clone());
+        KeyGuard {
+            migrations: self,
+            id,
+        }
+    }
+
+    // Ranges moved back here are served locally again.
+    fn start(&self, ranges: &[HashRange], target: SocketAddr) -> Result<()> {
+        let mut migrations = self.migrations.lock().unwrap();
+        migrations.retain(|m| !ranges.contains(&m.range));
+        migrations.extend(ranges.iter().map(|&range| Migration {
+            range,
+            target,
+            done: false,
+        }));
+        self.save(&migrations)
+    }
+
+    fn finish(&self, ranges: &[HashRange]) -> Result<()> {
+        let mut migrations = self.migrations.lock().unwrap();
+        for m in migrations.iter_mut() {
+            if ranges.contains(&m.range) {
+                m.done = true;
+            }
+        }
+        self.save(&migrations)
+    }
+
+    // write aside then rename, so that a crash leaves either version intact
+    fn save(&self, migrations: &[Migration]) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, migrations)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp_path, path)?;
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+struct KeyGuard<'a> {
+    migrations: &'a Migrations,
+    id: (Option<String>, String),
+}
+
+impl Drop for KeyGuard<'_> {
+    fn drop(&mut self) {
+        self.migrations.busy.lock().unwrap().remove(&self.id);
+        self.migrations.released.notify_all();
+    }
+}
+
+/// Copy every key in `ranges`, of every namespace, to `target`,
+/// then redirect clients there.
+/// A failed migration keeps the ranges moving, running it again resumes it.
+pub(crate) fn migrate<E: KvsEngine>(
+    engine: &E,
+    migrations: &Migrations,
+    ranges: Vec<HashRange>,
+    target: SocketAddr,
+) -> Result<u64> {
+    info!("Migrate {} ranges to {}", ranges.len(), target);
+    // saved before any key moves, a restart in the middle keeps them moving
+    migrations.start(&ranges, target)?;
+    let mut namespaces = vec![None];
+    namespaces.extend(engine.tree_names()?.into_iter().map(Some));
+    let mut moved = 0;
+    for namespace in namespaces {
+        let tree = tree_of(engine, namespace.as_deref())?;
+        let mut client = KvsClient::connect(target)?.with_namespace(namespace.clone());
+        let mut keys = Vec::new();
+        for key in tree.keys()? {
+            let key = key?;
+            let hash = key_hash(key.as_bytes());
+            if ranges.iter().any(|range| range.contains(hash)) {
+                keys.push(key);
+            }
+        }
+        for key in keys {
+            let _key = migrations.lock_key(&namespace, &key);
+            // may have been written or removed since
+            if let Some(value) = tree.get(key.clone())? {
+                client.set(key.clone(), value)?;
+                remove_if_exists(&tree, key)?;
+                moved += 1;
+            }
+        }
+    }
+    migrations.finish(&ranges)?;
+    info!("Migrated {} keys to {}", moved, target);
+    Ok(moved)
+}
+
+// Requests on a moving key are served with `tree`, the namespace
+// `namespace` of the store, on this side.
+pub(crate) fn get_moving<E: KvsEngine>(
+    tree: &E,
+    target: SocketAddr,
+    namespace: Option<String>,
+    key: String,
+) -> Result<Option<String>> {
+    match tree.get(key.clone())? {
+        Some(value) => Ok(Some(value)),
+        None => KvsClient::connect(target)?
+            .with_namespace(namespace)
+            .get(key),
+    }
+}
+
+pub(crate) fn set_moving<E: KvsEngine>(
+    tree: &E,
+    migrations: &Migrations,
+    target: SocketAddr,
+    namespace: Option<String>,
+    key: String,
+    value: String,
+) -> Result<()> {
+    let _key = migrations.lock_key(&namespace, &key);
+    KvsClient::connect(target)?
+        .with_namespace(namespace)
+        .set(key.clone(), value)?;
+    remove_if_exists(tree, key)
+}
+
+pub(crate) fn remove_moving<E: KvsEngine>(
+    tree: &E,
+    migrations: &Migrations,
+    target: SocketAddr,
+    namespace: Option<String>,
+    key: String,
+) -> Result<()> {
+    let _key = migrations.lock_key(&namespace, &key);
+    // never on both servers at once
+    match tree.remove(key.clone()) {
+        Err(KvsError::KeyNotFound) => KvsClient::connect(target)?
+            .with_namespace(namespace)
+            .remove(key),
+        result => result,
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::Request;
+
+pub type NodeId = u64;
+
+/// Cluster configuration, node id -> client address of the node.
+pub type Members = BTreeMap<NodeId, String>;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Payload {
+    // appended by every new leader to commit entries of previous terms
+    Noop,
+    // a `Set` or `Remove` to apply to the engine, in its namespace
+    Request(Request),
+    // the whole new configuration, effective as soon as it is appended
+    Membership(Members),
+    // writes applied together only if every key read still has the value read,
+    // `None` standing for a missing or removed key
+    Transaction {
+        namespace: Option<String>,
+        reads: Vec<(String, Option<String>)>,
+        writes: Vec<(String, Option<String>)>,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub payload: Payload,
+}
+
+/// Where the log starts over from a copy of the engine, which holds every
+/// entry up to and including `last_index`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub last_index: u64,
+    pub last_term: u64,
+    pub members: Members,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum RaftMessage {
+    RequestVote {
+        term: u64,
+        candidate: NodeId,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    AppendEntries {
+        term: u64,
+        leader: NodeId,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    // The copy of the engine is sent in chunks of pairs, namespace after
+    // namespace, the default one first.
+    InstallSnapshot {
+        term: u64,
+        leader: NodeId,
+        snapshot: Snapshot,
+        // position of the chunk in the copy, 0 starting it over
+        chunk: u64,
+        // namespace of `pairs`, the default one if `None`
+        tree: Option<String>,
+        pairs: Vec<(String, String)>,
+        // the last chunk
+        done: bool,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum RaftReply {
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    // On failure `match_index` is where the leader should retry from.
+    Append {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+    // `next_chunk` is the chunk expected next, 0 to start over.
+    Snapshot {
+        term: u64,
+        last_index: u64,
+        next_chunk: u64,
+    },
+}
@@ -0,0 +1,63 @@
+//! Raft consensus over a replicated log of `Set` and `Remove` requests,
+//! with any `KvsEngine` as the state machine.
+
+pub use message::{Entry, Members, NodeId, Payload, RaftMessage, RaftReply, Snapshot};
+pub use node::{RaftNode, RaftTransaction};
+pub use tcp::TcpTransport;
+
+mod message;
+mod node;
+mod storage;
+mod tcp;
+
+use std::time::Duration;
+
+use crate::Result;
+
+const DEFAULT_ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+const DEFAULT_SNAPSHOT_THRESHOLD: usize = 10_000;
+const DEFAULT_PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct RaftOption {
+    pub id: NodeId,
+    // initial configuration, including this node when bootstrapping a cluster,
+    // empty when joining one through `add_node`
+    pub members: Members,
+    // a follower waits between one and two times this long for the leader
+    pub election_timeout: Duration,
+    pub heartbeat_interval: Duration,
+    // number of applied entries kept in the log before taking a snapshot
+    pub snapshot_threshold: usize,
+    // how long a write waits to be committed
+    pub propose_timeout: Duration,
+}
+
+impl Default for RaftOption {
+    fn default() -> Self {
+        RaftOption {
+            id: 0,
+            members: Members::new(),
+            election_timeout: DEFAULT_ELECTION_TIMEOUT,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            snapshot_threshold: DEFAULT_SNAPSHOT_THRESHOLD,
+            propose_timeout: DEFAULT_PROPOSE_TIMEOUT,
+        }
+    }
+}
+
+/// How a node reaches its peers.
+pub trait Transport: Send + Sync + 'static {
+    /// Deliver `msg` to node `to` listening on `addr` and wait for its reply.
+    fn call(&self, to: NodeId, addr: &str, msg: RaftMessage) -> Result<RaftReply>;
+}
+
+// What the server needs from a raft node, whatever its engine.
+pub(crate) trait RaftService: Send + Sync {
+    fn handle(&self, msg: RaftMessage) -> RaftReply;
+    fn add_node(&self, id: NodeId, addr: String) -> Result<()>;
+    fn remove_node(&self, id: NodeId) -> Result<()>;
+    // `Redirect` to the leader unless this node is the leader
+    fn ensure_leader(&self) -> Result<()>;
+}
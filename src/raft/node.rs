@@ -0,0 +1,1284 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    common::{Page, Request},
+    engines::{tree_of, Events, Keys, Pairs, Transaction},
+    raft::{
+        message::{Entry, Members, NodeId, Payload, RaftMessage, RaftReply, Snapshot},
+        storage::{HardState, Storage},
+        RaftOption, RaftService, Transport,
+    },
+    KvsEngine, KvsError, Result,
+};
+
+const TICK: Duration = Duration::from_millis(10);
+const MAX_ENTRIES_PER_APPEND: usize = 256;
+const MAX_PAIRS_PER_SNAPSHOT_CHUNK: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// A member of a raft cluster.
+///
+/// Writes go through the replicated log and return once applied to the local
+/// engine, reads are served by the local engine.
+/// Every node is driven by a ticker thread, RPCs are sent on short-lived
+/// threads so that no lock is held while waiting for a peer.
+pub struct RaftNode<E: KvsEngine> {
+    shared: Arc<Shared<E>>,
+    // writes carry it through the log, the default namespace if `None`
+    namespace: Option<String>,
+}
+
+impl<E: KvsEngine> Clone for RaftNode<E> {
+    fn clone(&self) -> Self {
+        RaftNode {
+            shared: Arc::clone(&self.shared),
+            namespace: self.namespace.clone(),
+        }
+    }
+}
+
+struct Shared<E: KvsEngine> {
+    id: NodeId,
+    option: RaftOption,
+    transport: Arc<dyn Transport>,
+    state: Mutex<State<E>>,
+    // notified whenever entries are applied or the role changes
+    applied: Condvar,
+    shutdown: AtomicBool,
+    ticker: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct State<E: KvsEngine> {
+    engine: E,
+    storage: Storage,
+
+    // persistent
+    term: u64,
+    voted_for: Option<NodeId>,
+    snapshot: Snapshot,
+    // entries after `snapshot.last_index`
+    log: Vec<Entry>,
+
+    // volatile
+    role: Role,
+    leader: Option<NodeId>,
+    members: Members,
+    commit_index: u64,
+    last_applied: u64,
+    election_deadline: Instant,
+    votes: HashSet<NodeId>,
+
+    // leader only
+    next_index: HashMap<NodeId, u64>,
+    match_index: HashMap<NodeId, u64>,
+    next_heartbeat: Instant,
+    // peers with an append or snapshot RPC on the way
+    in_flight: HashSet<NodeId>,
+    // how far the snapshot is sent to peers which lag behind it
+    transfers: HashMap<NodeId, Transfer>,
+    // the copy of the engine of `snapshot`, opened once for every peer
+    snapshot_store: Option<E>,
+    // follower only, the snapshot being received
+    incoming: Option<Incoming<E>>,
+    // index -> (term, outcome) of entries proposed on this node
+    pending: HashMap<u64, (u64, Option<Result<()>>)>,
+}
+
+// The next chunk of a snapshot to send, which starts after `cursor`
+// in the namespace numbered `tree`, 0 being the default one and the others
+// following in the order of their names.
+#[derive(Debug, Clone, Default)]
+struct Transfer {
+    last_index: u64,
+    chunk: u64,
+    tree: usize,
+    cursor: Option<String>,
+}
+
+// A chunk of a snapshot, and where the one after it starts, if any.
+struct Chunk {
+    tree: Option<String>,
+    pairs: Vec<(String, String)>,
+    next: Option<Transfer>,
+}
+
+struct Incoming<E: KvsEngine> {
+    snapshot: Snapshot,
+    // the copy of the engine received so far
+    store: E,
+    next_chunk: u64,
+}
+
+impl<E: KvsEngine> RaftNode<E> {
+    /// Start a node keeping its raft state under `dir`.
+    pub fn start<T>(engine: E, dir: PathBuf, option: RaftOption, transport: T) -> Result<Self>
+    where
+        T: Transport,
+    {
+        let storage = Storage::open(dir)?;
+        let (hard_state, snapshot, log) = storage.load()?;
+        // Raft owns every key of the engine, a snapshot would wipe out
+        // whatever was there before.
+        let fresh = hard_state.term == 0 && snapshot.is_none() && log.is_empty();
+        if fresh && !is_empty(&engine)? {
+            return Err(KvsError::ServerErrorMessage(
+                "the store holds data written outside raft".to_owned(),
+            ));
+        }
+        let snapshot = snapshot.unwrap_or_else(|| Snapshot {
+            members: option.members.clone(),
+            ..Snapshot::default()
+        });
+        // Entries already applied must not be applied twice: a transaction
+        // would check its reads against later writes. Only a snapshot which
+        // was being installed is behind the engine.
+        let mut applied = storage.load_applied()?;
+        if applied < snapshot.last_index {
+            let copy = engine.open_copy(&storage.snapshot_data(snapshot.last_index))?;
+            restore(&engine, &copy)?;
+            applied = snapshot.last_index;
+            storage.save_applied(applied)?;
+        }
+        let mut state = State {
+            engine,
+            storage,
+            term: hard_state.term,
+            voted_for: hard_state.voted_for,
+            commit_index: applied,
+            last_applied: applied,
+            snapshot,
+            log,
+            role: Role::Follower,
+            leader: None,
+            members: Members::new(),
+            election_deadline: Instant::now(),
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            next_heartbeat: Instant::now(),
+            in_flight: HashSet::new(),
+            transfers: HashMap::new(),
+            snapshot_store: None,
+            incoming: None,
+            pending: HashMap::new(),
+        };
+        state.refresh_members();
+        state.election_deadline = Instant::now() + election_timeout(&option);
+
+        let shared = Arc::new(Shared {
+            id: option.id,
+            option,
+            transport: Arc::new(transport),
+            state: Mutex::new(state),
+            applied: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+            ticker: Mutex::new(None),
+        });
+        let ticker = {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || shared.run_ticker())
+        };
+        *shared.ticker.lock().unwrap() = Some(ticker);
+        Ok(RaftNode {
+            shared,
+            namespace: None,
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.shared.id
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.shared.lock().role == Role::Leader
+    }
+
+    /// Id of the leader this node currently knows of.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.shared.lock().leader
+    }
+
+    pub fn members(&self) -> Members {
+        self.shared.lock().members.clone()
+    }
+
+    /// Stop taking part in the cluster.
+    /// Pending proposals fail and the engine is released.
+    pub fn shutdown(&self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        self.shared.applied.notify_all();
+        if let Some(ticker) = self.shared.ticker.lock().unwrap().take() {
+            let _ = ticker.join();
+        }
+    }
+
+    /// Answer an RPC from a peer.
+    pub fn handle(&self, msg: RaftMessage) -> RaftReply {
+        self.shared.handle(msg)
+    }
+
+    pub fn add_node(&self, id: NodeId, addr: String) -> Result<()> {
+        self.change_membership(|members| {
+            members.insert(id, addr);
+        })
+    }
+
+    pub fn remove_node(&self, id: NodeId) -> Result<()> {
+        self.change_membership(|members| {
+            members.remove(&id);
+        })
+    }
+
+    // One node at a time, and only once the previous change is committed,
+    // so that the old and the new majorities always overlap.
+    fn change_membership<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Members),
+    {
+        let state = self.shared.lock();
+        let uncommitted_change = state
+            .log
+            .iter()
+            .filter(|entry| entry.index > state.commit_index)
+            .any(|entry| matches!(entry.payload, Payload::Membership(_)));
+        if uncommitted_change {
+            return Err(KvsError::ServerErrorMessage(
+                "another membership change is in progress".to_owned(),
+            ));
+        }
+        let mut members = state.members.clone();
+        f(&mut members);
+        drop(state);
+        self.shared.propose(Payload::Membership(members))
+    }
+}
+
+impl<E: KvsEngine> RaftService for RaftNode<E> {
+    fn handle(&self, msg: RaftMessage) -> RaftReply {
+        RaftNode::handle(self, msg)
+    }
+
+    fn add_node(&self, id: NodeId, addr: String) -> Result<()> {
+        RaftNode::add_node(self, id, addr)
+    }
+
+    fn remove_node(&self, id: NodeId) -> Result<()> {
+        RaftNode::remove_node(self, id)
+    }
+
+    fn ensure_leader(&self) -> Result<()> {
+        let state = self.shared.lock();
+        if state.role == Role::Leader {
+            Ok(())
+        } else {
+            Err(self.shared.redirect(&state))
+        }
+    }
+}
+
+impl<E: KvsEngine> RaftNode<E> {
+    // the namespace of the local engine this node is on
+    fn local_tree(&self) -> Result<E> {
+        tree_of(&self.shared.lock().engine, self.namespace.as_deref())
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for RaftNode<E> {
+    type Transaction = RaftTransaction<E>;
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.shared.propose(Payload::Request(Request::Set {
+            key,
+            value,
+            namespace: self.namespace.clone(),
+        }))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.local_tree()?.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.shared.propose(Payload::Request(Request::Remove {
+            key,
+            namespace: self.namespace.clone(),
+        }))
+    }
+
+    fn watch(&self, key_or_prefix: String) -> Result<Events> {
+        self.local_tree()?.watch(key_or_prefix)
+    }
+
+    fn keys(&self) -> Result<Keys> {
+        self.local_tree()?.keys()
+    }
+
+    fn iter(&self) -> Result<Pairs> {
+        self.local_tree()?.iter()
+    }
+
+    fn list_keys(&self, cursor: Option<String>, limit: usize) -> Result<Page<String>> {
+        self.local_tree()?.list_keys(cursor, limit)
+    }
+
+    fn scan(&self, cursor: Option<String>, limit: usize) -> Result<Page<(String, String)>> {
+        self.local_tree()?.scan(cursor, limit)
+    }
+
+    fn open_tree(&self, name: &str) -> Result<Self> {
+        // checks the name
+        self.shared.lock().engine.open_tree(name)?;
+        Ok(RaftNode {
+            shared: Arc::clone(&self.shared),
+            namespace: Some(name.to_owned()),
+        })
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        self.shared.lock().engine.tree_names()
+    }
+
+    // the local engine only, raft state is not part of it
+    fn snapshot(&self, dest_dir: &Path) -> Result<()> {
+        self.shared.lock().engine.snapshot(dest_dir)
+    }
+
+    // a copy holds no raft state to run a node on
+    fn open_copy(&self, _dir: &Path) -> Result<Self> {
+        Err(KvsError::ServerErrorMessage(
+            "a copy of a raft node is opened with its engine".to_owned(),
+        ))
+    }
+
+    fn begin(&self) -> Result<RaftTransaction<E>> {
+        Ok(RaftTransaction {
+            node: self.clone(),
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        })
+    }
+}
+
+/// A transaction on a `RaftNode`. Reads are served by the local engine,
+/// and checked again when the transaction is applied from the log,
+/// in the same order on every node.
+pub struct RaftTransaction<E: KvsEngine> {
+    node: RaftNode<E>,
+    // key -> value when first read
+    reads: HashMap<String, Option<String>>,
+    // key -> value written, `None` if removed
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl<E: KvsEngine> Transaction for RaftTransaction<E> {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = self.reads.get(&key) {
+            return Ok(value.clone());
+        }
+        let value = self.node.get(key.clone())?;
+        self.reads.insert(key, value.clone());
+        Ok(value)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    fn commit(self) -> Result<()> {
+        self.node.shared.propose(Payload::Transaction {
+            namespace: self.node.namespace.clone(),
+            reads: self.reads.into_iter().collect(),
+            writes: self.writes.into_iter().collect(),
+        })
+    }
+}
+
+impl<E: KvsEngine> Shared<E> {
+    fn lock(&self) -> MutexGuard<'_, State<E>> {
+        self.state.lock().unwrap()
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    fn run_ticker(self: Arc<Self>) {
+        while !self.is_shutdown() {
+            thread::sleep(TICK);
+            let mut state = self.lock();
+            let now = Instant::now();
+            match state.role {
+                Role::Leader => {
+                    if now >= state.next_heartbeat {
+                        state.next_heartbeat = now + self.option.heartbeat_interval;
+                        self.replicate(&mut state);
+                    }
+                }
+                Role::Follower | Role::Candidate => {
+                    if now >= state.election_deadline && state.members.contains_key(&self.id) {
+                        self.start_election(&mut state);
+                    }
+                }
+            }
+        }
+        // wake up proposals so that they fail
+        let mut state = self.lock();
+        state.role = Role::Follower;
+        state.leader = None;
+        self.applied.notify_all();
+    }
+
+    fn propose(self: &Arc<Self>, payload: Payload) -> Result<()> {
+        let mut state = self.lock();
+        if state.role != Role::Leader {
+            return Err(self.redirect(&state));
+        }
+        let term = state.term;
+        let index = state.last_index() + 1;
+        state.append(vec![Entry {
+            term,
+            index,
+            payload,
+        }])?;
+        state.pending.insert(index, (term, None));
+        self.advance_commit(&mut state)?;
+        self.replicate(&mut state);
+
+        let deadline = Instant::now() + self.option.propose_timeout;
+        loop {
+            if let Some((_, Some(_))) = state.pending.get(&index) {
+                let (_, outcome) = state.pending.remove(&index).unwrap();
+                return outcome.unwrap();
+            }
+            let now = Instant::now();
+            if self.is_shutdown() || state.term != term || now >= deadline {
+                state.pending.remove(&index);
+                return Err(KvsError::NoLeader);
+            }
+            state = self.applied.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    fn redirect(&self, state: &State<E>) -> KvsError {
+        state
+            .leader
+            .and_then(|leader| state.members.get(&leader))
+            .and_then(|addr| addr.parse().ok())
+            .map_or(KvsError::NoLeader, KvsError::Redirect)
+    }
+
+    fn start_election(self: &Arc<Self>, state: &mut State<E>) {
+        state.term += 1;
+        state.role = Role::Candidate;
+        state.leader = None;
+        state.voted_for = Some(self.id);
+        state.votes = HashSet::from([self.id]);
+        state.election_deadline = Instant::now() + election_timeout(&self.option);
+        if let Err(e) = state.save_hard_state() {
+            error!("Fail to persist raft state, cause {}", e);
+            return;
+        }
+        debug!("Node {} starts election for term {}", self.id, state.term);
+        if state.has_quorum(&state.votes) {
+            self.become_leader(state);
+            return;
+        }
+
+        let msg = RaftMessage::RequestVote {
+            term: state.term,
+            candidate: self.id,
+            last_log_index: state.last_index(),
+            last_log_term: state.last_term(),
+        };
+        for (&peer, addr) in state.members.iter().filter(|(&id, _)| id != self.id) {
+            let shared = Arc::clone(self);
+            let addr = addr.clone();
+            let msg = msg.clone();
+            thread::spawn(move || {
+                if let Ok(reply) = shared.transport.call(peer, &addr, msg) {
+                    shared.on_vote_reply(peer, reply);
+                }
+            });
+        }
+    }
+
+    fn on_vote_reply(self: &Arc<Self>, peer: NodeId, reply: RaftReply) {
+        if self.is_shutdown() {
+            return;
+        }
+        let mut state = self.lock();
+        if let RaftReply::Vote { term, granted } = reply {
+            if term > state.term {
+                self.step_down(&mut state, term);
+            } else if state.role == Role::Candidate && term == state.term && granted {
+                state.votes.insert(peer);
+                if state.has_quorum(&state.votes) {
+                    self.become_leader(&mut state);
+                }
+            }
+        }
+    }
+
+    fn become_leader(self: &Arc<Self>, state: &mut State<E>) {
+        info!("Node {} becomes leader of term {}", self.id, state.term);
+        state.role = Role::Leader;
+        state.leader = Some(self.id);
+        state.next_index.clear();
+        state.match_index.clear();
+        state.in_flight.clear();
+        let term = state.term;
+        let index = state.last_index() + 1;
+        if let Err(e) = state.append(vec![Entry {
+            term,
+            index,
+            payload: Payload::Noop,
+        }]) {
+            error!("Fail to append to raft log, cause {}", e);
+        }
+        if let Err(e) = self.advance_commit(state) {
+            error!("Fail to apply raft log, cause {}", e);
+        }
+        state.next_heartbeat = Instant::now() + self.option.heartbeat_interval;
+        self.replicate(state);
+    }
+
+    fn step_down(&self, state: &mut State<E>, term: u64) {
+        if term > state.term {
+            state.term = term;
+            state.voted_for = None;
+            if let Err(e) = state.save_hard_state() {
+                error!("Fail to persist raft state, cause {}", e);
+            }
+        }
+        if state.role != Role::Follower {
+            debug!("Node {} steps down in term {}", self.id, state.term);
+            state.role = Role::Follower;
+            state.leader = None;
+            self.applied.notify_all();
+        }
+        state.election_deadline = Instant::now() + election_timeout(&self.option);
+    }
+
+    // Send whatever each peer is missing, or a heartbeat.
+    fn replicate(self: &Arc<Self>, state: &mut State<E>) {
+        let peers: Vec<_> = state
+            .members
+            .iter()
+            .filter(|(&id, _)| id != self.id)
+            .map(|(&id, addr)| (id, addr.clone()))
+            .collect();
+        let last_index = state.last_index();
+        for (peer, addr) in peers {
+            if !state.in_flight.insert(peer) {
+                continue;
+            }
+            let next_index = *state.next_index.entry(peer).or_insert(last_index + 1);
+            if next_index <= state.snapshot.last_index {
+                self.send_snapshot(state, peer, addr);
+                continue;
+            }
+            let prev_log_index = next_index - 1;
+            let entries = state
+                .entries_from(next_index)
+                .iter()
+                .take(MAX_ENTRIES_PER_APPEND)
+                .cloned()
+                .collect();
+            let msg = RaftMessage::AppendEntries {
+                term: state.term,
+                leader: self.id,
+                prev_log_index,
+                prev_log_term: state.term_at(prev_log_index).unwrap_or(0),
+                entries,
+                leader_commit: state.commit_index,
+            };
+            let term = state.term;
+            let shared = Arc::clone(self);
+            thread::spawn(move || {
+                let reply = shared.transport.call(peer, &addr, msg);
+                shared.on_append_reply(peer, term, reply, None);
+            });
+        }
+    }
+
+    // Send the next chunk of the snapshot, read off the lock.
+    fn send_snapshot(self: &Arc<Self>, state: &mut State<E>, peer: NodeId, addr: String) {
+        let store = match state.snapshot_store() {
+            Ok(store) => store,
+            Err(e) => {
+                error!("Fail to open raft snapshot, cause {}", e);
+                state.in_flight.remove(&peer);
+                return;
+            }
+        };
+        let snapshot = state.snapshot.clone();
+        // a snapshot taken since starts over
+        let transfer = match state.transfers.get(&peer) {
+            Some(transfer) if transfer.last_index == snapshot.last_index => transfer.clone(),
+            _ => Transfer {
+                last_index: snapshot.last_index,
+                ..Transfer::default()
+            },
+        };
+        let term = state.term;
+        let shared = Arc::clone(self);
+        thread::spawn(move || {
+            let mut next = None;
+            let reply = read_chunk(&store, &transfer).and_then(|chunk| {
+                next = chunk.next;
+                let msg = RaftMessage::InstallSnapshot {
+                    term,
+                    leader: shared.id,
+                    snapshot,
+                    chunk: transfer.chunk,
+                    tree: chunk.tree,
+                    pairs: chunk.pairs,
+                    done: next.is_none(),
+                };
+                shared.transport.call(peer, &addr, msg)
+            });
+            shared.on_append_reply(peer, term, reply, next);
+        });
+    }
+
+    // `next` is where the snapshot goes on from after the chunk sent, if any.
+    fn on_append_reply(
+        self: &Arc<Self>,
+        peer: NodeId,
+        sent_term: u64,
+        reply: Result<RaftReply>,
+        next: Option<Transfer>,
+    ) {
+        if self.is_shutdown() {
+            return;
+        }
+        let mut state = self.lock();
+        state.in_flight.remove(&peer);
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(e) => {
+                debug!("Node {} cannot reach node {}, cause {}", self.id, peer, e);
+                return;
+            }
+        };
+        let (term, success, match_index, next_chunk) = match reply {
+            RaftReply::Append {
+                term,
+                success,
+                match_index,
+            } => (term, success, match_index, None),
+            RaftReply::Snapshot {
+                term,
+                last_index,
+                next_chunk,
+            } => (term, true, last_index, Some(next_chunk)),
+            RaftReply::Vote { .. } => return,
+        };
+        if term > state.term {
+            self.step_down(&mut state, term);
+            return;
+        }
+        if state.role != Role::Leader || state.term != sent_term {
+            return;
+        }
+        // on with the next chunk, unless the peer is done or starts over
+        if let Some(next_chunk) = next_chunk {
+            state.transfers.remove(&peer);
+            if let Some(next) =
+                next.filter(|next| next.chunk == next_chunk && match_index < next.last_index)
+            {
+                state.transfers.insert(peer, next);
+            }
+        }
+        if success {
+            let matched = state.match_index.entry(peer).or_insert(0);
+            *matched = (*matched).max(match_index);
+            let matched = *matched;
+            state.next_index.insert(peer, matched + 1);
+            if let Err(e) = self.advance_commit(&mut state) {
+                error!("Fail to apply raft log, cause {}", e);
+            }
+        } else {
+            let next = state.next_index.entry(peer).or_insert(1);
+            *next = (match_index + 1).min(*next - 1).max(1);
+        }
+        // keep going while the peer lags behind
+        if state.next_index.get(&peer).copied().unwrap_or(0) <= state.last_index() {
+            self.replicate(&mut state);
+        }
+    }
+
+    // Commit the latest entry of the current term stored on a majority.
+    fn advance_commit(self: &Arc<Self>, state: &mut State<E>) -> Result<()> {
+        let mut index = state.last_index();
+        while index > state.commit_index {
+            if state.term_at(index) == Some(state.term) {
+                let stored: HashSet<NodeId> = state
+                    .members
+                    .keys()
+                    .copied()
+                    .filter(|&id| {
+                        id == self.id || state.match_index.get(&id).copied().unwrap_or(0) >= index
+                    })
+                    .collect();
+                if state.has_quorum(&stored) {
+                    state.commit_index = index;
+                    break;
+                }
+            }
+            index -= 1;
+        }
+        self.apply(state)
+    }
+
+    fn apply(&self, state: &mut State<E>) -> Result<()> {
+        while state.last_applied < state.commit_index {
+            let index = state.last_applied + 1;
+            let entry = match state.entry_at(index) {
+                Some(entry) => entry.clone(),
+                None => break,
+            };
+            let outcome = match entry.payload {
+                Payload::Request(Request::Set {
+                    key,
+                    value,
+                    namespace,
+                }) => tree_of(&state.engine, namespace.as_deref())
+                    .and_then(|tree| tree.set(key, value)),
+                Payload::Request(Request::Remove { key, namespace }) => {
+                    tree_of(&state.engine, namespace.as_deref()).and_then(|tree| tree.remove(key))
+                }
+                Payload::Membership(members) => {
+                    if state.role == Role::Leader && !members.contains_key(&self.id) {
+                        info!("Node {} is removed from the cluster", self.id);
+                        state.role = Role::Follower;
+                        state.leader = None;
+                    }
+                    Ok(())
+                }
+                Payload::Transaction {
+                    namespace,
+                    reads,
+                    writes,
+                } => tree_of(&state.engine, namespace.as_deref())
+                    .and_then(|tree| apply_transaction(&tree, reads, writes)),
+                Payload::Request(_) | Payload::Noop => Ok(()),
+            };
+            state.last_applied = index;
+            state.storage.save_applied(index)?;
+            if let Some((term, slot)) = state.pending.get_mut(&index) {
+                *slot = Some(if *term == entry.term {
+                    outcome
+                } else {
+                    Err(KvsError::NoLeader)
+                });
+            }
+        }
+        self.applied.notify_all();
+        if state.log.len() > self.option.snapshot_threshold {
+            state.compact()?;
+        }
+        Ok(())
+    }
+
+    fn handle(&self, msg: RaftMessage) -> RaftReply {
+        let mut state = self.lock();
+        match msg {
+            RaftMessage::RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => {
+                if term > state.term {
+                    self.step_down(&mut state, term);
+                }
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (state.last_term(), state.last_index());
+                let mut granted = term == state.term
+                    && up_to_date
+                    && state.voted_for.is_none_or(|id| id == candidate);
+                if granted {
+                    // a vote only counts once it survives a crash
+                    let voted_for = state.voted_for.replace(candidate);
+                    if let Err(e) = state.save_hard_state() {
+                        error!("Fail to persist raft state, cause {}", e);
+                        state.voted_for = voted_for;
+                        granted = false;
+                    } else {
+                        state.election_deadline = Instant::now() + election_timeout(&self.option);
+                    }
+                }
+                RaftReply::Vote {
+                    term: state.term,
+                    granted,
+                }
+            }
+            RaftMessage::AppendEntries {
+                term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                if term < state.term {
+                    return RaftReply::Append {
+                        term: state.term,
+                        success: false,
+                        match_index: state.last_index(),
+                    };
+                }
+                self.follow(&mut state, term, leader);
+                let (success, match_index) = match self.append_entries(
+                    &mut state,
+                    prev_log_index,
+                    prev_log_term,
+                    entries,
+                    leader_commit,
+                ) {
+                    Ok(result) => result,
+                    Err(e) => {
+                        error!("Fail to append to raft log, cause {}", e);
+                        (false, state.commit_index)
+                    }
+                };
+                RaftReply::Append {
+                    term: state.term,
+                    success,
+                    match_index,
+                }
+            }
+            RaftMessage::InstallSnapshot {
+                term,
+                leader,
+                snapshot,
+                chunk,
+                tree,
+                pairs,
+                done,
+            } => {
+                let mut next_chunk = 0;
+                if term >= state.term {
+                    self.follow(&mut state, term, leader);
+                    match self.receive_snapshot(&mut state, snapshot, chunk, tree, pairs, done) {
+                        Ok(next) => next_chunk = next,
+                        Err(e) => {
+                            error!("Fail to install snapshot, cause {}", e);
+                            state.incoming = None;
+                        }
+                    }
+                }
+                RaftReply::Snapshot {
+                    term: state.term,
+                    last_index: state.commit_index,
+                    next_chunk,
+                }
+            }
+        }
+    }
+
+    fn follow(&self, state: &mut State<E>, term: u64, leader: NodeId) {
+        self.step_down(state, term);
+        state.leader = Some(leader);
+    }
+
+    fn append_entries(
+        &self,
+        state: &mut State<E>,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    ) -> Result<(bool, u64)> {
+        if prev_log_index > state.last_index() {
+            return Ok((false, state.last_index()));
+        }
+        if let Some(term) = state.term_at(prev_log_index) {
+            if term != prev_log_term {
+                // retry from before the conflicting term
+                let mut index = prev_log_index - 1;
+                while index > state.commit_index && state.term_at(index) == Some(term) {
+                    index -= 1;
+                }
+                return Ok((false, index));
+            }
+        }
+
+        let last_new_index = prev_log_index + entries.len() as u64;
+        let mut new_entries = Vec::new();
+        for entry in entries {
+            if entry.index <= state.snapshot.last_index {
+                continue;
+            }
+            match state.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => state.truncate(entry.index)?,
+                None => {}
+            }
+            new_entries.push(entry);
+        }
+        state.append(new_entries)?;
+
+        if leader_commit > state.commit_index {
+            state.commit_index = leader_commit.min(last_new_index);
+            self.apply(state)?;
+        }
+        Ok((true, last_new_index))
+    }
+
+    // Write a chunk into the copy received so far, and install the copy
+    // once complete. Returns the chunk expected next.
+    fn receive_snapshot(
+        &self,
+        state: &mut State<E>,
+        snapshot: Snapshot,
+        chunk: u64,
+        tree: Option<String>,
+        pairs: Vec<(String, String)>,
+        done: bool,
+    ) -> Result<u64> {
+        if snapshot.last_index <= state.commit_index {
+            return Ok(0);
+        }
+        if chunk == 0 {
+            // closed before its files go away
+            state.incoming = None;
+            let dir = state.storage.prepare_snapshot(snapshot.last_index)?;
+            state.incoming = Some(Incoming {
+                store: state.engine.open_copy(&dir)?,
+                snapshot: snapshot.clone(),
+                next_chunk: 0,
+            });
+        }
+        let incoming = match &mut state.incoming {
+            Some(incoming) if incoming.snapshot == snapshot && incoming.next_chunk == chunk => {
+                incoming
+            }
+            _ => return Ok(0),
+        };
+        let store = tree_of(&incoming.store, tree.as_deref())?;
+        for (key, value) in pairs {
+            store.set(key, value)?;
+        }
+        incoming.next_chunk += 1;
+        if !done {
+            return Ok(incoming.next_chunk);
+        }
+        // closed before its files are moved in place
+        drop(store);
+        state.incoming = None;
+        self.install_snapshot(state, snapshot)?;
+        Ok(0)
+    }
+
+    fn install_snapshot(&self, state: &mut State<E>, snapshot: Snapshot) -> Result<()> {
+        info!(
+            "Node {} installs snapshot up to {}",
+            self.id, snapshot.last_index
+        );
+        // saved first, a crash while the engine is half restored
+        // restores it again on start
+        state.snapshot_store = None;
+        state.storage.save_snapshot(&snapshot)?;
+        // keep the entries following the snapshot if they agree with it
+        let keep_log = state.term_at(snapshot.last_index) == Some(snapshot.last_term);
+        if keep_log {
+            let last_index = snapshot.last_index;
+            state.log.retain(|entry| entry.index > last_index);
+        } else {
+            state.log.clear();
+        }
+        state.storage.rewrite(&state.log)?;
+        state.snapshot = snapshot;
+        state.refresh_members();
+        let copy = state
+            .engine
+            .open_copy(&state.storage.snapshot_data(state.snapshot.last_index))?;
+        restore(&state.engine, &copy)?;
+        state.commit_index = state.snapshot.last_index;
+        state.last_applied = state.snapshot.last_index;
+        state.storage.save_applied(state.last_applied)
+    }
+}
+
+impl<E: KvsEngine> State<E> {
+    fn last_index(&self) -> u64 {
+        self.log
+            .last()
+            .map_or(self.snapshot.last_index, |entry| entry.index)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log
+            .last()
+            .map_or(self.snapshot.last_term, |entry| entry.term)
+    }
+
+    fn entry_at(&self, index: u64) -> Option<&Entry> {
+        if index <= self.snapshot.last_index {
+            return None;
+        }
+        self.log
+            .get((index - self.snapshot.last_index - 1) as usize)
+    }
+
+    fn entries_from(&self, index: u64) -> &[Entry] {
+        let offset =
+            (index.max(self.snapshot.last_index + 1) - self.snapshot.last_index - 1) as usize;
+        &self.log[offset.min(self.log.len())..]
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.last_index {
+            Some(self.snapshot.last_term)
+        } else {
+            self.entry_at(index).map(|entry| entry.term)
+        }
+    }
+
+    fn has_quorum(&self, nodes: &HashSet<NodeId>) -> bool {
+        let count = self.members.keys().filter(|id| nodes.contains(id)).count();
+        !self.members.is_empty() && count > self.members.len() / 2
+    }
+
+    fn save_hard_state(&self) -> Result<()> {
+        self.storage.save_state(&HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+        })
+    }
+
+    fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.storage.append(&entries)?;
+        let membership_changed = entries
+            .iter()
+            .any(|entry| matches!(entry.payload, Payload::Membership(_)));
+        self.log.extend(entries);
+        if membership_changed {
+            self.refresh_members();
+        }
+        Ok(())
+    }
+
+    // drop `index` and everything after it
+    fn truncate(&mut self, index: u64) -> Result<()> {
+        let last_index = self.snapshot.last_index;
+        self.log.truncate((index - last_index - 1) as usize);
+        self.storage.rewrite(&self.log)?;
+        self.refresh_members();
+        Ok(())
+    }
+
+    // The latest configuration in the log wins, committed or not.
+    fn refresh_members(&mut self) {
+        self.members = self
+            .log
+            .iter()
+            .rev()
+            .find_map(|entry| match &entry.payload {
+                Payload::Membership(members) => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot.members.clone());
+    }
+
+    // Replace applied entries with a copy of the engine, which links
+    // its files rather than copying them where it can.
+    fn compact(&mut self) -> Result<()> {
+        let last_index = self.last_applied;
+        if last_index == self.snapshot.last_index {
+            return Ok(());
+        }
+        let last_term = match self.term_at(last_index) {
+            Some(term) => term,
+            None => return Ok(()),
+        };
+        let members = self
+            .log
+            .iter()
+            .rev()
+            .filter(|entry| entry.index <= last_index)
+            .find_map(|entry| match &entry.payload {
+                Payload::Membership(members) => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot.members.clone());
+        self.engine
+            .snapshot(&self.storage.prepare_snapshot(last_index)?)?;
+        // closed before the previous snapshot goes away
+        self.snapshot_store = None;
+        self.snapshot = Snapshot {
+            last_index,
+            last_term,
+            members,
+        };
+        self.storage.save_snapshot(&self.snapshot)?;
+        self.log.retain(|entry| entry.index > last_index);
+        self.storage.rewrite(&self.log)?;
+        Ok(())
+    }
+
+    fn snapshot_store(&mut self) -> Result<E> {
+        if self.snapshot_store.is_none() {
+            let dir = self.storage.snapshot_data(self.snapshot.last_index);
+            self.snapshot_store = Some(self.engine.open_copy(&dir)?);
+        }
+        Ok(self.snapshot_store.clone().unwrap())
+    }
+}
+
+// Nothing else writes to the engine meanwhile, the local transaction
+// cannot conflict.
+fn apply_transaction<E: KvsEngine>(
+    engine: &E,
+    reads: Vec<(String, Option<String>)>,
+    writes: Vec<(String, Option<String>)>,
+) -> Result<()> {
+    let mut tx = engine.begin()?;
+    for (key, value) in reads {
+        if tx.get(key)? != value {
+            return Err(KvsError::TransactionConflict);
+        }
+    }
+    for (key, value) in writes {
+        match value {
+            Some(value) => tx.set(key, value)?,
+            None => match tx.remove(key) {
+                Ok(()) | Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            },
+        }
+    }
+    tx.commit()
+}
+
+fn is_empty<E: KvsEngine>(engine: &E) -> Result<bool> {
+    if engine.keys()?.next().is_some() {
+        return Ok(false);
+    }
+    for name in engine.tree_names()? {
+        if engine.open_tree(&name)?.keys()?.next().is_some() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// The chunk of `store` where `transfer` stands.
+fn read_chunk<E: KvsEngine>(store: &E, transfer: &Transfer) -> Result<Chunk> {
+    let names = store.tree_names()?;
+    let tree = match transfer.tree {
+        0 => None,
+        n => Some(names.get(n - 1).cloned().ok_or_else(|| {
+            KvsError::ServerErrorMessage("snapshot namespace is gone".to_owned())
+        })?),
+    };
+    let page = tree_of(store, tree.as_deref())?
+        .scan(transfer.cursor.clone(), MAX_PAIRS_PER_SNAPSHOT_CHUNK)?;
+    let next = if page.cursor.is_some() {
+        Some(Transfer {
+            chunk: transfer.chunk + 1,
+            cursor: page.cursor,
+            ..transfer.clone()
+        })
+    } else if transfer.tree < names.len() {
+        Some(Transfer {
+            last_index: transfer.last_index,
+            chunk: transfer.chunk + 1,
+            tree: transfer.tree + 1,
+            cursor: None,
+        })
+    } else {
+        None
+    };
+    Ok(Chunk {
+        tree,
+        pairs: page.items,
+        next,
+    })
+}
+
+// Make `engine` hold exactly the content of `copy`, in every namespace.
+fn restore<E: KvsEngine>(engine: &E, copy: &E) -> Result<()> {
+    restore_tree(engine, copy)?;
+    let names = copy.tree_names()?;
+    for name in engine.tree_names()? {
+        if !names.contains(&name) {
+            let tree = engine.open_tree(&name)?;
+            for key in tree.keys()? {
+                tree.remove(key?)?;
+            }
+        }
+    }
+    for name in names {
+        restore_tree(&engine.open_tree(&name)?, &copy.open_tree(&name)?)?;
+    }
+    Ok(())
+}
+
+// Both sides are walked in key order, the writes to `engine` land on keys
+// already passed.
+fn restore_tree<E: KvsEngine>(engine: &E, copy: &E) -> Result<()> {
+    let mut pairs = engine.iter()?.peekable();
+    for pair in copy.iter()? {
+        let (key, value) = pair?;
+        while let Some(Ok((stale, _))) =
+            pairs.next_if(|pair| pair.as_ref().map_or(true, |(other, _)| *other < key))
+        {
+            engine.remove(stale)?;
+        }
+        match pairs.next_if(|pair| pair.as_ref().map_or(true, |(other, _)| *other == key)) {
+            Some(Ok((_, old))) if old == value => {}
+            Some(Err(e)) => return Err(e),
+            _ => engine.set(key, value)?,
+        }
+    }
+    for pair in pairs {
+        engine.remove(pair?.0)?;
+    }
+    Ok(())
+}
+
+// randomized in [timeout, 2 * timeout) so that candidates rarely collide
+fn election_timeout(option: &RaftOption) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos() as u64);
+    let mut x = nanos ^ option.id.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    let base = option.election_timeout.as_millis() as u64;
+    Duration::from_millis(base + x % base.max(1))
+}
@@ -0,0 +1,230 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::{
+    raft::message::{Entry, NodeId, Snapshot},
+    Result,
+};
+
+const STATE_FILE: &str = "state";
+const LOG_FILE: &str = "log";
+const APPLIED_FILE: &str = "applied";
+// a snapshot lives in `snapshot-<last index>`, written aside in
+// `snapshot-<last index>.tmp`
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_TMP_SUFFIX: &str = ".tmp";
+const SNAPSHOT_META: &str = "meta";
+// the copy of the engine
+const SNAPSHOT_DATA: &str = "data";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct HardState {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+}
+
+// Everything a node must remember across crashes, kept under `<dir>/raft`.
+pub(crate) struct Storage {
+    dir: PathBuf,
+    // index of the last entry applied to the engine, overwritten in place
+    applied: File,
+}
+
+impl Storage {
+    pub(crate) fn open(dir: PathBuf) -> Result<Self> {
+        let dir = dir.join("raft");
+        fs::create_dir_all(&dir)?;
+        let applied = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(APPLIED_FILE))?;
+        Ok(Storage { dir, applied })
+    }
+
+    pub(crate) fn load(&self) -> Result<(HardState, Option<Snapshot>, Vec<Entry>)> {
+        let state_path = self.dir.join(STATE_FILE);
+        let state = if state_path.exists() {
+            serde_json::from_reader(BufReader::new(File::open(state_path)?))?
+        } else {
+            HardState::default()
+        };
+        // snapshots half written or half received are of no use
+        let mut last_snapshot = None;
+        for (last_index, complete) in self.snapshots()? {
+            if complete {
+                last_snapshot = last_snapshot.max(Some(last_index));
+            } else {
+                fs::remove_dir_all(self.snapshot_dir(last_index, false))?;
+            }
+        }
+        let snapshot = match last_snapshot {
+            Some(last_index) => {
+                let meta_path = self.snapshot_dir(last_index, true).join(SNAPSHOT_META);
+                Some(serde_json::from_reader(BufReader::new(File::open(
+                    meta_path,
+                )?))?)
+            }
+            None => None,
+        };
+        let log_path = self.dir.join(LOG_FILE);
+        let mut entries = Vec::new();
+        if log_path.exists() {
+            let reader = BufReader::new(File::open(log_path)?);
+            for entry in Deserializer::from_reader(reader).into_iter() {
+                entries.push(entry?);
+            }
+        }
+        Ok((state, snapshot, entries))
+    }
+
+    // 0 until the first entry is applied
+    pub(crate) fn load_applied(&self) -> Result<u64> {
+        let mut buf = [0; 8];
+        let mut file = &self.applied;
+        file.seek(SeekFrom::Start(0))?;
+        match file.read_exact(&mut buf) {
+            Ok(()) => Ok(u64::from_le_bytes(buf)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Written after each entry applied and, like the engine writes, left to
+    // the OS: a crash in between applies the last entry once more, which
+    // is harmless.
+    pub(crate) fn save_applied(&self, index: u64) -> Result<()> {
+        let mut file = &self.applied;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&index.to_le_bytes())?;
+        Ok(())
+    }
+
+    pub(crate) fn save_state(&self, state: &HardState) -> Result<()> {
+        self.replace(STATE_FILE, |writer| {
+            Ok(serde_json::to_writer(writer, state)?)
+        })
+    }
+
+    // the copy of the engine of the snapshot up to `last_index`
+    pub(crate) fn snapshot_data(&self, last_index: u64) -> PathBuf {
+        self.snapshot_dir(last_index, true).join(SNAPSHOT_DATA)
+    }
+
+    // Where to write the copy of the engine up to `last_index`,
+    // a snapshot once saved.
+    pub(crate) fn prepare_snapshot(&self, last_index: u64) -> Result<PathBuf> {
+        let dir = self.snapshot_dir(last_index, false);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir(&dir)?;
+        Ok(dir.join(SNAPSHOT_DATA))
+    }
+
+    // Earlier snapshots, complete or not, are removed once this one is in place.
+    pub(crate) fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        let tmp_dir = self.snapshot_dir(snapshot.last_index, false);
+        let mut writer = BufWriter::new(File::create(tmp_dir.join(SNAPSHOT_META))?);
+        serde_json::to_writer(&mut writer, snapshot)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        File::open(&tmp_dir)?.sync_all()?;
+        let dir = self.snapshot_dir(snapshot.last_index, true);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::rename(tmp_dir, dir)?;
+        self.sync_dir()?;
+        for (last_index, complete) in self.snapshots()? {
+            if last_index < snapshot.last_index {
+                fs::remove_dir_all(self.snapshot_dir(last_index, complete))?;
+            }
+        }
+        Ok(())
+    }
+
+    // last index of every snapshot, and whether it is complete
+    fn snapshots(&self) -> Result<Vec<(u64, bool)>> {
+        let mut snapshots = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            let Some(suffix) = name.strip_prefix(SNAPSHOT_PREFIX) else {
+                continue;
+            };
+            let (last_index, complete) = match suffix.strip_suffix(SNAPSHOT_TMP_SUFFIX) {
+                Some(last_index) => (last_index, false),
+                None => (suffix, true),
+            };
+            if let Ok(last_index) = last_index.parse() {
+                snapshots.push((last_index, complete));
+            }
+        }
+        Ok(snapshots)
+    }
+
+    fn snapshot_dir(&self, last_index: u64, complete: bool) -> PathBuf {
+        let suffix = if complete { "" } else { SNAPSHOT_TMP_SUFFIX };
+        self.dir
+            .join(format!("{}{}{}", SNAPSHOT_PREFIX, last_index, suffix))
+    }
+
+    // on disk before the entries are acknowledged
+    pub(crate) fn append(&self, entries: &[Entry]) -> Result<()> {
+        let log_path = self.dir.join(LOG_FILE);
+        let created = !log_path.exists();
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(log_path)?;
+        let mut writer = BufWriter::new(file);
+        for entry in entries {
+            serde_json::to_writer(&mut writer, entry)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        if created {
+            self.sync_dir()?;
+        }
+        Ok(())
+    }
+
+    // after a truncation or a compaction
+    pub(crate) fn rewrite(&self, entries: &[Entry]) -> Result<()> {
+        self.replace(LOG_FILE, |writer| {
+            for entry in entries {
+                serde_json::to_writer(&mut *writer, entry)?;
+            }
+            Ok(())
+        })
+    }
+
+    // write aside then rename, so that a crash leaves either version intact,
+    // both synced so that the new one is there for good once this returns
+    fn replace<F>(&self, name: &str, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut BufWriter<File>) -> Result<()>,
+    {
+        let tmp_path = self.dir.join(format!("{}.tmp", name));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        f(&mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp_path, self.dir.join(name))?;
+        self.sync_dir()
+    }
+
+    // makes the files created or renamed in the directory durable
+    fn sync_dir(&self) -> Result<()> {
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+}
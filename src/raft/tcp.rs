@@ -0,0 +1,45 @@
+use std::{net::SocketAddr, time::Duration};
+
+use crate::{
+    raft::{NodeId, RaftMessage, RaftReply, Transport},
+    KvsClient, KvsError, Result,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(200);
+const DEFAULT_SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sends raft messages to other `kvs-server`s over the client protocol,
+/// on a fresh connection per message.
+pub struct TcpTransport {
+    timeout: Duration,
+    // the last chunk of a snapshot waits for the engine to be restored from it
+    snapshot_timeout: Duration,
+}
+
+impl TcpTransport {
+    pub fn new(timeout: Duration, snapshot_timeout: Duration) -> Self {
+        TcpTransport {
+            timeout,
+            snapshot_timeout,
+        }
+    }
+}
+
+impl Default for TcpTransport {
+    fn default() -> Self {
+        Self::new(DEFAULT_TIMEOUT, DEFAULT_SNAPSHOT_TIMEOUT)
+    }
+}
+
+impl Transport for TcpTransport {
+    fn call(&self, _to: NodeId, addr: &str, msg: RaftMessage) -> Result<RaftReply> {
+        let addr: SocketAddr = addr
+            .parse()
+            .map_err(|_| KvsError::ServerErrorMessage(format!("invalid address {}", addr)))?;
+        let read_timeout = match msg {
+            RaftMessage::InstallSnapshot { .. } => self.snapshot_timeout,
+            _ => self.timeout,
+        };
+        KvsClient::connect_timeout(&addr, self.timeout, read_timeout)?.raft(msg)
+    }
+}
@@ -0,0 +1,378 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{BufReader, BufWriter, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError as ChannelTimeoutError, Sender};
+use serde::Deserialize;
+use serde_json::Deserializer;
+
+use crate::{
+    common::{Change, Request, SyncEvent, SyncResponse, WatchEvent},
+    engines::{remove_if_exists, tree_of, ChangeFeed, Events},
+    server::client_closed,
+    KvsEngine, KvsError, Result,
+};
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+// how long a leader waits for a change before looking for new namespaces
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// a namespace, `None` for the default one
+type Namespace = Option<String>;
+
+/// Replication role of a server.
+/// A follower knows its leader, a leader knows nobody.
+#[derive(Default)]
+pub(crate) struct Role {
+    leader: Mutex<Option<SocketAddr>>,
+    // connection to the leader, shut down on promotion
+    link: Mutex<Option<TcpStream>>,
+}
+
+impl Role {
+    pub(crate) fn follower_of(leader: SocketAddr) -> Self {
+        Role {
+            leader: Mutex::new(Some(leader)),
+            link: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn leader(&self) -> Option<SocketAddr> {
+        *self.leader.lock().unwrap()
+    }
+
+    pub(crate) fn promote(&self) {
+        // hold `leader` so that no new link is attached meanwhile
+        let mut leader = self.leader.lock().unwrap();
+        if let Some(link) = self.link.lock().unwrap().take() {
+            let _ = link.shutdown(Shutdown::Both);
+        }
+        *leader = None;
+    }
+
+    // Returns false if the server has been promoted in the meantime.
+    fn attach(&self, link: &TcpStream) -> Result<bool> {
+        let leader = self.leader.lock().unwrap();
+        if leader.is_none() {
+            return Ok(false);
+        }
+        *self.link.lock().unwrap() = Some(link.try_clone()?);
+        Ok(true)
+    }
+}
+
+/// Follow the leader until promoted, reconnecting whenever the link breaks.
+/// A reconnected follower resumes after the last change it applied.
+pub(crate) fn replicate<E: KvsEngine>(engine: E, role: &Role) {
+    // namespace -> sequence number on the leader of the last change applied
+    let mut seqs = HashMap::new();
+    while let Some(leader) = role.leader() {
+        match sync_from(&engine, role, leader, &mut seqs) {
+            Ok(()) => info!("Replication from {} stopped", leader),
+            Err(e) => warn!("Replication from {} failed, cause {}", leader, e),
+        }
+        if role.leader().is_some() {
+            thread::sleep(RETRY_INTERVAL);
+        }
+    }
+    info!("Promoted to leader");
+}
+
+fn sync_from<E: KvsEngine>(
+    engine: &E,
+    role: &Role,
+    leader: SocketAddr,
+    seqs: &mut HashMap<Namespace, u64>,
+) -> Result<()> {
+    let stream = TcpStream::connect(leader)?;
+    if !role.attach(&stream)? {
+        return Ok(());
+    }
+    let mut writer = BufWriter::new(&stream);
+    let since = seqs.iter().map(|(name, &seq)| (name.clone(), seq));
+    serde_json::to_writer(
+        &mut writer,
+        &Request::Sync {
+            since: since.collect(),
+        },
+    )?;
+    writer.flush()?;
+
+    let mut reader = Deserializer::from_reader(BufReader::new(&stream));
+    if let SyncResponse::Err(s) = SyncResponse::deserialize(&mut reader)? {
+        return Err(KvsError::ServerErrorMessage(s));
+    }
+    info!("Start syncing from {}", leader);
+
+    // namespace -> keys of the snapshot being received
+    let mut snapshot_keys: HashMap<Namespace, HashSet<String>> = HashMap::new();
+    for event in reader.into_iter() {
+        match event? {
+            SyncEvent::Pair {
+                namespace,
+                key,
+                value,
+            } => {
+                // a snapshot cut short cannot be resumed
+                seqs.remove(&namespace);
+                let tree = tree_of(engine, namespace.as_deref())?;
+                if tree.get(key.clone())?.as_ref() != Some(&value) {
+                    tree.set(key.clone(), value)?;
+                }
+                snapshot_keys.entry(namespace).or_default().insert(key);
+            }
+            SyncEvent::SnapshotDone { namespace, seq } => {
+                let keys = snapshot_keys.remove(&namespace).unwrap_or_default();
+                let tree = tree_of(engine, namespace.as_deref())?;
+                // drop what the leader no longer has
+                for pair in tree.iter()? {
+                    let (key, _) = pair?;
+                    if !keys.contains(&key) {
+                        remove_if_exists(&tree, key)?;
+                    }
+                }
+                match seq {
+                    Some(seq) => seqs.insert(namespace.clone(), seq),
+                    None => seqs.remove(&namespace),
+                };
+                let name = namespace.as_deref().unwrap_or("the default namespace");
+                info!("Snapshot of {} from {} applied", name, leader);
+            }
+            SyncEvent::Change {
+                namespace,
+                seq,
+                event,
+            } => {
+                let tree = tree_of(engine, namespace.as_deref())?;
+                match event {
+                    WatchEvent::Set { key, value, .. } => tree.set(key, value)?,
+                    WatchEvent::Remove { key, .. } => remove_if_exists(&tree, key)?,
+                }
+                if let Some(seq) = seq {
+                    seqs.insert(namespace, seq);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// A namespace of the leader being streamed.
+struct Synced<E: KvsEngine> {
+    tree: E,
+    // changes after the last one sent, read on from where it stopped,
+    // `None` if the engine does not log mutations
+    feed: Option<ChangeFeed>,
+}
+
+/// Every namespace of a leader, streamed to a follower.
+///
+/// A namespace is subscribed to first, then sent in full, or from where the
+/// follower left it if the leader still logs every change since. Changes are
+/// read from the leader's log, which the follower may resume from later,
+/// by a feed kept for the whole stream.
+/// Engines without a log send changes as they are watched.
+pub(crate) struct SyncSource<E: KvsEngine> {
+    engine: E,
+    synced: BTreeMap<Namespace, Synced<E>>,
+    // namespaces subscribed to, not sent yet
+    pending: Vec<Namespace>,
+    // events of every namespace
+    sender: Sender<(Namespace, WatchEvent)>,
+    events: Receiver<(Namespace, WatchEvent)>,
+    // ends the threads forwarding events once the follower is gone
+    done: Arc<AtomicBool>,
+}
+
+impl<E: KvsEngine> SyncSource<E> {
+    pub(crate) fn new(engine: E) -> Result<Self> {
+        let (sender, events) = channel::unbounded();
+        let mut source = SyncSource {
+            engine,
+            synced: BTreeMap::new(),
+            pending: Vec::new(),
+            sender,
+            events,
+            done: Arc::new(AtomicBool::new(false)),
+        };
+        source.subscribe_new()?;
+        Ok(source)
+    }
+
+    /// Stream every namespace to the follower on `stream`, until it is gone.
+    /// `since` is where it left each namespace.
+    pub(crate) fn serve(mut self, since: Vec<(Namespace, u64)>, stream: TcpStream) -> Result<()> {
+        let since: HashMap<Namespace, u64> = since.into_iter().collect();
+        let mut writer = BufWriter::new(stream);
+        loop {
+            for name in std::mem::take(&mut self.pending) {
+                self.start(name.clone(), since.get(&name).copied(), &mut writer)?;
+            }
+            for (name, synced) in self.synced.iter_mut() {
+                synced.send_logged(name, &mut writer)?;
+            }
+            writer.flush()?;
+            // any change published wakes the leader up, logs tell which ones
+            match self.events.recv_timeout(POLL_INTERVAL) {
+                Ok(event) => {
+                    let events: Vec<_> = self.events.try_iter().collect();
+                    for (name, event) in std::iter::once(event).chain(events) {
+                        self.send_watched(name, event, &mut writer)?;
+                    }
+                }
+                Err(ChannelTimeoutError::Timeout) if client_closed(writer.get_ref())? => {
+                    return Ok(())
+                }
+                Err(ChannelTimeoutError::Timeout) => self.subscribe_new()?,
+                Err(ChannelTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+
+    // Namespaces created since are subscribed to, and sent next.
+    fn subscribe_new(&mut self) -> Result<()> {
+        let mut names = vec![None];
+        names.extend(self.engine.tree_names()?.into_iter().map(Some));
+        for name in names {
+            if self.synced.contains_key(&name) {
+                continue;
+            }
+            let tree = tree_of(&self.engine, name.as_deref())?;
+            let events = tree.watch(String::new())?;
+            let sender = self.sender.clone();
+            let done = Arc::clone(&self.done);
+            let forwarded = name.clone();
+            thread::spawn(move || forward(forwarded, events, sender, done));
+            self.synced
+                .insert(name.clone(), Synced { tree, feed: None });
+            self.pending.push(name);
+        }
+        Ok(())
+    }
+
+    fn start(
+        &mut self,
+        name: Namespace,
+        since: Option<u64>,
+        writer: &mut BufWriter<TcpStream>,
+    ) -> Result<()> {
+        let synced = self.synced.get_mut(&name).expect("subscribed namespace");
+        let last_seq = match synced.tree.last_seq() {
+            Some(last_seq) => last_seq,
+            None => return send_snapshot(&synced.tree, name, None, writer),
+        };
+        // A follower ahead of the leader has followed another one,
+        // and starts over, as does one the log no longer holds changes for.
+        if let Some(since) = since.filter(|&since| since <= last_seq) {
+            match synced.tree.change_feed(since) {
+                Ok(feed) => {
+                    synced.feed = Some(feed);
+                    return Ok(());
+                }
+                Err(KvsError::HistoryTruncated(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        synced.feed = Some(synced.tree.change_feed(last_seq)?);
+        send_snapshot(&synced.tree, name, Some(last_seq), writer)
+    }
+
+    // Changes of namespaces with a log are read from it instead.
+    fn send_watched(
+        &self,
+        name: Namespace,
+        event: WatchEvent,
+        writer: &mut BufWriter<TcpStream>,
+    ) -> Result<()> {
+        match self.synced.get(&name) {
+            Some(synced) if synced.feed.is_none() => {
+                let change = SyncEvent::Change {
+                    namespace: name,
+                    seq: None,
+                    event,
+                };
+                serde_json::to_writer(writer, &change)?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<E: KvsEngine> Drop for SyncSource<E> {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<E: KvsEngine> Synced<E> {
+    fn send_logged(&mut self, name: &Namespace, writer: &mut BufWriter<TcpStream>) -> Result<()> {
+        let feed = match &mut self.feed {
+            Some(feed) => feed,
+            None => return Ok(()),
+        };
+        for change in feed {
+            let Change {
+                seq: change_seq,
+                event,
+            } = change?;
+            let change = SyncEvent::Change {
+                namespace: name.clone(),
+                seq: Some(change_seq),
+                event,
+            };
+            serde_json::to_writer(&mut *writer, &change)?;
+        }
+        Ok(())
+    }
+}
+
+// Runs until the follower is gone, the subscription ends with it.
+fn forward(
+    name: Namespace,
+    mut events: Events,
+    sender: Sender<(Namespace, WatchEvent)>,
+    done: Arc<AtomicBool>,
+) {
+    while !done.load(Ordering::Relaxed) {
+        match events.next_timeout(POLL_INTERVAL) {
+            Ok(event) => {
+                if sender.send((name.clone(), event)).is_err() {
+                    return;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+// Pairs are read while writes go on, changes replayed on top of them
+// bring the follower up to date.
+fn send_snapshot<E: KvsEngine>(
+    tree: &E,
+    namespace: Namespace,
+    seq: Option<u64>,
+    writer: &mut BufWriter<TcpStream>,
+) -> Result<()> {
+    for pair in tree.iter()? {
+        let (key, value) = pair?;
+        let pair = SyncEvent::Pair {
+            namespace: namespace.clone(),
+            key,
+            value,
+        };
+        serde_json::to_writer(&mut *writer, &pair)?;
+    }
+    serde_json::to_writer(&mut *writer, &SyncEvent::SnapshotDone { namespace, seq })?;
+    Ok(())
+}
@@ -1,16 +1,35 @@
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    io::{self, BufReader, BufWriter, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::{Component, Path, PathBuf},
+    sync::{mpsc::RecvTimeoutError, Arc},
+    thread,
+    time::Duration,
 };
 
 use serde_json::Deserializer;
 
 use crate::{
-    common::{GetResponse, RemoveResponse, Request, SetResponse},
+    backup::backup,
+    common::{
+        GetResponse, KeysResponse, MembershipResponse, MigrateResponse, PromoteResponse,
+        RaftResponse, RemoveResponse, Request, ScanResponse, SetResponse, SnapshotResponse,
+        SyncResponse, TransactionResponse, WatchResponse,
+    },
+    engines::{tree_of, Events, Transaction},
+    migration::{get_moving, migrate, remove_moving, set_moving, Migrations, Route},
+    raft::{RaftNode, RaftService},
+    replication::{replicate, Role, SyncSource},
     thread_pool::ThreadPool,
-    KvsEngine, Result,
+    KvsEngine, KvsError, Result,
 };
 
+const NOT_IN_CLUSTER: &str = "Not a member of a raft cluster";
+const NO_TRANSACTION: &str = "No transaction is open";
+const NO_BACKUP_DIR: &str = "Snapshots and backups are disabled, no backup directory is set";
+// how long a quiet event stream waits before checking on its client
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct KvsServer<E, P>
 where
     E: KvsEngine,
@@ -18,6 +37,11 @@ where
 {
     engine: E,
     pool: P,
+    role: Arc<Role>,
+    raft: Option<Arc<dyn RaftService>>,
+    migrations: Arc<Migrations>,
+    // snapshots and backups asked for by clients are written under it only
+    backup_dir: Option<PathBuf>,
 }
 
 impl<E, P> KvsServer<E, P>
@@ -26,18 +50,62 @@ where
     P: ThreadPool,
 {
     pub fn new(engine: E, pool: P) -> Self {
-        Self { engine, pool }
+        Self {
+            engine,
+            pool,
+            role: Arc::new(Role::default()),
+            raft: None,
+            migrations: Arc::new(Migrations::default()),
+            backup_dir: None,
+        }
+    }
+
+    /// Serve as a read-only follower of `leader` until promoted.
+    pub fn replica_of(mut self, leader: SocketAddr) -> Self {
+        self.role = Arc::new(Role::follower_of(leader));
+        self
+    }
+
+    /// Keep the routes of migrations in `dir`, the data directory of the
+    /// store, so that a restarted server still knows which keys moved.
+    pub fn with_data_dir(mut self, dir: &Path) -> Result<Self> {
+        self.migrations = Arc::new(Migrations::open(dir)?);
+        Ok(self)
+    }
+
+    /// Let clients write snapshots and backups of the store, at paths
+    /// relative to `dir`.
+    pub fn with_backup_dir(mut self, dir: PathBuf) -> Self {
+        self.backup_dir = Some(dir);
+        self
     }
 
     pub fn run<T>(&mut self, addr: &T) -> Result<()>
     where
         T: ToSocketAddrs,
     {
-        for stream in TcpListener::bind(addr)?.incoming() {
+        let listener = TcpListener::bind(addr)?;
+        if self.role.leader().is_some() {
             let engine = self.engine.clone();
+            let role = Arc::clone(&self.role);
+            thread::spawn(move || replicate(engine, &role));
+        }
+        for stream in listener.incoming() {
+            let engine = self.engine.clone();
+            let role = Arc::clone(&self.role);
+            let raft = self.raft.clone();
+            let migrations = Arc::clone(&self.migrations);
+            let backup_dir = self.backup_dir.clone();
             self.pool.spawn(move || match stream {
                 Ok(stream) => {
-                    if let Err(e) = Self::serve(engine, stream) {
+                    if let Err(e) = Self::serve(
+                        engine,
+                        &role,
+                        raft.as_deref(),
+                        &migrations,
+                        backup_dir.as_deref(),
+                        stream,
+                    ) {
                         error!("Error when serving client: {}", e)
                     }
                 }
@@ -49,7 +117,14 @@ where
         Ok(())
     }
 
-    fn serve(engine: E, tcp_stream: TcpStream) -> Result<()> {
+    fn serve(
+        engine: E,
+        role: &Role,
+        raft: Option<&dyn RaftService>,
+        migrations: &Migrations,
+        backup_dir: Option<&Path>,
+        tcp_stream: TcpStream,
+    ) -> Result<()> {
         let client_addr = tcp_stream.peer_addr()?;
         let reader = BufReader::new(&tcp_stream);
         let mut writer = BufWriter::new(&tcp_stream);
@@ -63,29 +138,332 @@ where
             }};
         }
 
+        // the transaction open on this connection, with its namespace
+        let mut txn: Option<(Option<String>, E::Transaction)> = None;
+
         for request in Deserializer::from_reader(reader).into_iter() {
             match request? {
-                Request::Get { key } => {
-                    send_resp!(match engine.get(key) {
+                Request::Get { key, namespace } if txn.is_some() => {
+                    let result = transaction_of(&mut txn, &namespace, migrations, &key)
+                        .and_then(|tx| tx.get(key).map_err(|e| e.to_string()));
+                    send_resp!(match result {
                         Ok(value) => GetResponse::Ok(value),
+                        Err(e) => GetResponse::Err(e),
+                    })
+                }
+                Request::Set {
+                    key,
+                    value,
+                    namespace,
+                } if txn.is_some() => {
+                    let result = transaction_of(&mut txn, &namespace, migrations, &key)
+                        .and_then(|tx| tx.set(key, value).map_err(|e| e.to_string()));
+                    send_resp!(match result {
+                        Ok(_) => SetResponse::Ok(()),
+                        Err(e) => SetResponse::Err(e),
+                    })
+                }
+                Request::Remove { key, namespace } if txn.is_some() => {
+                    let result = transaction_of(&mut txn, &namespace, migrations, &key)
+                        .and_then(|tx| tx.remove(key).map_err(|e| e.to_string()));
+                    send_resp!(match result {
+                        Ok(_) => RemoveResponse::Ok(()),
+                        Err(e) => RemoveResponse::Err(e),
+                    })
+                }
+                // Migrations move keys of every namespace by their hash.
+                Request::Get { key, namespace } => {
+                    let result = tree_of(&engine, namespace.as_deref()).and_then(|tree| {
+                        match migrations.route(&key) {
+                            Route::Local => tree.get(key),
+                            Route::Moving(target) => get_moving(&tree, target, namespace, key),
+                            Route::Moved(target) => Err(KvsError::Moved(target)),
+                        }
+                    });
+                    send_resp!(match result {
+                        Ok(value) => GetResponse::Ok(value),
+                        Err(KvsError::Moved(target)) => GetResponse::Moved(target),
                         Err(e) => GetResponse::Err(e.to_string()),
                     })
                 }
-                Request::Set { key, value } => {
-                    send_resp!(match engine.set(key, value) {
+                Request::Set {
+                    key,
+                    value,
+                    namespace,
+                } => {
+                    let result =
+                        match role.leader() {
+                            Some(leader) => Err(KvsError::Redirect(leader)),
+                            None => tree_of(&engine, namespace.as_deref()).and_then(|tree| {
+                                match migrations.route(&key) {
+                                    Route::Local => tree.set(key, value),
+                                    Route::Moving(target) => {
+                                        set_moving(&tree, migrations, target, namespace, key, value)
+                                    }
+                                    Route::Moved(target) => Err(KvsError::Moved(target)),
+                                }
+                            }),
+                        };
+                    send_resp!(match result {
                         Ok(_) => SetResponse::Ok(()),
+                        Err(KvsError::Redirect(leader)) => SetResponse::Redirect(leader),
+                        Err(KvsError::Moved(target)) => SetResponse::Moved(target),
                         Err(e) => SetResponse::Err(e.to_string()),
                     })
                 }
-                Request::Remove { key } => {
-                    send_resp!(match engine.remove(key) {
+                Request::Remove { key, namespace } => {
+                    let result =
+                        match role.leader() {
+                            Some(leader) => Err(KvsError::Redirect(leader)),
+                            None => tree_of(&engine, namespace.as_deref()).and_then(|tree| {
+                                match migrations.route(&key) {
+                                    Route::Local => tree.remove(key),
+                                    Route::Moving(target) => {
+                                        remove_moving(&tree, migrations, target, namespace, key)
+                                    }
+                                    Route::Moved(target) => Err(KvsError::Moved(target)),
+                                }
+                            }),
+                        };
+                    send_resp!(match result {
                         Ok(_) => RemoveResponse::Ok(()),
+                        Err(KvsError::Redirect(leader)) => RemoveResponse::Redirect(leader),
+                        Err(KvsError::Moved(target)) => RemoveResponse::Moved(target),
                         Err(e) => RemoveResponse::Err(e.to_string()),
                     })
                 }
+                // listings are of the local store, migrated keys are not followed
+                Request::Keys {
+                    cursor,
+                    limit,
+                    namespace,
+                } => {
+                    let result = tree_of(&engine, namespace.as_deref())
+                        .and_then(|tree| tree.list_keys(cursor, limit));
+                    send_resp!(match result {
+                        Ok(page) => KeysResponse::Ok(page),
+                        Err(e) => KeysResponse::Err(e.to_string()),
+                    })
+                }
+                Request::Scan {
+                    cursor,
+                    limit,
+                    namespace,
+                } => {
+                    let result = tree_of(&engine, namespace.as_deref())
+                        .and_then(|tree| tree.scan(cursor, limit));
+                    send_resp!(match result {
+                        Ok(page) => ScanResponse::Ok(page),
+                        Err(e) => ScanResponse::Err(e.to_string()),
+                    })
+                }
+                Request::Watch {
+                    key_or_prefix,
+                    namespace,
+                } => match tree_of(&engine, namespace.as_deref())
+                    .and_then(|tree| tree.watch(key_or_prefix))
+                {
+                    Ok(events) => {
+                        send_resp!(WatchResponse::Ok(()));
+                        // The connection becomes an event stream from now on.
+                        // Streaming happens on its own thread so that
+                        // long-lived watchers do not occupy the pool.
+                        let stream = tcp_stream.try_clone()?;
+                        thread::spawn(move || {
+                            if let Err(e) = stream_events(events, stream) {
+                                debug!("Watcher {} is gone, cause {}", client_addr, e);
+                            }
+                        });
+                        return Ok(());
+                    }
+                    Err(e) => send_resp!(WatchResponse::Err(e.to_string())),
+                },
+                Request::Sync { since } => match SyncSource::new(engine.clone()) {
+                    Ok(source) => {
+                        send_resp!(SyncResponse::Ok(()));
+                        let stream = tcp_stream.try_clone()?;
+                        thread::spawn(move || {
+                            if let Err(e) = source.serve(since, stream) {
+                                info!("Follower {} is gone, cause {}", client_addr, e);
+                            }
+                        });
+                        return Ok(());
+                    }
+                    Err(e) => send_resp!(SyncResponse::Err(e.to_string())),
+                },
+                Request::Promote => {
+                    role.promote();
+                    send_resp!(PromoteResponse::Ok(()))
+                }
+                Request::Raft(msg) => send_resp!(match raft {
+                    Some(raft) => RaftResponse::Ok(raft.handle(msg)),
+                    None => RaftResponse::Err(NOT_IN_CLUSTER.to_owned()),
+                }),
+                Request::AddNode { id, addr } => send_resp!(membership_response(
+                    raft.map(|raft| raft.add_node(id, addr))
+                )),
+                Request::RemoveNode { id } => {
+                    send_resp!(membership_response(raft.map(|raft| raft.remove_node(id))))
+                }
+                // only the leader owns its keys, as for writes
+                Request::Migrate { ranges, target } => {
+                    let result = match (role.leader(), raft) {
+                        (Some(leader), _) => Err(KvsError::Redirect(leader)),
+                        (None, Some(raft)) => raft
+                            .ensure_leader()
+                            .and_then(|()| migrate(&engine, migrations, ranges, target)),
+                        (None, None) => migrate(&engine, migrations, ranges, target),
+                    };
+                    send_resp!(match result {
+                        Ok(moved) => MigrateResponse::Ok(moved),
+                        Err(KvsError::Redirect(leader)) => MigrateResponse::Redirect(leader),
+                        Err(e) => MigrateResponse::Err(e.to_string()),
+                    })
+                }
+                Request::Snapshot { dest_dir } => send_resp!(snapshot_response(
+                    backup_path(backup_dir, &dest_dir)
+                        .and_then(|dest_dir| engine.snapshot(&dest_dir))
+                )),
+                Request::Backup { archive } => send_resp!(snapshot_response(
+                    backup_path(backup_dir, &archive).and_then(|archive| backup(&engine, &archive))
+                )),
+                Request::Begin { namespace } => {
+                    let result = match (role.leader(), &txn) {
+                        (Some(leader), _) => Err(KvsError::Redirect(leader)),
+                        (None, Some(_)) => Err(KvsError::ServerErrorMessage(
+                            "A transaction is already open".to_owned(),
+                        )),
+                        (None, None) => {
+                            tree_of(&engine, namespace.as_deref()).and_then(|tree| tree.begin())
+                        }
+                    };
+                    send_resp!(transaction_response(result.map(|tx| {
+                        txn = Some((namespace, tx));
+                    })))
+                }
+                Request::Commit => send_resp!(match txn.take() {
+                    Some((_, tx)) => transaction_response(tx.commit()),
+                    None => TransactionResponse::Err(NO_TRANSACTION.to_owned()),
+                }),
+                Request::Abort => send_resp!(match txn.take() {
+                    Some(_) => TransactionResponse::Ok(()),
+                    None => TransactionResponse::Err(NO_TRANSACTION.to_owned()),
+                }),
             }
         }
 
         Ok(())
     }
 }
+
+impl<E, P> KvsServer<RaftNode<E>, P>
+where
+    E: KvsEngine,
+    P: ThreadPool,
+{
+    /// Serve as a member of the raft cluster `node` belongs to.
+    /// Writes sent to a follower are redirected to the leader.
+    pub fn with_raft(node: RaftNode<E>, pool: P) -> Self {
+        Self {
+            engine: node.clone(),
+            pool,
+            role: Arc::new(Role::default()),
+            raft: Some(Arc::new(node)),
+            migrations: Arc::new(Migrations::default()),
+            backup_dir: None,
+        }
+    }
+}
+
+fn membership_response(result: Option<Result<()>>) -> MembershipResponse {
+    match result {
+        Some(Ok(())) => MembershipResponse::Ok(()),
+        Some(Err(KvsError::Redirect(leader))) => MembershipResponse::Redirect(leader),
+        Some(Err(e)) => MembershipResponse::Err(e.to_string()),
+        None => MembershipResponse::Err(NOT_IN_CLUSTER.to_owned()),
+    }
+}
+
+// A transaction is served by this server alone, so keys migrated away
+// cannot be part of it.
+fn transaction_of<'a, T>(
+    txn: &'a mut Option<(Option<String>, T)>,
+    namespace: &Option<String>,
+    migrations: &Migrations,
+    key: &str,
+) -> std::result::Result<&'a mut T, String> {
+    match txn {
+        Some((name, _)) if name != namespace => {
+            Err("Key is not in the namespace of the transaction".to_owned())
+        }
+        Some(_) if !matches!(migrations.route(key), Route::Local) => {
+            Err(format!("Key {:?} is migrated to another server", key))
+        }
+        Some((_, tx)) => Ok(tx),
+        None => Err(NO_TRANSACTION.to_owned()),
+    }
+}
+
+fn transaction_response(result: Result<()>) -> TransactionResponse {
+    match result {
+        Ok(()) => TransactionResponse::Ok(()),
+        Err(KvsError::TransactionConflict) => TransactionResponse::Conflict,
+        Err(KvsError::Redirect(leader)) => TransactionResponse::Redirect(leader),
+        Err(e) => TransactionResponse::Err(e.to_string()),
+    }
+}
+
+// Clients may only write under the backup directory: `path` must be relative
+// to it and must not climb out of it.
+fn backup_path(backup_dir: Option<&Path>, path: &str) -> Result<PathBuf> {
+    let backup_dir =
+        backup_dir.ok_or_else(|| KvsError::ServerErrorMessage(NO_BACKUP_DIR.to_owned()))?;
+    let path = Path::new(path);
+    let inside = path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !inside {
+        return Err(KvsError::ServerErrorMessage(format!(
+            "Path {:?} is not inside the backup directory",
+            path
+        )));
+    }
+    Ok(backup_dir.join(path))
+}
+
+fn snapshot_response(result: Result<()>) -> SnapshotResponse {
+    match result {
+        Ok(()) => SnapshotResponse::Ok(()),
+        Err(e) => SnapshotResponse::Err(e.to_string()),
+    }
+}
+
+// Returning drops `events`, which ends the subscription.
+fn stream_events(mut events: Events, tcp_stream: TcpStream) -> Result<()> {
+    let mut writer = BufWriter::new(tcp_stream);
+    loop {
+        match events.next_timeout(PROBE_INTERVAL) {
+            Ok(event) => {
+                serde_json::to_writer(&mut writer, &event)?;
+                writer.flush()?;
+            }
+            Err(RecvTimeoutError::Timeout) if client_closed(writer.get_ref())? => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+// Peeks without blocking: end of file or an error means the client is gone.
+pub(crate) fn client_closed(tcp_stream: &TcpStream) -> Result<bool> {
+    tcp_stream.set_nonblocking(true)?;
+    let result = tcp_stream.peek(&mut [0]);
+    tcp_stream.set_nonblocking(false)?;
+    match result {
+        Ok(0) => Ok(true),
+        Ok(_) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(_) => Ok(true),
+    }
+}
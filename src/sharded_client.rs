@@ -0,0 +1,202 @@
+use std::{collections::BTreeMap, net::SocketAddr, thread};
+
+use crate::{common::HashRange, KvsClient, KvsClientPool, PoolOption, Result};
+
+const DEFAULT_VIRTUAL_NODES: usize = 128;
+
+/// A client spreading keys over several independent kvs servers.
+///
+/// Every server owns a number of virtual nodes on a hash ring and a key
+/// belongs to the first virtual node at or after its hash, so adding or
+/// removing a server only moves the keys next to its virtual nodes.
+#[derive(Clone)]
+pub struct ShardedKvsClient {
+    addrs: Vec<SocketAddr>,
+    virtual_nodes: usize,
+    option: PoolOption,
+    shards: Vec<KvsClientPool>,
+    // hash of a virtual node -> index of its server in `shards`
+    ring: BTreeMap<u64, usize>,
+}
+
+impl ShardedKvsClient {
+    pub fn new(addrs: &[SocketAddr], option: PoolOption) -> Self {
+        Self::with_virtual_nodes(addrs, DEFAULT_VIRTUAL_NODES, option)
+    }
+
+    pub fn with_virtual_nodes(
+        addrs: &[SocketAddr],
+        virtual_nodes: usize,
+        option: PoolOption,
+    ) -> Self {
+        assert!(!addrs.is_empty(), "a cluster needs at least one server");
+        let mut ring = BTreeMap::new();
+        for (shard, addr) in addrs.iter().enumerate() {
+            for i in 0..virtual_nodes.max(1) {
+                ring.insert(key_hash(format!("{}#{}", addr, i).as_bytes()), shard);
+            }
+        }
+        ShardedKvsClient {
+            addrs: addrs.to_vec(),
+            virtual_nodes,
+            option: option.clone(),
+            shards: addrs
+                .iter()
+                .map(|&addr| KvsClientPool::new(addr, option.clone()))
+                .collect(),
+            ring,
+        }
+    }
+
+    /// Address of the server `key` lives on.
+    pub fn shard_of(&self, key: &str) -> SocketAddr {
+        self.addrs[self.locate(key)]
+    }
+
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.shards[self.locate(&key)].set(key, value)
+    }
+
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.shards[self.locate(&key)].get(key)
+    }
+
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.shards[self.locate(&key)].remove(key)
+    }
+
+    /// Get many keys at once, querying the servers in parallel.
+    /// Values come back in the order of `keys`.
+    pub fn mget(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        let batches = self.split(keys.into_iter().enumerate(), |(_, key)| key);
+        let results = self.fan_out(batches, |shard, batch| {
+            batch
+                .into_iter()
+                .map(|(i, key)| Ok((i, shard.get(key)?)))
+                .collect::<Result<Vec<_>>>()
+        });
+        for result in results {
+            for (i, value) in result? {
+                values[i] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Set many pairs at once, writing to the servers in parallel.
+    /// This is not atomic, pairs written before a failure stay written.
+    pub fn mset(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let batches = self.split(pairs.into_iter(), |(key, _)| key);
+        self.fan_out(batches, |shard, batch| {
+            batch
+                .into_iter()
+                .try_for_each(|(key, value)| shard.set(key, value))
+        })
+        .into_iter()
+        .collect()
+    }
+
+    /// Add the server at `addr` to the cluster.
+    ///
+    /// The keys it takes over are migrated from their current owners,
+    /// which then answer clients still using the old server list with
+    /// where the keys have moved.
+    pub fn add_server(&self, addr: SocketAddr) -> Result<ShardedKvsClient> {
+        let mut addrs = self.addrs.clone();
+        addrs.push(addr);
+        let grown = Self::with_virtual_nodes(&addrs, self.virtual_nodes, self.option.clone());
+        let new_shard = addrs.len() - 1;
+
+        // The old ring has no point inside an arc of the new one,
+        // so every arc of the new server comes from a single old owner.
+        let mut ranges: Vec<Vec<HashRange>> = self.addrs.iter().map(|_| Vec::new()).collect();
+        for (range, shard) in grown.arcs() {
+            if shard == new_shard {
+                ranges[self.owner_of(range.end)].push(range);
+            }
+        }
+        for (source, ranges) in self.addrs.iter().zip(ranges) {
+            if !ranges.is_empty() {
+                let moved = KvsClient::connect(source)?.migrate(ranges, addr)?;
+                info!("Moved {} keys from {} to {}", moved, source, addr);
+            }
+        }
+        Ok(grown)
+    }
+
+    // every virtual node owns the hashes since the previous one
+    fn arcs(&self) -> impl Iterator<Item = (HashRange, usize)> + '_ {
+        let last = *self.ring.keys().next_back().unwrap();
+        let starts = std::iter::once(last).chain(self.ring.keys().copied());
+        starts
+            .zip(&self.ring)
+            .map(|(start, (&end, &shard))| (HashRange { start, end }, shard))
+    }
+
+    fn locate(&self, key: &str) -> usize {
+        self.owner_of(key_hash(key.as_bytes()))
+    }
+
+    fn owner_of(&self, h: u64) -> usize {
+        let (_, &shard) = self
+            .ring
+            .range(h..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .unwrap();
+        shard
+    }
+
+    // group items by the shard of their key
+    fn split<T, I, F>(&self, items: I, key_of: F) -> Vec<Vec<T>>
+    where
+        I: Iterator<Item = T>,
+        F: Fn(&T) -> &String,
+    {
+        let mut batches: Vec<Vec<T>> = self.shards.iter().map(|_| Vec::new()).collect();
+        for item in items {
+            let shard = self.locate(key_of(&item));
+            batches[shard].push(item);
+        }
+        batches
+    }
+
+    // run `f` on every non-empty batch, one thread per shard
+    fn fan_out<T, R, F>(&self, batches: Vec<Vec<T>>, f: F) -> Vec<Result<R>>
+    where
+        T: Send,
+        R: Send,
+        F: Fn(&KvsClientPool, Vec<T>) -> Result<R> + Sync,
+    {
+        thread::scope(|scope| {
+            let handles: Vec<_> = batches
+                .into_iter()
+                .zip(&self.shards)
+                .filter(|(batch, _)| !batch.is_empty())
+                .map(|(batch, shard)| {
+                    let f = &f;
+                    scope.spawn(move || f(shard, batch))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("shard worker panicked"))
+                .collect()
+        })
+    }
+}
+
+// FNV-1a followed by a final mix to spread similar keys over the ring.
+// Unlike `DefaultHasher` it is stable across processes and Rust versions,
+// so that every client agrees on the placement of a key.
+pub(crate) fn key_hash(bytes: &[u8]) -> u64 {
+    let mut h = bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
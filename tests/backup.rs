@@ -0,0 +1,219 @@
+use assert_cmd::prelude::*;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    backup, engine_type_of, restore, EngineType, KvStore, KvsClient, KvsEngine, KvsError,
+    KvsServer, Result, SledWrapper,
+};
+use predicates::str::contains;
+use std::net::SocketAddr;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A snapshot holds every write made before it, whatever is written or
+// compacted while it is taken.
+#[test]
+fn kvs_snapshot_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (store, done) = (store.clone(), Arc::clone(&done));
+        // overwriting the same keys keeps triggering compaction
+        thread::spawn(move || -> Result<()> {
+            let mut round = 0;
+            while !done.load(Ordering::SeqCst) {
+                for i in 1000..1100 {
+                    store.set(format!("key{}", i), format!("{:0>1000}", round))?;
+                }
+                round += 1;
+            }
+            Ok(())
+        })
+    };
+
+    let snapshots = TempDir::new().unwrap();
+    for n in 0..5 {
+        let dest = snapshots.path().join(n.to_string());
+        store.snapshot(&dest)?;
+        assert_eq!(engine_type_of(&dest)?, Some(EngineType::kvs));
+        let copy = KvStore::open(&dest)?;
+        for i in 0..1000 {
+            assert_eq!(copy.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    writer.join().unwrap()?;
+
+    // the snapshot is a store of its own
+    let dest = snapshots.path().join("0");
+    let copy = KvStore::open(&dest)?;
+    copy.set("key0".to_owned(), "changed".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn snapshot_into_non_empty_dir() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let dest = TempDir::new().unwrap();
+    store.snapshot(dest.path())?;
+    assert!(matches!(
+        store.snapshot(dest.path()),
+        Err(KvsError::DirectoryNotEmpty(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn sled_backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = SledWrapper::new(sled::open(temp_dir.path())?);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+
+    let archive_dir = TempDir::new().unwrap();
+    let archive = archive_dir.path().join("backup.tar");
+    backup(&engine, &archive)?;
+    engine.remove("key1".to_owned())?;
+
+    let restored = TempDir::new().unwrap();
+    restore(&archive, restored.path())?;
+    assert_eq!(engine_type_of(restored.path())?, Some(EngineType::sled));
+    let engine = SledWrapper::new(sled::open(restored.path())?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // never over an existing store
+    drop(engine);
+    assert!(matches!(
+        restore(&archive, restored.path()),
+        Err(KvsError::DirectoryNotEmpty(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn cli_backup_and_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let archive_dir = TempDir::new().unwrap();
+    let archive = archive_dir.path().join("backup.tar");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("backup")
+        .arg(&archive)
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let restored = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("restore")
+        .arg(&archive)
+        .current_dir(&restored)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&restored)
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("restore")
+        .arg(&archive)
+        .current_dir(&restored)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn server_snapshot_and_backup() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let backups = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4025".parse().unwrap();
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let backup_dir = backups.path().to_path_buf();
+    thread::spawn(move || {
+        KvsServer::new(engine, pool)
+            .with_backup_dir(backup_dir)
+            .run(&addr)
+    });
+    thread::sleep(Duration::from_millis(500));
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value1".to_owned())?;
+
+    // paths are taken relative to the backup directory of the server
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["snapshot", "snapshot", "--addr", "127.0.0.1:4025"])
+        .assert()
+        .success();
+    let copy = KvStore::open(backups.path().join("snapshot"))?;
+    assert_eq!(copy.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["backup", "backup.tar", "--addr", "127.0.0.1:4025"])
+        .assert()
+        .success();
+    let restored = TempDir::new().unwrap();
+    restore(&backups.path().join("backup.tar"), restored.path())?;
+    let copy = KvStore::open(restored.path())?;
+    assert_eq!(copy.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // the snapshot directory is taken now
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["snapshot", "snapshot", "--addr", "127.0.0.1:4025"])
+        .assert()
+        .failure()
+        .stderr(contains("not empty"));
+
+    // nothing is written outside of the backup directory
+    let outside = TempDir::new().unwrap();
+    let absolute = outside.path().join("snapshot");
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client
+        .snapshot(absolute.to_string_lossy().into_owned())
+        .is_err());
+    assert!(client.backup("../backup.tar".to_owned()).is_err());
+    assert!(client.snapshot("snapshot/../..".to_owned()).is_err());
+    assert!(!absolute.exists());
+    assert!(!backups.path().parent().unwrap().join("backup.tar").exists());
+    Ok(())
+}
+
+#[test]
+fn server_without_backup_dir() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4030".parse().unwrap();
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || KvsServer::new(engine, pool).run(&addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.snapshot("snapshot".to_owned()).is_err());
+    assert!(client.backup("backup.tar".to_owned()).is_err());
+    assert!(!temp_dir.path().join("snapshot").exists());
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use kvs::{KvStore, KvsEngine, KvsError, Result, StoreOption, WatchEvent};
+use tempfile::TempDir;
+
+// Should stream every mutation in order, across reopening
+#[test]
+fn changes_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+
+    let changes = store.changes_since(0)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        changes.iter().map(|change| change.seq).collect::<Vec<_>>(),
+        vec![1, 2, 3]
+    );
+    assert!(matches!(
+        &changes[2].event,
+        WatchEvent::Remove { key, .. } if key == "key1"
+    ));
+
+    let changes = store.changes_since(2)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].seq, 3);
+    assert_eq!(store.changes_since(3)?.count(), 0);
+    Ok(())
+}
+
+// Compaction should keep the retained history and nothing older
+#[test]
+fn changes_since_with_retention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let option = StoreOption {
+        history_retention: 2000,
+        ..StoreOption::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), option.clone())?;
+
+    // several compactions worth of overwrites
+    let seq = 5000;
+    let value = "v".repeat(1000);
+    for i in 0..seq {
+        store.set(format!("key{}", i % 100), value.clone())?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        let changes = store
+            .changes_since(seq - 2000)?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            changes.iter().map(|change| change.seq).collect::<Vec<_>>(),
+            (seq - 2000 + 1..=seq).collect::<Vec<_>>()
+        );
+        assert!(matches!(
+            store.changes_since(0),
+            Err(KvsError::HistoryTruncated(_))
+        ));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with(temp_dir.path(), option)?)
+}
+
+// A stream caught up should go on with later mutations,
+// across compactions that remove the files it reads
+#[test]
+fn changes_since_resumes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let mut changes = store.changes_since(0)?;
+    assert_eq!(
+        changes.next().transpose()?.map(|change| change.seq),
+        Some(1)
+    );
+    assert!(changes.next().is_none());
+
+    // several compactions worth of overwrites
+    let value = "v".repeat(1000);
+    for seq in 2..5000 {
+        store.set(format!("key{}", seq % 100), value.clone())?;
+        assert_eq!(
+            changes.next().transpose()?.map(|change| change.seq),
+            Some(seq)
+        );
+        assert!(changes.next().is_none());
+    }
+    assert!(store.stats().compactions > 0);
+
+    // files written and compacted away in between are missed
+    for seq in 5000..10000 {
+        store.set(format!("key{}", seq % 100), value.clone())?;
+    }
+    let mut last_seq = 4999;
+    for change in changes {
+        match change {
+            Ok(change) => {
+                assert_eq!(change.seq, last_seq + 1);
+                last_seq = change.seq;
+            }
+            Err(KvsError::HistoryTruncated(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+    panic!("changes up to {} read without a gap", last_seq);
+}
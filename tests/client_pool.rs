@@ -0,0 +1,127 @@
+mod common;
+
+use common::spawn_server;
+use kvs::{KvsClientPool, PoolOption, Result};
+use serde_json::{json, Value};
+use std::io::Write;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Idle connections broken by a server restart should be replaced transparently
+#[test]
+fn reconnect_after_server_restart() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let server = spawn_server(&temp_dir, &["--engine", "kvs", "--addr", addr]);
+    thread::sleep(Duration::from_secs(1));
+
+    let pool = KvsClientPool::new(addr.parse().unwrap(), PoolOption::default());
+    pool.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(server);
+    let server = spawn_server(&temp_dir, &["--engine", "kvs", "--addr", addr]);
+    thread::sleep(Duration::from_secs(1));
+
+    pool.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(pool.get("key1".to_owned())?, Some("value1".to_owned()));
+    pool.remove("key2".to_owned())?;
+    assert_eq!(pool.get("key2".to_owned())?, None);
+
+    drop(server);
+    Ok(())
+}
+
+// `get` should be retried with backoff until the server comes up
+#[test]
+fn retry_get_until_server_up() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let option = PoolOption {
+        max_retries: 6,
+        backoff: Duration::from_millis(200),
+        ..PoolOption::default()
+    };
+    let pool = KvsClientPool::new(addr.parse().unwrap(), option);
+
+    let server = spawn_server(&temp_dir, &["--engine", "kvs", "--addr", addr]);
+    assert_eq!(pool.get("key1".to_owned())?, None);
+    drop(server);
+
+    let pool = KvsClientPool::new(
+        addr.parse().unwrap(),
+        PoolOption {
+            max_retries: 0,
+            ..PoolOption::default()
+        },
+    );
+    assert!(pool.get("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Counts the requests it reads, answering each with `reply`, if any
+fn fake_server(addr: &str, reply: Option<Value>) -> Arc<AtomicUsize> {
+    let listener = TcpListener::bind(addr).unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&requests);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let counter = Arc::clone(&counter);
+            let reply = reply.clone();
+            thread::spawn(move || {
+                let reader = stream.try_clone().unwrap();
+                for request in serde_json::Deserializer::from_reader(reader).into_iter::<Value>() {
+                    if request.is_err() {
+                        return;
+                    }
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    if let Some(reply) = &reply {
+                        serde_json::to_writer(&mut stream, reply).unwrap();
+                        stream.flush().unwrap();
+                    }
+                }
+            });
+        }
+    });
+    requests
+}
+
+fn short_timeouts() -> PoolOption {
+    PoolOption {
+        connect_timeout: Duration::from_millis(200),
+        read_timeout: Duration::from_millis(200),
+        ..PoolOption::default()
+    }
+}
+
+// A slow server may still apply a request, which must not be sent twice
+#[test]
+fn timeout_is_not_retried() {
+    let addr = "127.0.0.1:4033";
+    let requests = fake_server(addr, None);
+    let pool = KvsClientPool::new(addr.parse().unwrap(), short_timeouts());
+
+    assert!(pool.set("key1".to_owned(), "value1".to_owned()).is_err());
+    assert!(pool.get("key1".to_owned()).is_err());
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+// A key moved to a server which does not answer fails in time
+#[test]
+fn moved_key_follows_timeouts() {
+    let (addr, target) = ("127.0.0.1:4034", "127.0.0.1:4035");
+    fake_server(addr, Some(json!({ "Moved": target })));
+    let requests = fake_server(target, None);
+    let pool = KvsClientPool::new(addr.parse().unwrap(), short_timeouts());
+
+    let start = Instant::now();
+    assert!(pool.get("key1".to_owned()).is_err());
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,24 @@
+use assert_cmd::prelude::*;
+use std::process::{Child, Command};
+use tempfile::TempDir;
+
+// Kills the server when dropped, even if the test bails out early
+pub struct Server(pub Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.0.kill().expect("server exited before killed");
+        self.0.wait().unwrap();
+    }
+}
+
+// A `kvs-server` run in `temp_dir` with `args`
+pub fn spawn_server(temp_dir: &TempDir, args: &[&str]) -> Server {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    Server(child)
+}
@@ -0,0 +1,80 @@
+use kvs::{Compression, KvStore, KvsEngine, Result, StoreOption, WatchEvent};
+use std::fs;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+// Values are compressed in the log only, and records of every codec mix.
+#[test]
+fn compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+    let document = |i: usize| format!(r#"{{"id":{},"tags":{:?}}}"#, i, vec!["tag"; 200]);
+    let open = |compression: Compression| {
+        let option = StoreOption {
+            compression,
+            ..StoreOption::default()
+        };
+        KvStore::open_with(temp_dir.path(), option)
+    };
+
+    let store = open(Compression::Zstd)?;
+    let mut events = store.watch("doc".to_owned())?;
+    for i in 0..100 {
+        store.set(format!("doc{}", i), document(i))?;
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    let raw_size: usize = (0..100).map(|i| document(i).len()).sum();
+    assert!(log_size() < raw_size as u64 / 4);
+    // watchers and readers of changes see plain values
+    let change = store.changes_since(0)?.next().unwrap()?;
+    assert_eq!(events.next(), Some(change.event.clone()));
+    match change.event {
+        WatchEvent::Set { key, value, .. } => {
+            assert_eq!((key, value), ("doc0".to_owned(), document(0)))
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+    drop(store);
+
+    let store = open(Compression::Lz4)?;
+    for i in 50..100 {
+        store.set(format!("doc{}", i), document(i + 1))?;
+    }
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..50 {
+            assert_eq!(store.get(format!("doc{}", i))?, Some(document(i)));
+        }
+        for i in 50..100 {
+            assert_eq!(store.get(format!("doc{}", i))?, Some(document(i + 1)));
+        }
+        assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+
+    // compaction rewrites every value without compression
+    let store = open(Compression::None)?;
+    check(&store)?;
+    let filler = "v".repeat(1000);
+    for _ in 0..2000 {
+        store.set("filler".to_owned(), filler.clone())?;
+    }
+    assert!(!temp_dir.path().join("1.log").exists(), "no compaction");
+    check(&store)?;
+    for entry in WalkDir::new(temp_dir.path()) {
+        let path = entry.unwrap().into_path();
+        if path.extension() == Some("log".as_ref()) {
+            assert!(!fs::read_to_string(path)?.contains("compression"));
+        }
+    }
+    drop(store);
+    check(&open(Compression::None)?)
+}
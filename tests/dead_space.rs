@@ -0,0 +1,43 @@
+use kvs::{KvStore, KvsEngine, Result, Transaction};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+// Live and dead bytes of every file are the same whether kept up to date
+// by writes and compactions or counted again on open.
+#[test]
+fn dead_space_accounting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+    let value = "v".repeat(1000);
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut compacted = false;
+    for round in 0..5 {
+        for i in 0..400 {
+            store.set(format!("key{}", i), value.clone())?;
+        }
+        for i in (0..400).step_by(7) {
+            store.remove(format!("key{}", i))?;
+        }
+        let mut tx = store.begin()?;
+        tx.set("txn".to_owned(), round.to_string())?;
+        tx.remove("key1".to_owned())?;
+        tx.commit()?;
+
+        let stats = store.stats();
+        compacted |= stats.compactions > 0;
+        assert_eq!(stats.live_bytes + stats.dead_bytes, stats.log_bytes);
+        assert_eq!(stats.log_bytes, log_size());
+        drop(store);
+        store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.stats().files, stats.files);
+    }
+    assert!(compacted);
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use kvs::{KvStore, KvsEngine, KvsError, Result, StoreOption};
+use std::collections::BTreeSet;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+// A store holds its directory locked for as long as a handle of it is open,
+// shared only by read-only stores.
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let read_only = StoreOption {
+        read_only: true,
+        ..StoreOption::default()
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let tree = store.open_tree("tree")?;
+    tree.set("key1".to_owned(), "tree1".to_owned())?;
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::DirectoryLocked(_))
+    ));
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), read_only.clone()),
+        Err(KvsError::DirectoryLocked(_))
+    ));
+    drop(tree);
+
+    let files = || -> BTreeSet<_> {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().path().to_owned())
+            .collect()
+    };
+    let before = files();
+    let store = KvStore::open_with(temp_dir.path(), read_only.clone())?;
+    let other = KvStore::open_with(temp_dir.path(), read_only)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        other.open_tree("tree")?.get("key1".to_owned())?,
+        Some("tree1".to_owned())
+    );
+    assert!(matches!(
+        store.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::DirectoryLocked(_))
+    ));
+    assert_eq!(files(), before);
+    drop(store);
+    drop(other);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,273 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm,
+};
+use assert_cmd::prelude::*;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use kvs::{
+    Compression, EncryptionKey, KvStore, KvsClient, KvsEngine, KvsError, Result, StoreOption,
+    WatchEvent, ENCRYPTION_KEY_ENV,
+};
+use predicates::str::contains;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+const KEY1: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+const KEY2: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+fn key(hex: &str) -> EncryptionKey {
+    EncryptionKey::from_hex(hex).unwrap()
+}
+
+fn open(dir: &Path, key: Option<EncryptionKey>, old_keys: Vec<EncryptionKey>) -> Result<KvStore> {
+    let option = StoreOption {
+        encryption_key: key,
+        old_encryption_keys: old_keys,
+        ..StoreOption::default()
+    };
+    KvStore::open_with(dir, option)
+}
+
+fn log_contents(dir: &Path) -> String {
+    WalkDir::new(dir)
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| fs::read_to_string(path).unwrap())
+        .collect()
+}
+
+#[test]
+fn encrypted_values() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path(), Some(key(KEY1)), Vec::new())?;
+    store.set("key1".to_owned(), "secret1".to_owned())?;
+    store.set("key2".to_owned(), "secret2".repeat(1000))?;
+    let events = store.watch("key".to_owned())?;
+    store.set("key3".to_owned(), "secret3".to_owned())?;
+    assert_eq!(events.take(1).next().unwrap().key(), "key3");
+    assert_eq!(store.get("key1".to_owned())?, Some("secret1".to_owned()));
+    let change = store.changes_since(0)?.next().unwrap()?;
+    match change.event {
+        WatchEvent::Set { key, value, .. } => {
+            assert_eq!((key, value), ("key1".into(), "secret1".into()))
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+    drop(store);
+    assert!(!log_contents(temp_dir.path()).contains("secret"));
+
+    // compressed, then encrypted
+    let option = StoreOption {
+        compression: Compression::Zstd,
+        encryption_key: Some(key(KEY1)),
+        ..StoreOption::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), option)?;
+    store.set("key4".to_owned(), "secret4".repeat(1000))?;
+    assert_eq!(store.get("key2".to_owned())?, Some("secret2".repeat(1000)));
+    assert_eq!(store.get("key4".to_owned())?, Some("secret4".repeat(1000)));
+    drop(store);
+
+    for (key, old_keys) in [(None, vec![]), (Some(key(KEY2)), vec![])] {
+        assert!(matches!(
+            open(temp_dir.path(), key, old_keys),
+            Err(KvsError::WrongEncryptionKey)
+        ));
+    }
+    let store = open(temp_dir.path(), Some(key(KEY1)), Vec::new())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("secret3".to_owned()));
+    Ok(())
+}
+
+// Compaction encrypts every value with the current key,
+// former keys are only needed until then.
+#[test]
+fn key_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path(), None, Vec::new())?;
+    store.set("plain".to_owned(), "value0".to_owned())?;
+    drop(store);
+    let store = open(temp_dir.path(), Some(key(KEY1)), Vec::new())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = open(temp_dir.path(), Some(key(KEY2)), vec![key(KEY1)])?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("plain".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let filler = "v".repeat(1000);
+    for _ in 0..2000 {
+        store.set("filler".to_owned(), filler.clone())?;
+    }
+    drop(store);
+    assert!(!log_contents(temp_dir.path()).contains("value0"));
+
+    assert!(matches!(
+        open(temp_dir.path(), Some(key(KEY1)), Vec::new()),
+        Err(KvsError::WrongEncryptionKey)
+    ));
+    let store = open(temp_dir.path(), Some(key(KEY2)), Vec::new())?;
+    for (key, value) in [("plain", "value0"), ("key1", "value1"), ("key2", "value2")] {
+        assert_eq!(store.get(key.to_owned())?, Some(value.to_owned()));
+    }
+    Ok(())
+}
+
+// A sealed value only opens in the record it was written in.
+#[test]
+fn sealed_values_bound_to_records() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path(), Some(key(KEY1)), Vec::new())?;
+    store.set("key1".to_owned(), "secret0".to_owned())?;
+    store.set("key1".to_owned(), "secret1".to_owned())?;
+    store.set("key2".to_owned(), "secret2".to_owned())?;
+    store.set("key3".to_owned(), "secret3".to_owned())?;
+    drop(store);
+
+    // the first value of key1 replayed over its second, and copied to key2,
+    // and one sealed with the same key but bound to nothing in key3
+    let log_path = temp_dir.path().join("1.log");
+    let mut records: Vec<serde_json::Value> =
+        serde_json::Deserializer::from_str(&fs::read_to_string(&log_path)?)
+            .into_iter()
+            .collect::<serde_json::Result<_>>()?;
+    let first = records[0]["Set"]["value"].clone();
+    records[1]["Set"]["value"] = first.clone();
+    records[2]["Set"]["value"] = first;
+    let cipher = Aes256Gcm::new_from_slice(&hex::decode(KEY1).unwrap()).unwrap();
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let mut unbound = nonce.to_vec();
+    unbound.extend(cipher.encrypt(&nonce, b"secret3".as_ref()).unwrap());
+    records[3]["Set"]["value"] = STANDARD.encode(unbound).into();
+    let tampered: String = records.iter().map(|record| record.to_string()).collect();
+    fs::write(&log_path, tampered)?;
+
+    let store = open(temp_dir.path(), Some(key(KEY1)), Vec::new())?;
+    for key in ["key1", "key2", "key3"] {
+        assert!(matches!(
+            store.get(key.to_owned()),
+            Err(KvsError::WrongEncryptionKey)
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn malformed_keys() {
+    for hex in ["", "00", &KEY1[1..], &format!("{}zz", &KEY1[2..])] {
+        assert!(EncryptionKey::from_hex(hex).is_err());
+    }
+    // as written by most editors
+    assert!(EncryptionKey::from_hex(&format!("{}\n", KEY1)).is_ok());
+}
+
+#[test]
+fn cli_encryption_key_from_env() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "secret1"])
+        .env(ENCRYPTION_KEY_ENV, KEY1)
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(!log_contents(temp_dir.path()).contains("secret1"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .env(ENCRYPTION_KEY_ENV, KEY1)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("secret1\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .env_remove(ENCRYPTION_KEY_ENV)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("WrongEncryptionKey"));
+}
+
+#[test]
+fn cli_migrate_encrypted_store() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = open(temp_dir.path(), Some(key(KEY1)), vec![])?;
+    store.set("key1".to_owned(), "secret1".to_owned())?;
+    drop(store);
+
+    // the key is taken from the environment, as by the other commands
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(temp_dir.path())
+        .env_remove(ENCRYPTION_KEY_ENV)
+        .assert()
+        .failure()
+        .stderr(contains("WrongEncryptionKey"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(temp_dir.path())
+        .env(ENCRYPTION_KEY_ENV, KEY1)
+        .assert()
+        .success();
+
+    // and the store converted back is encrypted with it again
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "sled", "--to", "kvs"])
+        .arg(temp_dir.path())
+        .env(ENCRYPTION_KEY_ENV, KEY1)
+        .assert()
+        .success();
+    assert!(!log_contents(temp_dir.path()).contains("secret1"));
+    let store = open(temp_dir.path(), Some(key(KEY1)), vec![])?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn server_encryption_key_file() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let key_file = temp_dir.path().join("key");
+    fs::write(&key_file, KEY1)?;
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir)?;
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4028", "--key-file"])
+        .arg(&key_file)
+        .current_dir(&data_dir)
+        .assert()
+        .failure()
+        .stderr(contains("kvs engine"));
+
+    let mut child = std::process::Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4028", "--key-file"])
+        .arg(&key_file)
+        .env_remove(ENCRYPTION_KEY_ENV)
+        .current_dir(&data_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let result = KvsClient::connect("127.0.0.1:4028")
+        .and_then(|mut client| client.set("key1".to_owned(), "secret1".to_owned()));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    result?;
+
+    assert!(!log_contents(&data_dir).contains("secret1"));
+    let store = open(&data_dir, Some(key(KEY1)), Vec::new())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,179 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    convert, engine_type_of, export, import, EngineType, ExportFormat, KvStore, KvsEngine,
+    KvsError, Result, SledWrapper,
+};
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn tricky_pairs() -> Vec<(String, String)> {
+    vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("with,comma".to_owned(), "\"quoted\"".to_owned()),
+        ("multi\nline".to_owned(), "tab\tand ünicode".to_owned()),
+        ("empty".to_owned(), String::new()),
+    ]
+}
+
+#[test]
+fn export_and_import_both_formats() -> Result<()> {
+    for format in [ExportFormat::jsonl, ExportFormat::csv] {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path())?;
+        for (key, value) in tricky_pairs() {
+            store.set(key, value)?;
+        }
+        store
+            .open_tree("ns1")?
+            .set("key1".to_owned(), "ns-value1".to_owned())?;
+        let mut exported = Vec::new();
+        assert_eq!(export(&store, format, None, &mut exported)?, 5);
+
+        let other_dir = TempDir::new().unwrap();
+        let sled = SledWrapper::new(sled::open(other_dir.path())?);
+        assert_eq!(import(&sled, format, None, exported.as_slice())?, 5);
+        for (key, value) in tricky_pairs() {
+            assert_eq!(sled.get(key)?, Some(value), "{}", format);
+        }
+        let ns1 = sled.open_tree("ns1")?;
+        assert_eq!(ns1.get("key1".to_owned())?, Some("ns-value1".to_owned()));
+
+        // one namespace, imported into another
+        let mut exported = Vec::new();
+        assert_eq!(export(&store, format, Some("ns1"), &mut exported)?, 1);
+        assert_eq!(import(&sled, format, Some("ns2"), exported.as_slice())?, 1);
+        let ns2 = sled.open_tree("ns2")?;
+        assert_eq!(ns2.get("key1".to_owned())?, Some("ns-value1".to_owned()));
+        assert_eq!(ns2.keys()?.count(), 1);
+    }
+    Ok(())
+}
+
+#[test]
+fn cli_export_and_import() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    for (key, value) in tricky_pairs() {
+        store.set(key, value)?;
+    }
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv", "--output", "pairs.csv"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let csv = fs::read_to_string(temp_dir.path().join("pairs.csv"))?;
+    assert!(csv.starts_with("key,value,namespace\n"));
+    assert!(csv.contains("\"with,comma\",\"\"\"quoted\"\"\""));
+
+    let other_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--format", "csv"])
+        .arg(temp_dir.path().join("pairs.csv"))
+        .current_dir(&other_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "with,comma"])
+        .current_dir(&other_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"quoted\""));
+
+    // jsonl through standard input and output
+    let exported = Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("export")
+        .current_dir(&other_dir)
+        .output()?;
+    assert!(exported.status.success());
+    assert_eq!(
+        String::from_utf8(exported.stdout.clone())?.lines().count(),
+        4
+    );
+    let third_dir = TempDir::new().unwrap();
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("import")
+        .current_dir(&third_dir)
+        .write_stdin(exported.stdout)
+        .assert()
+        .success();
+    let store = KvStore::open(third_dir.path())?;
+    for (key, value) in tricky_pairs() {
+        assert_eq!(store.get(key)?, Some(value));
+    }
+    Ok(())
+}
+
+#[test]
+fn cli_migrate_kvs_to_sled_and_back() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+    fs::write(temp_dir.path().join("notes.txt"), "not a store file")?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(temp_dir.path())
+        .assert()
+        .success();
+    assert_eq!(engine_type_of(temp_dir.path())?, Some(EngineType::sled));
+    let names: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert!(!names.iter().any(|name| name.ends_with(".log")));
+    // nothing of the old store is left aside
+    assert!(!names.iter().any(|name| name.starts_with(".convert")));
+    assert!(names.contains(&"notes.txt".to_owned()));
+    {
+        let sled = SledWrapper::new(sled::open(temp_dir.path())?);
+        assert_eq!(sled.get("key0".to_owned())?, None);
+        assert_eq!(sled.get("key99".to_owned())?, Some("value99".to_owned()));
+    }
+
+    // the marker says sled now
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure();
+    assert!(matches!(
+        convert(temp_dir.path(), EngineType::kvs, EngineType::sled),
+        Err(KvsError::ImcompatibleEngineType)
+    ));
+
+    assert_eq!(
+        convert(temp_dir.path(), EngineType::sled, EngineType::kvs)?,
+        99
+    );
+    assert_eq!(engine_type_of(temp_dir.path())?, Some(EngineType::kvs));
+    assert!(!temp_dir.path().join("db").exists());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.iter()?.count(), 99);
+    Ok(())
+}
+
+#[test]
+fn cli_migrate_to_same_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "kvs"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure();
+}
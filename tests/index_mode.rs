@@ -0,0 +1,200 @@
+use kvs::{IndexMode, KvStore, KvsEngine, KvsError, Result, StoreOption};
+use std::fs;
+use tempfile::TempDir;
+
+// Every index mode gives the same answers, through reopening and compaction.
+#[test]
+fn index_modes() -> Result<()> {
+    for index_mode in [
+        IndexMode::Standard,
+        IndexMode::Compact,
+        IndexMode::HashOnly,
+        IndexMode::OnDisk,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let option = StoreOption {
+            index_mode,
+            ..StoreOption::default()
+        };
+        let store = KvStore::open_with(temp_dir.path(), option.clone())?;
+        for i in 0..1000 {
+            store.set(format!("key{:04}", i), format!("value{}", i))?;
+        }
+        for i in (0..1000).step_by(3) {
+            store.remove(format!("key{:04}", i))?;
+        }
+        // enough removed keys for compact arenas to be repacked
+        for i in 0..5000 {
+            let key = format!("{:0>100}", i);
+            store.set(key.clone(), "gone".to_owned())?;
+            store.remove(key)?;
+        }
+        let view = store.read_view();
+        store.set("key0001".to_owned(), "changed".to_owned())?;
+        assert_eq!(view.get("key0001".to_owned())?, Some("value1".to_owned()));
+        drop(view);
+        drop(store);
+
+        let store = KvStore::open_with(temp_dir.path(), option)?;
+        assert_eq!(store.stats().keys, 666);
+        assert_eq!(store.get("key0000".to_owned())?, None);
+        assert_eq!(store.get("key0001".to_owned())?, Some("changed".to_owned()));
+        assert!(matches!(
+            store.remove("key0003".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+        let filler = "v".repeat(1000);
+        for i in 0..2000 {
+            store.set(format!("filler{}", i % 10), filler.clone())?;
+        }
+        assert!(!temp_dir.path().join("1.log").exists());
+        for i in 2..1000 {
+            let expected = match i % 3 {
+                0 => None,
+                _ => Some(format!("value{}", i)),
+            };
+            assert_eq!(store.get(format!("key{:04}", i))?, expected);
+        }
+        let keys: Vec<_> = store.keys()?.collect::<Result<_>>()?;
+        assert_eq!(keys.len(), 676);
+        assert_eq!(keys[0], "filler0");
+        assert_eq!(keys[10], "key0001");
+
+        // keys written since the compaction come in order with the others
+        store.set("key0002a".to_owned(), "new".to_owned())?;
+        store.remove("key0002".to_owned())?;
+        store.set("key0004".to_owned(), "changed".to_owned())?;
+        let keys: Vec<_> = store.keys()?.collect::<Result<_>>()?;
+        assert_eq!(keys.len(), 676);
+        assert_eq!(keys[10..13], ["key0001", "key0002a", "key0004"]);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        let page = store.list_keys(Some("key0001".to_owned()), 2)?;
+        assert_eq!(page.items, ["key0002a", "key0004"]);
+        assert_eq!(page.cursor, Some("key0004".to_owned()));
+    }
+    Ok(())
+}
+
+// Compact modes take less memory per key, as reported by `stats`.
+#[test]
+fn index_memory() -> Result<()> {
+    let mut bytes_per_key = Vec::new();
+    for index_mode in [IndexMode::Standard, IndexMode::Compact, IndexMode::HashOnly] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let option = StoreOption {
+            index_mode,
+            ..StoreOption::default()
+        };
+        let store = KvStore::open_with(temp_dir.path(), option)?;
+        for i in 0..10000 {
+            store.set(format!("key{}", i), "value".to_owned())?;
+        }
+        let stats = store.stats();
+        assert_eq!(stats.keys, 10000);
+        bytes_per_key.push(stats.index_bytes_per_key());
+    }
+    assert!(bytes_per_key[1] < bytes_per_key[0] * 0.7);
+    assert!(bytes_per_key[2] < bytes_per_key[1]);
+
+    // keys on disk take next to no memory once compacted
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let option = StoreOption {
+        index_mode: IndexMode::OnDisk,
+        ..StoreOption::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), option.clone())?;
+    for i in 0..10000 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    let filler = "v".repeat(1000);
+    for i in 0..2000 {
+        store.set(format!("filler{}", i % 10), filler.clone())?;
+    }
+    assert!(!temp_dir.path().join("1.log").exists());
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), option)?;
+    let stats = store.stats();
+    assert_eq!(stats.keys, 10010);
+    assert!(stats.index_bytes_per_key() < bytes_per_key[2] / 4.0);
+    assert_eq!(store.get("key9999".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Keys on disk written past the bound are indexed in segments without
+// compacting, keeping memory bounded, and read back in order on reopening.
+#[test]
+fn index_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let option = StoreOption {
+        index_mode: IndexMode::OnDisk,
+        max_unindexed_keys: 1000,
+        ..StoreOption::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), option.clone())?;
+    let mut index_bytes = Vec::new();
+    for round in 0..20 {
+        for i in 0..1000 {
+            store.set(format!("key{:05}", round * 1000 + i), "value".to_owned())?;
+        }
+        // keys of earlier segments changed and removed in later ones
+        store.set(format!("key{:05}", round), "changed".to_owned())?;
+        if round > 0 {
+            store.remove(format!("key{:05}", round * 1000 - 1))?;
+        }
+        index_bytes.push(store.stats().index_bytes);
+    }
+    let stats = store.stats();
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.keys, 19981);
+    // fences of the segments aside, no more than the bound is held
+    assert!(index_bytes[19] < index_bytes[1] * 2);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.stats().keys, 19981);
+        assert_eq!(
+            store.get("key00000".to_owned())?,
+            Some("changed".to_owned())
+        );
+        assert_eq!(
+            store.get("key00019".to_owned())?,
+            Some("changed".to_owned())
+        );
+        assert_eq!(store.get("key00020".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("key00999".to_owned())?, None);
+        assert_eq!(store.get("key19999".to_owned())?, Some("value".to_owned()));
+        let keys: Vec<_> = store.keys()?.collect::<Result<_>>()?;
+        assert_eq!(keys.len(), 19981);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(!keys.contains(&"key18999".to_owned()));
+        let page = store.list_keys(Some("key00998".to_owned()), 2)?;
+        assert_eq!(page.items, ["key01000", "key01001"]);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), option.clone())?;
+    check(&store)?;
+
+    // a compaction folds the segments into its index
+    let filler = "v".repeat(1000);
+    for i in 0..2000 {
+        store.set(format!("filler{}", i % 10), filler.clone())?;
+    }
+    assert!(store.stats().compactions > 0);
+    let index_files = fs::read_dir(temp_dir.path())?
+        .filter(|entry| {
+            let path = entry.as_ref().unwrap().path();
+            path.extension() == Some("index".as_ref())
+        })
+        .count();
+    assert_eq!(index_files, 1);
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), option)?;
+    assert_eq!(store.stats().keys, 19991);
+    assert_eq!(
+        store.get("key00000".to_owned())?,
+        Some("changed".to_owned())
+    );
+    assert_eq!(store.get("key00999".to_owned())?, None);
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use kvs::{KvStore, KvsEngine, Result};
+use std::thread;
+use tempfile::TempDir;
+
+// Keys and pairs should come in key order, a page at a time
+#[test]
+fn list_keys_and_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in (0..25).rev() {
+        store.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    store.remove("key10".to_owned())?;
+
+    let keys = store.keys()?.collect::<Result<Vec<_>>>()?;
+    let mut expected: Vec<_> = (0..25)
+        .filter(|&i| i != 10)
+        .map(|i| format!("key{:02}", i))
+        .collect();
+    assert_eq!(keys, expected);
+
+    let mut listed = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = store.list_keys(cursor, 10)?;
+        assert!(page.items.len() <= 10);
+        listed.extend(page.items);
+        cursor = page.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(listed, expected);
+
+    let page = store.scan(Some("key08".to_owned()), 2)?;
+    assert_eq!(
+        page.items,
+        vec![
+            ("key09".to_owned(), "value9".to_owned()),
+            ("key11".to_owned(), "value11".to_owned())
+        ]
+    );
+    assert_eq!(page.cursor, Some("key11".to_owned()));
+    let mut scanned = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = store.scan(cursor, 4)?;
+        scanned.extend(page.items);
+        cursor = page.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(scanned, store.iter()?.collect::<Result<Vec<_>>>()?);
+    expected.retain(|key| key.as_str() > "key23");
+    assert_eq!(
+        store.list_keys(Some("key23".to_owned()), 10)?.items,
+        expected
+    );
+    assert_eq!(store.list_keys(Some("key23".to_owned()), 10)?.cursor, None);
+    Ok(())
+}
+
+// Iterating should be safe while writes keep compacting the log
+#[test]
+fn iter_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("stable{:03}", i), format!("value{}", i))?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            let value = "v".repeat(1000);
+            for i in 0..5000 {
+                store.set(format!("hot{}", i % 50), value.clone())?;
+            }
+            Ok(())
+        })
+    };
+    for _ in 0..20 {
+        let stable: Vec<_> = store
+            .iter()?
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|(key, _)| key.starts_with("stable"))
+            .collect();
+        assert_eq!(stable.len(), 100);
+        for (i, (key, value)) in stable.iter().enumerate() {
+            assert_eq!(key, &format!("stable{:03}", i));
+            assert_eq!(value, &format!("value{}", i));
+        }
+    }
+    writer.join().unwrap()?;
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use kvs::{KvStore, KvsEngine, Result};
+use std::fs;
+use tempfile::TempDir;
+
+// A crash before a merge commits its manifest leaves the store as it was
+// before the merge. Its outputs, the last one written in part, are removed.
+#[test]
+fn crash_before_merge_commits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file_names = || -> Vec<_> {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect()
+    };
+    let value = "v".repeat(1000);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..500 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let before = file_names();
+    for name in &before {
+        fs::hard_link(temp_dir.path().join(name), backup_dir.path().join(name))?;
+    }
+    let mut written = 0;
+    while store.stats().compactions == 0 {
+        store.set(
+            format!("key{}", written % 500),
+            format!("{}{}", written, value),
+        )?;
+        written += 1;
+    }
+    drop(store);
+
+    let mut outputs: Vec<_> = file_names()
+        .into_iter()
+        .filter(|name| !before.contains(name))
+        .filter(|name| name.to_string_lossy().ends_with(".log"))
+        .map(|name| temp_dir.path().join(name))
+        .collect();
+    assert!(!outputs.is_empty());
+    outputs.sort_by_key(|path| fs::metadata(path).unwrap().len());
+    let partial = fs::OpenOptions::new()
+        .write(true)
+        .open(outputs.last().unwrap())?;
+    partial.set_len(partial.metadata()?.len() / 2)?;
+    for name in &before {
+        let path = temp_dir.path().join(name);
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        fs::hard_link(backup_dir.path().join(name), path)?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().keys, 500);
+    for i in 0..500 {
+        let expected = match (i..written).step_by(500).next_back() {
+            Some(last) => format!("{}{}", last, value),
+            None => value.clone(),
+        };
+        assert_eq!(store.get(format!("key{}", i))?, Some(expected));
+    }
+    // the log files as before, and a new active one
+    let log_count = |names: Vec<std::ffi::OsString>| {
+        names
+            .iter()
+            .filter(|name| name.to_string_lossy().ends_with(".log"))
+            .count()
+    };
+    assert_eq!(log_count(file_names()), log_count(before) + 1);
+    Ok(())
+}
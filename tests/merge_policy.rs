@@ -0,0 +1,61 @@
+use kvs::{KvStore, KvsEngine, Result, StoreOption};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+// Only files mostly dead are merged, into files of bounded size, and
+// removes they hold outlive the values they remove in other files.
+#[test]
+fn merge_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let option = StoreOption {
+        merge_file_size: 64 * 1024,
+        ..StoreOption::default()
+    };
+    let value = "v".repeat(1000);
+    let store = KvStore::open_with(temp_dir.path(), option.clone())?;
+    for i in 0..500 {
+        store.set(format!("cold{}", i), value.clone())?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), option.clone())?;
+    store.remove("cold0".to_owned())?;
+    for i in 0..2000 {
+        store.set(format!("hot{}", i % 10), value.clone())?;
+    }
+    let stats = store.stats();
+    assert!(stats.compactions > 0);
+    assert!(temp_dir.path().join("1.log").exists());
+    assert!(stats.compacted_bytes < 500 * 1000);
+    assert!(stats.dead_bytes < stats.log_bytes / 2);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), option.clone())?;
+    assert_eq!(store.get("cold0".to_owned())?, None);
+    for i in 0..400 {
+        store.set(format!("cold{}", i), "new".to_owned())?;
+    }
+    for i in 0..1000 {
+        store.set(format!("hot{}", i % 10), value.clone())?;
+    }
+    assert!(!temp_dir.path().join("1.log").exists());
+    let mut log_sizes: Vec<_> = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .map(|entry| entry.metadata().unwrap().len())
+        .collect();
+    log_sizes.sort_unstable();
+    // every file but the active one
+    log_sizes.pop();
+    assert!(log_sizes.iter().all(|&size| size < 64 * 1024 + 2000));
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), option)?;
+    assert_eq!(store.stats().keys, 510);
+    assert_eq!(store.get("cold0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("cold399".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("cold400".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("hot9".to_owned())?, Some(value));
+    Ok(())
+}
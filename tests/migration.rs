@@ -0,0 +1,179 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use common::spawn_server;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsServer, PoolOption, Result, ShardedKvsClient};
+use serde_json::{json, Value};
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Servers run in-process with a few workers each, so that a migration can
+// be served alongside regular requests. They live until the test ends.
+fn start_servers(addrs: &[&str]) -> (Vec<SocketAddr>, Vec<TempDir>) {
+    let dirs: Vec<_> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    for (addr, dir) in addrs.iter().zip(&dirs) {
+        let addr: SocketAddr = addr.parse().unwrap();
+        let engine = KvStore::open(dir.path()).unwrap();
+        let pool = SharedQueueThreadPool::new(4).unwrap();
+        thread::spawn(move || KvsServer::new(engine, pool).run(&addr));
+    }
+    thread::sleep(Duration::from_millis(500));
+    let addrs = addrs.iter().map(|addr| addr.parse().unwrap()).collect();
+    (addrs, dirs)
+}
+
+// A get which does not follow `Moved`
+fn raw_get(addr: SocketAddr, key: &str) -> Value {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    serde_json::to_writer(&mut stream, &json!({ "Get": { "key": key } })).unwrap();
+    stream.flush().unwrap();
+    let mut de = serde_json::Deserializer::from_reader(BufReader::new(stream));
+    serde::Deserialize::deserialize(&mut de).unwrap()
+}
+
+#[test]
+fn migrate_to_new_server() -> Result<()> {
+    let (addrs, _dirs) = start_servers(&["127.0.0.1:4020", "127.0.0.1:4021", "127.0.0.1:4022"]);
+    let (old, new_server) = (&addrs[..2], addrs[2]);
+
+    let client = ShardedKvsClient::new(old, PoolOption::default());
+    let pairs: Vec<_> = (0..500)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client.mset(pairs.clone())?;
+    // keys of other namespaces move by their hash as well
+    let ns = Some("ns1".to_owned());
+    for (key, value) in &pairs[..100] {
+        KvsClient::connect(client.shard_of(key))?
+            .with_namespace(ns.clone())
+            .set(key.clone(), format!("ns-{}", value))?;
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--cluster", "127.0.0.1:4020,127.0.0.1:4021"])
+        .args(["migrate", "127.0.0.1:4022"])
+        .assert()
+        .success();
+
+    let grown = ShardedKvsClient::new(&addrs, PoolOption::default());
+    let mut moved = 0;
+    for (key, value) in &pairs {
+        let owner = grown.shard_of(key);
+        assert_eq!(
+            KvsClient::connect(owner)?.get(key.clone())?.as_ref(),
+            Some(value)
+        );
+        if owner == new_server {
+            moved += 1;
+            // the old owner points at the new one
+            let old_owner = client.shard_of(key);
+            assert_eq!(
+                raw_get(old_owner, key),
+                json!({ "Moved": new_server.to_string() })
+            );
+        }
+    }
+    assert!(moved > 50, "only {} keys moved", moved);
+    for (key, value) in &pairs[..100] {
+        let mut owner = KvsClient::connect(grown.shard_of(key))?.with_namespace(ns.clone());
+        assert_eq!(owner.get(key.clone())?, Some(format!("ns-{}", value)));
+    }
+
+    // clients with the old server list follow the keys
+    let keys: Vec<_> = pairs.iter().map(|(key, _)| key.clone()).collect();
+    let values = client.mget(keys.clone())?;
+    assert!(values
+        .iter()
+        .zip(&pairs)
+        .all(|(got, (_, value))| got.as_ref() == Some(value)));
+    client.set("key1".to_owned(), "new".to_owned())?;
+    client.remove("key2".to_owned())?;
+    assert_eq!(grown.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(grown.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn writes_during_migration() -> Result<()> {
+    let (addrs, _dirs) = start_servers(&["127.0.0.1:4023", "127.0.0.1:4024"]);
+    let client = ShardedKvsClient::new(&addrs[..1], PoolOption::default());
+    let keys: Vec<_> = (0..3000).map(|i| format!("key{}", i)).collect();
+    client.mset(
+        keys.iter()
+            .map(|key| (key.clone(), "0".to_owned()))
+            .collect(),
+    )?;
+
+    let writer = {
+        let client = client.clone();
+        let keys = keys.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 1..=3 {
+                for key in keys.iter().step_by(7) {
+                    client.set(key.clone(), round.to_string())?;
+                }
+            }
+            for key in keys.iter().step_by(11) {
+                client.remove(key.clone())?;
+            }
+            Ok(())
+        })
+    };
+    let grown = client.add_server(addrs[1])?;
+    writer.join().unwrap()?;
+
+    for (i, key) in keys.iter().enumerate() {
+        let expected = if i % 11 == 0 {
+            None
+        } else if i % 7 == 0 {
+            Some("3".to_owned())
+        } else {
+            Some("0".to_owned())
+        };
+        assert_eq!(grown.get(key.clone())?, expected, "{}", key);
+        assert_eq!(client.get(key.clone())?, expected, "{}", key);
+    }
+    Ok(())
+}
+
+// A restarted server still points at where its keys moved.
+#[test]
+fn routes_survive_restart() -> Result<()> {
+    let dirs: Vec<_> = (0..2).map(|_| TempDir::new().unwrap()).collect();
+    let addrs: Vec<SocketAddr> = vec![
+        "127.0.0.1:4031".parse().unwrap(),
+        "127.0.0.1:4032".parse().unwrap(),
+    ];
+    let spawn = |i: usize| spawn_server(&dirs[i], &["--addr", &addrs[i].to_string()]);
+    let old_owner = spawn(0);
+    let _new_owner = spawn(1);
+    thread::sleep(Duration::from_secs(1));
+
+    // a client keeps its connections, which occupy the workers of a server
+    let client = || ShardedKvsClient::new(&addrs[..1], PoolOption::default());
+    let pairs: Vec<_> = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client().mset(pairs.clone())?;
+    let grown = client().add_server(addrs[1])?;
+    let moved = pairs
+        .iter()
+        .find(|(key, _)| grown.shard_of(key) == addrs[1])
+        .unwrap();
+
+    drop(old_owner);
+    let _old_owner = spawn(0);
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(
+        raw_get(addrs[0], &moved.0),
+        json!({ "Moved": addrs[1].to_string() })
+    );
+    assert_eq!(client().get(moved.0.clone())?, Some(moved.1.clone()));
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use assert_cmd::prelude::*;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    KvStore, KvsClient, KvsEngine, KvsError, KvsServer, PoolOption, Result, ShardedKvsClient,
+    SledWrapper,
+};
+use std::net::SocketAddr;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn namespaces_are_separate<E: KvsEngine>(engine: E) -> Result<()> {
+    let users = engine.open_tree("users")?;
+    let orders = engine.open_tree("orders")?;
+    engine.set("key1".to_owned(), "default".to_owned())?;
+    users.set("key1".to_owned(), "user".to_owned())?;
+    orders.set("key2".to_owned(), "order".to_owned())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key1".to_owned())?, Some("user".to_owned()));
+    assert_eq!(orders.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(
+        users.keys()?.collect::<Result<Vec<_>>>()?,
+        vec!["key1".to_owned()]
+    );
+
+    // pages start after the cursor, within the namespace
+    for i in 3..6 {
+        orders.set(format!("key{}", i), format!("order{}", i))?;
+    }
+    let page = orders.list_keys(Some("key2".to_owned()), 2)?;
+    assert_eq!(page.items, vec!["key3".to_owned(), "key4".to_owned()]);
+    assert_eq!(page.cursor, Some("key4".to_owned()));
+    let page = orders.scan(page.cursor, 2)?;
+    assert_eq!(page.items, vec![("key5".to_owned(), "order5".to_owned())]);
+    assert_eq!(page.cursor, None);
+
+    // every handle on a namespace sees the same keys
+    orders.open_tree("users")?.remove("key1".to_owned())?;
+    assert_eq!(users.get("key1".to_owned())?, None);
+    assert!(matches!(
+        users.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.get("key1".to_owned())?, Some("default".to_owned()));
+
+    let mut names = engine.tree_names()?;
+    names.sort();
+    assert_eq!(names, vec!["orders".to_owned(), "users".to_owned()]);
+
+    for name in ["", "..", "a/b"] {
+        assert!(matches!(
+            engine.open_tree(name),
+            Err(KvsError::InvalidNamespace(_))
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn kvs_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    namespaces_are_separate(KvStore::open(temp_dir.path())?)?;
+
+    // namespaces persist, and are part of snapshots
+    let store = KvStore::open(temp_dir.path())?;
+    let orders = store.open_tree("orders")?;
+    assert_eq!(orders.get("key2".to_owned())?, Some("order".to_owned()));
+    let snapshot = TempDir::new().unwrap();
+    store.snapshot(&snapshot.path().join("copy"))?;
+    let copy = KvStore::open(snapshot.path().join("copy"))?;
+    assert_eq!(
+        copy.open_tree("orders")?.get("key2".to_owned())?,
+        Some("order".to_owned())
+    );
+    assert_eq!(copy.get("key1".to_owned())?, Some("default".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sled_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    namespaces_are_separate(SledWrapper::new(sled::open(temp_dir.path())?))
+}
+
+#[test]
+fn cli_namespaces() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--ns", "users", "set", "key1", "user"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--ns", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["keys", "--ns", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1\n");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--ns", "../x"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn server_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4026".parse().unwrap();
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || KvsServer::new(engine, pool).run(&addr));
+    thread::sleep(Duration::from_millis(500));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set",
+            "key1",
+            "user",
+            "--ns",
+            "users",
+            "--addr",
+            "127.0.0.1:4026",
+        ])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--ns", "users", "get", "key1", "--addr", "127.0.0.1:4026"])
+        .assert()
+        .success()
+        .stdout("user\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4026"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    let mut client = KvsClient::connect(addr)?.with_namespace(Some("orders".to_owned()));
+    client.set("key1".to_owned(), "order".to_owned())?;
+    assert_eq!(client.keys(None, 10)?.items, vec!["key1".to_owned()]);
+    drop(client);
+
+    let option = PoolOption {
+        namespace: Some("users".to_owned()),
+        ..PoolOption::default()
+    };
+    let sharded = ShardedKvsClient::new(&[addr], option);
+    assert_eq!(sharded.get("key1".to_owned())?, Some("user".to_owned()));
+    drop(sharded);
+
+    let mut client = KvsClient::connect(addr)?.with_namespace(Some("a/b".to_owned()));
+    assert!(client.get("key1".to_owned()).is_err());
+    Ok(())
+}
@@ -0,0 +1,521 @@
+mod common;
+
+use common::{spawn_server, Server};
+use kvs::raft::{Members, NodeId, RaftMessage, RaftNode, RaftOption, RaftReply, Transport};
+use kvs::{KvStore, KvsClient, KvsEngine, KvsError, Result, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// In-process network of raft nodes, able to cut nodes off and crash them
+#[derive(Clone, Default)]
+struct Network {
+    nodes: Arc<Mutex<HashMap<NodeId, RaftNode<KvStore>>>>,
+    isolated: Arc<Mutex<HashSet<NodeId>>>,
+    // number of pairs of every snapshot chunk sent
+    chunks: Arc<Mutex<Vec<usize>>>,
+}
+
+struct NetworkTransport {
+    from: NodeId,
+    network: Network,
+}
+
+impl Transport for NetworkTransport {
+    fn call(&self, to: NodeId, _addr: &str, msg: RaftMessage) -> Result<RaftReply> {
+        let isolated = self.network.isolated.lock().unwrap();
+        if isolated.contains(&self.from) || isolated.contains(&to) {
+            return Err(unreachable());
+        }
+        drop(isolated);
+        if let RaftMessage::InstallSnapshot { pairs, .. } = &msg {
+            self.network.chunks.lock().unwrap().push(pairs.len());
+        }
+        let node = self.network.nodes.lock().unwrap().get(&to).cloned();
+        node.map(|node| node.handle(msg)).ok_or_else(unreachable)
+    }
+}
+
+fn unreachable() -> KvsError {
+    io::Error::new(io::ErrorKind::ConnectionRefused, "unreachable").into()
+}
+
+struct Cluster {
+    network: Network,
+    dirs: HashMap<NodeId, TempDir>,
+    option: RaftOption,
+}
+
+impl Cluster {
+    fn new(ids: &[NodeId], snapshot_threshold: usize) -> Self {
+        let option = RaftOption {
+            members: ids.iter().map(|&id| (id, addr_of(id))).collect(),
+            election_timeout: Duration::from_millis(150),
+            heartbeat_interval: Duration::from_millis(30),
+            snapshot_threshold,
+            propose_timeout: Duration::from_secs(1),
+            ..RaftOption::default()
+        };
+        let mut cluster = Cluster {
+            network: Network::default(),
+            dirs: HashMap::new(),
+            option,
+        };
+        for &id in ids {
+            cluster.dirs.insert(id, TempDir::new().unwrap());
+            cluster.start(id, cluster.option.members.clone());
+        }
+        cluster
+    }
+
+    fn start(&self, id: NodeId, members: Members) {
+        let dir = self.dirs[&id].path();
+        let option = RaftOption {
+            id,
+            members,
+            ..self.option.clone()
+        };
+        let transport = NetworkTransport {
+            from: id,
+            network: self.network.clone(),
+        };
+        let engine = KvStore::open(dir).unwrap();
+        let node = RaftNode::start(engine, dir.to_path_buf(), option, transport).unwrap();
+        self.network.nodes.lock().unwrap().insert(id, node);
+    }
+
+    // a fresh node which knows nobody until it is added
+    fn join(&mut self, id: NodeId) {
+        self.dirs.insert(id, TempDir::new().unwrap());
+        self.start(id, Members::new());
+    }
+
+    fn crash(&self, id: NodeId) {
+        let node = self.network.nodes.lock().unwrap().remove(&id).unwrap();
+        node.shutdown();
+    }
+
+    fn restart(&self, id: NodeId) {
+        self.start(id, self.option.members.clone());
+    }
+
+    fn node(&self, id: NodeId) -> RaftNode<KvStore> {
+        self.network.nodes.lock().unwrap()[&id].clone()
+    }
+
+    fn isolate(&self, id: NodeId) {
+        self.network.isolated.lock().unwrap().insert(id);
+    }
+
+    fn heal(&self) {
+        self.network.isolated.lock().unwrap().clear();
+    }
+
+    // Waits for a single leader among the reachable nodes
+    fn leader(&self) -> NodeId {
+        wait_until(|| {
+            let isolated = self.network.isolated.lock().unwrap().clone();
+            let leaders: Vec<_> = self
+                .network
+                .nodes
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|node| node.is_leader() && !isolated.contains(&node.id()))
+                .map(|node| node.id())
+                .collect();
+            match leaders[..] {
+                [leader] => Some(leader),
+                _ => None,
+            }
+        })
+    }
+
+    fn wait_for(&self, id: NodeId, key: &str, expected: Option<&str>) {
+        let node = self.node(id);
+        wait_until(|| {
+            let value = node.get(key.to_owned()).unwrap();
+            (value.as_deref() == expected).then_some(())
+        })
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for node in self.network.nodes.lock().unwrap().values() {
+            node.shutdown();
+        }
+    }
+}
+
+fn addr_of(id: NodeId) -> String {
+    format!("127.0.0.1:{}", 5000 + id)
+}
+
+fn wait_until<T, F: FnMut() -> Option<T>>(mut f: F) -> T {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        if let Some(t) = f() {
+            return t;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    panic!("condition not met in time");
+}
+
+#[test]
+fn elect_and_replicate() -> Result<()> {
+    let cluster = Cluster::new(&[1, 2, 3], 1000);
+    let leader = cluster.node(cluster.leader());
+
+    leader.set("key1".to_owned(), "value1".to_owned())?;
+    leader.set("key2".to_owned(), "value2".to_owned())?;
+    leader.remove("key2".to_owned())?;
+    assert!(matches!(
+        leader.remove("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    for id in 1..=3 {
+        cluster.wait_for(id, "key1", Some("value1"));
+        cluster.wait_for(id, "key2", None);
+    }
+
+    // writes sent to a follower point at the leader
+    let follower = (1..=3).find(|&id| id != leader.id()).unwrap();
+    match cluster
+        .node(follower)
+        .set("key3".to_owned(), "value3".to_owned())
+    {
+        Err(KvsError::Redirect(addr)) => assert_eq!(addr.to_string(), addr_of(leader.id())),
+        result => panic!("unexpected result {:?}", result),
+    }
+    Ok(())
+}
+
+#[test]
+fn leader_partition() -> Result<()> {
+    let cluster = Cluster::new(&[1, 2, 3], 1000);
+    let old_leader = cluster.node(cluster.leader());
+    old_leader.set("key1".to_owned(), "value1".to_owned())?;
+
+    cluster.isolate(old_leader.id());
+    let new_leader = cluster.node(cluster.leader());
+    assert_ne!(new_leader.id(), old_leader.id());
+    new_leader.set("key1".to_owned(), "value2".to_owned())?;
+
+    // the minority cannot commit anything
+    assert!(old_leader
+        .set("key1".to_owned(), "value3".to_owned())
+        .is_err());
+
+    cluster.heal();
+    for id in 1..=3 {
+        cluster.wait_for(id, "key1", Some("value2"));
+    }
+    Ok(())
+}
+
+#[test]
+fn crash_and_catch_up_from_snapshot() -> Result<()> {
+    let cluster = Cluster::new(&[1, 2, 3], 20);
+    let leader_id = cluster.leader();
+    let leader = cluster.node(leader_id);
+    let follower = (1..=3).find(|&id| id != leader_id).unwrap();
+
+    cluster.crash(follower);
+    for i in 0..100 {
+        leader.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    leader.remove("key0".to_owned())?;
+
+    cluster.restart(follower);
+    cluster.wait_for(follower, "key99", Some("value99"));
+    cluster.wait_for(follower, "key0", None);
+    for i in 1..100 {
+        assert_eq!(
+            cluster.node(follower).get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    // the whole cluster survives a restart of every node,
+    // no handle of which may keep its store open
+    drop(leader);
+    for id in 1..=3 {
+        cluster.crash(id);
+    }
+    for id in 1..=3 {
+        cluster.restart(id);
+    }
+    let leader = cluster.node(cluster.leader());
+    leader.set("key100".to_owned(), "value100".to_owned())?;
+    for id in 1..=3 {
+        cluster.wait_for(id, "key50", Some("value50"));
+        cluster.wait_for(id, "key100", Some("value100"));
+    }
+    Ok(())
+}
+
+#[test]
+fn namespaces_catch_up_from_snapshot() -> Result<()> {
+    let cluster = Cluster::new(&[1, 2, 3], 20);
+    let leader_id = cluster.leader();
+    let leader = cluster.node(leader_id);
+    let follower = (1..=3).find(|&id| id != leader_id).unwrap();
+
+    cluster.crash(follower);
+    let users = leader.open_tree("users")?;
+    for i in 0..50 {
+        users.set(format!("key{}", i), format!("user{}", i))?;
+        leader.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    cluster.restart(follower);
+    cluster.wait_for(follower, "key49", Some("value49"));
+    let users = cluster.node(follower).open_tree("users")?;
+    wait_until(|| {
+        let value = users.get("key49".to_owned()).unwrap();
+        (value.as_deref() == Some("user49")).then_some(())
+    });
+    assert_eq!(users.get("key0".to_owned())?, Some("user0".to_owned()));
+    assert_eq!(users.iter()?.count(), 50);
+    Ok(())
+}
+
+#[test]
+fn snapshot_sent_in_chunks() -> Result<()> {
+    let cluster = Cluster::new(&[1, 2, 3], 100);
+    let leader_id = cluster.leader();
+    let leader = cluster.node(leader_id);
+    let follower = (1..=3).find(|&id| id != leader_id).unwrap();
+
+    cluster.crash(follower);
+    let users = leader.open_tree("users")?;
+    for i in 0..2500 {
+        leader.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..1200 {
+        users.set(format!("key{}", i), format!("user{}", i))?;
+    }
+
+    cluster.restart(follower);
+    let users = cluster.node(follower).open_tree("users")?;
+    wait_until(|| {
+        let value = users.get("key1199".to_owned()).unwrap();
+        (value.as_deref() == Some("user1199")).then_some(())
+    });
+    cluster.wait_for(follower, "key2499", Some("value2499"));
+    assert_eq!(cluster.node(follower).iter()?.count(), 2500);
+    assert_eq!(users.iter()?.count(), 1200);
+
+    let chunks = cluster.network.chunks.lock().unwrap().clone();
+    assert!(chunks.len() >= 5, "chunks: {:?}", chunks);
+    assert!(chunks.iter().all(|&len| len < 1200), "chunks: {:?}", chunks);
+    Ok(())
+}
+
+#[test]
+fn transactions_through_the_log() -> Result<()> {
+    let cluster = Cluster::new(&[1, 2, 3], 1000);
+    let leader = cluster.node(cluster.leader());
+    leader.set("alice".to_owned(), "10".to_owned())?;
+    leader.transaction(|tx| {
+        let alice: u32 = tx.get("alice".to_owned())?.unwrap().parse().unwrap();
+        tx.set("alice".to_owned(), (alice - 3).to_string())?;
+        tx.set("bob".to_owned(), "3".to_owned())
+    })?;
+    for id in 1..=3 {
+        cluster.wait_for(id, "alice", Some("7"));
+        cluster.wait_for(id, "bob", Some("3"));
+    }
+
+    // a key read and written in between fails the whole transaction
+    let mut tx = leader.begin()?;
+    tx.get("alice".to_owned())?;
+    tx.remove("bob".to_owned())?;
+    leader.set("alice".to_owned(), "0".to_owned())?;
+    assert!(matches!(tx.commit(), Err(KvsError::TransactionConflict)));
+    assert_eq!(leader.get("bob".to_owned())?, Some("3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn transactions_after_snapshot_survive_restart() -> Result<()> {
+    let cluster = Cluster::new(&[1, 2, 3], 20);
+    let leader = cluster.node(cluster.leader());
+
+    // reads "alice" before it is set, so the commit below fails
+    let mut tx = leader.begin()?;
+    tx.get("alice".to_owned())?;
+    tx.set("bob".to_owned(), "3".to_owned())?;
+    leader.set("alice".to_owned(), "10".to_owned())?;
+    for i in 0..20 {
+        leader.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(matches!(tx.commit(), Err(KvsError::TransactionConflict)));
+    // would let the transaction succeed if replayed on the latest state
+    leader.remove("alice".to_owned())?;
+    for id in 1..=3 {
+        cluster.wait_for(id, "alice", None);
+    }
+
+    drop(leader);
+    for id in 1..=3 {
+        cluster.crash(id);
+    }
+    for id in 1..=3 {
+        cluster.restart(id);
+    }
+    let leader = cluster.node(cluster.leader());
+    leader.set("key20".to_owned(), "value20".to_owned())?;
+    for id in 1..=3 {
+        cluster.wait_for(id, "key20", Some("value20"));
+        assert_eq!(cluster.node(id).get("bob".to_owned())?, None);
+        assert_eq!(cluster.node(id).get("alice".to_owned())?, None);
+    }
+    Ok(())
+}
+
+#[test]
+fn membership_change() -> Result<()> {
+    let mut cluster = Cluster::new(&[1, 2, 3], 20);
+    let leader = cluster.node(cluster.leader());
+    for i in 0..50 {
+        leader.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    cluster.join(4);
+    leader.add_node(4, addr_of(4))?;
+    cluster.wait_for(4, "key49", Some("value49"));
+    assert_eq!(cluster.node(4).members().len(), 4);
+
+    // removing the leader hands the cluster over to the others
+    leader.remove_node(leader.id())?;
+    let new_leader = cluster.node(wait_until(|| {
+        let id = cluster.leader();
+        (id != leader.id()).then_some(id)
+    }));
+    new_leader.set("key50".to_owned(), "value50".to_owned())?;
+    for id in (1..=4).filter(|&id| id != leader.id()) {
+        cluster.wait_for(id, "key50", Some("value50"));
+        assert!(!cluster.node(id).members().contains_key(&leader.id()));
+    }
+    Ok(())
+}
+
+#[test]
+fn restart_before_snapshot_applies_nothing_twice() -> Result<()> {
+    let cluster = Cluster::new(&[1, 2, 3], 1000);
+    let leader = cluster.node(cluster.leader());
+    for i in 0..50 {
+        leader.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for id in 1..=3 {
+        cluster.wait_for(id, "key49", Some("value49"));
+    }
+
+    drop(leader);
+    for id in 1..=3 {
+        cluster.crash(id);
+    }
+    let last_seq = |id: NodeId| {
+        let store = KvStore::open(cluster.dirs[&id].path()).unwrap();
+        store.last_seq().unwrap()
+    };
+    let seqs: Vec<_> = (1..=3).map(last_seq).collect();
+    for id in 1..=3 {
+        cluster.restart(id);
+    }
+    let leader = cluster.node(cluster.leader());
+    leader.set("key50".to_owned(), "value50".to_owned())?;
+    for id in 1..=3 {
+        cluster.wait_for(id, "key50", Some("value50"));
+    }
+
+    // the new entry is the only write since the crash
+    drop(leader);
+    for id in 1..=3 {
+        cluster.crash(id);
+    }
+    for id in 1..=3 {
+        assert_eq!(last_seq(id), seqs[id as usize - 1] + 1);
+    }
+    Ok(())
+}
+
+#[test]
+fn refuse_data_written_outside_raft() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let option = RaftOption {
+        id: 1,
+        members: [(1, addr_of(1))].into_iter().collect(),
+        ..RaftOption::default()
+    };
+    let transport = NetworkTransport {
+        from: 1,
+        network: Network::default(),
+    };
+    let result = RaftNode::start(engine, temp_dir.path().to_path_buf(), option, transport);
+    assert!(matches!(result, Err(KvsError::ServerErrorMessage(_))));
+
+    let engine = KvStore::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+fn spawn_node(temp_dir: &TempDir, id: NodeId, addr: &str, peers: &str) -> Server {
+    let id = id.to_string();
+    spawn_server(
+        temp_dir,
+        &["--addr", addr, "--raft-id", &id, "--peers", peers],
+    )
+}
+
+// Sends a write to every node until one of them accepts it
+fn set_anywhere(addrs: &[&str], key: &str, value: &str) {
+    wait_until(|| {
+        addrs.iter().find_map(|addr| {
+            KvsClient::connect(addr)
+                .and_then(|mut client| client.set(key.to_owned(), value.to_owned()))
+                .ok()
+        })
+    })
+}
+
+fn wait_for_value(addr: &str, key: &str, expected: &str) {
+    wait_until(|| {
+        let value = KvsClient::connect(addr)
+            .and_then(|mut client| client.get(key.to_owned()))
+            .ok()?;
+        (value.as_deref() == Some(expected)).then_some(())
+    })
+}
+
+#[test]
+fn kvs_server_cluster() {
+    let addrs = ["127.0.0.1:4014", "127.0.0.1:4015", "127.0.0.1:4016"];
+    let peers = "1=127.0.0.1:4014,2=127.0.0.1:4015,3=127.0.0.1:4016";
+    let dirs: Vec<_> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<_> = (0..3)
+        .map(|i| Some(spawn_node(&dirs[i], i as NodeId + 1, addrs[i], peers)))
+        .collect();
+
+    set_anywhere(&addrs, "key1", "value1");
+    for addr in addrs {
+        wait_for_value(addr, "key1", "value1");
+    }
+
+    // any two nodes keep the cluster writable
+    servers[0].take();
+    set_anywhere(&addrs[1..], "key1", "value2");
+    wait_for_value(addrs[1], "key1", "value2");
+    wait_for_value(addrs[2], "key1", "value2");
+
+    servers[0] = Some(spawn_node(&dirs[0], 1, addrs[0], peers));
+    wait_for_value(addrs[0], "key1", "value2");
+}
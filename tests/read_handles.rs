@@ -0,0 +1,47 @@
+use kvs::{KvStore, KvsEngine, Result, StoreOption};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use tempfile::TempDir;
+
+// With a single open file shared by every handle and namespace, readers
+// keep evicting each other's handles while in flight.
+#[test]
+fn get_with_one_open_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let option = StoreOption {
+        max_open_files: 1,
+        ..StoreOption::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), option)?;
+    let tree = store.open_tree("other")?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        tree.set(format!("key{}", i), format!("other{}", i))?;
+    }
+
+    let barrier = Arc::new(Barrier::new(8));
+    let readers: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let (store, barrier) = (store.clone(), Arc::clone(&barrier));
+            thread::spawn(move || -> Result<()> {
+                let (store, prefix) = match thread_id % 2 {
+                    0 => (store, "value"),
+                    _ => (store.open_tree("other")?, "other"),
+                };
+                barrier.wait();
+                for round in 0..10 {
+                    for i in 0..100 {
+                        let key_id = (i + round + thread_id) % 100;
+                        let value = store.get(format!("key{}", key_id))?;
+                        assert_eq!(value, Some(format!("{}{}", prefix, key_id)));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use kvs::{KvStore, KvsEngine, Result};
+use std::thread;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+// A read view keeps seeing the store as it was, whatever is written
+// or compacted meanwhile.
+#[test]
+fn read_view_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    let expected: Vec<_> = (0..100)
+        .map(|i| (format!("key{:03}", i), format!("value{}", i)))
+        .collect();
+
+    let view = store.read_view();
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            let value = "v".repeat(1000);
+            for round in 0..30 {
+                for i in 0..100 {
+                    store.set(format!("key{:03}", i), value.clone())?;
+                }
+                store.set(format!("new{}", round), value.clone())?;
+                store.remove(format!("key{:03}", round))?;
+            }
+            Ok(())
+        })
+    };
+    for _ in 0..20 {
+        assert_eq!(view.get("key000".to_owned())?, Some("value0".to_owned()));
+        let page = view.scan(None, 1000)?;
+        assert_eq!(page.items, expected);
+    }
+    writer.join().unwrap()?;
+    assert_eq!(store.get("key029".to_owned())?, None);
+    assert_eq!(view.get("key029".to_owned())?, Some("value29".to_owned()));
+    assert_eq!(view.get("new0".to_owned())?, None);
+    assert_eq!(view.scan(None, 1000)?.items, expected);
+    // pages skip keys the view does not see
+    let mut scanned = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = view.scan(cursor, 7)?;
+        scanned.extend(page.items);
+        cursor = page.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(scanned, expected);
+
+    // stale files are only removed once no view reads them
+    let log_count = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().path().extension().map(|ext| ext.to_owned()))
+            .filter(|ext| {
+                ext.as_deref() == Some("log".as_ref()) || ext.as_deref() == Some("history".as_ref())
+            })
+            .count()
+    };
+    let pinned = log_count();
+    drop(view);
+    assert!(log_count() < pinned);
+
+    let view = store.read_view();
+    store.set("key099".to_owned(), "changed".to_owned())?;
+    assert_eq!(view.get("key099".to_owned())?, Some("v".repeat(1000)));
+    assert_eq!(view.seq() + 1, store.read_view().seq());
+    Ok(())
+}
@@ -0,0 +1,265 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use common::{spawn_server, Server};
+use kvs::{HashRange, KvsClient, KvsError, Result};
+use std::io::{self, BufRead, BufReader};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn spawn_node(temp_dir: &TempDir, engine: &str, addr: &str, leader: Option<&str>) -> Server {
+    let mut args = vec!["--engine", engine, "--addr", addr];
+    if let Some(leader) = leader {
+        args.extend(["--replica-of", leader]);
+    }
+    spawn_server(temp_dir, &args)
+}
+
+// Every request uses its own connection: an open connection
+// occupies one of the server's worker threads.
+fn set(addr: &str, key: &str, value: &str) -> Result<()> {
+    KvsClient::connect(addr)?.set(key.to_owned(), value.to_owned())
+}
+
+fn get(addr: &str, key: &str) -> Result<Option<String>> {
+    KvsClient::connect(addr)?.get(key.to_owned())
+}
+
+fn remove(addr: &str, key: &str) -> Result<()> {
+    KvsClient::connect(addr)?.remove(key.to_owned())
+}
+
+fn set_in(addr: &str, namespace: &str, key: &str, value: &str) -> Result<()> {
+    KvsClient::connect(addr)?
+        .with_namespace(Some(namespace.to_owned()))
+        .set(key.to_owned(), value.to_owned())
+}
+
+// Polls `addr` until `key` has the expected value
+fn wait_for(addr: &str, key: &str, expected: Option<&str>) -> Result<()> {
+    wait_for_in(addr, None, key, expected)
+}
+
+fn wait_for_in(
+    addr: &str,
+    namespace: Option<&str>,
+    key: &str,
+    expected: Option<&str>,
+) -> Result<()> {
+    for _ in 0..50 {
+        let mut client = KvsClient::connect(addr)?.with_namespace(namespace.map(str::to_owned));
+        if client.get(key.to_owned())?.as_deref() == expected {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("{} never became {:?} on {}", key, expected, addr);
+}
+
+fn follow_leader(engine: &str, leader_addr: &str, follower_addr: &str) -> Result<()> {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let leader = spawn_node(&leader_dir, engine, leader_addr, None);
+    thread::sleep(Duration::from_secs(1));
+
+    set(leader_addr, "key1", "value1")?;
+    set(leader_addr, "key2", "value2")?;
+    set_in(leader_addr, "ns1", "key1", "ns-value1")?;
+
+    // snapshot
+    let follower_server = spawn_node(&follower_dir, engine, follower_addr, Some(leader_addr));
+    thread::sleep(Duration::from_secs(1));
+    wait_for(follower_addr, "key1", Some("value1"))?;
+    wait_for(follower_addr, "key2", Some("value2"))?;
+    wait_for_in(follower_addr, Some("ns1"), "key1", Some("ns-value1"))?;
+
+    // subsequent mutations, in namespaces created since too
+    set(leader_addr, "key1", "value3")?;
+    remove(leader_addr, "key2")?;
+    set_in(leader_addr, "ns1", "key1", "ns-value2")?;
+    set_in(leader_addr, "ns2", "key1", "ns-value3")?;
+    wait_for(follower_addr, "key1", Some("value3"))?;
+    wait_for(follower_addr, "key2", None)?;
+    wait_for_in(follower_addr, Some("ns1"), "key1", Some("ns-value2"))?;
+    wait_for_in(follower_addr, Some("ns2"), "key1", Some("ns-value3"))?;
+
+    // writes are redirected to the leader
+    match set(follower_addr, "key3", "value4") {
+        Err(KvsError::Redirect(addr)) => assert_eq!(addr.to_string(), leader_addr),
+        result => panic!("unexpected result {:?}", result),
+    }
+    assert!(matches!(
+        remove(follower_addr, "key1"),
+        Err(KvsError::Redirect(_))
+    ));
+    // and so are migrations, keys are only moved by their owner
+    let everything = HashRange { start: 0, end: 0 };
+    match KvsClient::connect(follower_addr)?.migrate(vec![everything], leader_addr.parse().unwrap())
+    {
+        Err(KvsError::Redirect(addr)) => assert_eq!(addr.to_string(), leader_addr),
+        result => panic!("unexpected result {:?}", result),
+    }
+    wait_for(follower_addr, "key1", Some("value3"))?;
+
+    // a restarted follower catches up
+    drop(follower_server);
+    set(leader_addr, "key4", "value5")?;
+    let _follower_server = spawn_node(&follower_dir, engine, follower_addr, Some(leader_addr));
+    thread::sleep(Duration::from_secs(1));
+    wait_for(follower_addr, "key4", Some("value5"))?;
+    wait_for(follower_addr, "key1", Some("value3"))?;
+
+    // the follower reconnects to a restarted leader, and resumes
+    // after the last change it applied if the leader logs them
+    drop(leader);
+    let _leader = spawn_node(&leader_dir, engine, leader_addr, None);
+    thread::sleep(Duration::from_secs(1));
+    set(leader_addr, "key5", "value8")?;
+    remove(leader_addr, "key4")?;
+    set_in(leader_addr, "ns2", "key1", "ns-value4")?;
+    wait_for(follower_addr, "key5", Some("value8"))?;
+    wait_for_in(follower_addr, Some("ns2"), "key1", Some("ns-value4"))?;
+    wait_for(follower_addr, "key4", None)?;
+    wait_for(follower_addr, "key1", Some("value3"))?;
+
+    // promotion
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["promote", "--addr", follower_addr])
+        .assert()
+        .success();
+    set(follower_addr, "key3", "value6")?;
+    assert_eq!(get(follower_addr, "key3")?, Some("value6".to_owned()));
+    set(leader_addr, "key1", "value7")?;
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(get(follower_addr, "key1")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn follow_leader_kvs_engine() -> Result<()> {
+    follow_leader("kvs", "127.0.0.1:4010", "127.0.0.1:4011")
+}
+
+#[test]
+fn follow_leader_sled_engine() -> Result<()> {
+    follow_leader("sled", "127.0.0.1:4012", "127.0.0.1:4013")
+}
+
+// Forwards connections from `addr` to `target`, until cut off
+struct Proxy {
+    up: Arc<AtomicBool>,
+    links: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Proxy {
+    fn new(addr: &str, target: &str) -> Proxy {
+        let listener = TcpListener::bind(addr).unwrap();
+        let target = target.to_owned();
+        let up = Arc::new(AtomicBool::new(true));
+        let links = Arc::new(Mutex::new(Vec::new()));
+        let proxy = Proxy {
+            up: Arc::clone(&up),
+            links: Arc::clone(&links),
+        };
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = client.unwrap();
+                if !up.load(Ordering::SeqCst) {
+                    continue;
+                }
+                let server = match TcpStream::connect(&target) {
+                    Ok(server) => server,
+                    Err(_) => continue,
+                };
+                let mut links = links.lock().unwrap();
+                links.push(client.try_clone().unwrap());
+                links.push(server.try_clone().unwrap());
+                for (mut from, mut to) in [
+                    (client.try_clone().unwrap(), server.try_clone().unwrap()),
+                    (server, client),
+                ] {
+                    thread::spawn(move || {
+                        let _ = io::copy(&mut from, &mut to);
+                        let _ = to.shutdown(Shutdown::Both);
+                    });
+                }
+            }
+        });
+        proxy
+    }
+
+    fn cut(&self) {
+        self.up.store(false, Ordering::SeqCst);
+        for link in self.links.lock().unwrap().drain(..) {
+            let _ = link.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn restore(&self) {
+        self.up.store(true, Ordering::SeqCst);
+    }
+}
+
+// A follower cut off while the leader compacts its log should resume
+// from the history the leader keeps, instead of taking another snapshot
+#[test]
+fn resume_after_compaction() -> Result<()> {
+    let leader_addr = "127.0.0.1:4036";
+    let proxy_addr = "127.0.0.1:4037";
+    let follower_addr = "127.0.0.1:4038";
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let _leader = spawn_server(
+        &leader_dir,
+        &["--addr", leader_addr, "--history-retention", "10000"],
+    );
+    let proxy = Proxy::new(proxy_addr, leader_addr);
+    thread::sleep(Duration::from_secs(1));
+    set(leader_addr, "key1", "value1")?;
+
+    let mut follower = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", follower_addr, "--replica-of", proxy_addr])
+        .current_dir(&follower_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stderr = follower.stderr.take().unwrap();
+    let _follower = Server(follower);
+    let (sender, log) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(io::Result::ok) {
+            if sender.send(line).is_err() {
+                return;
+            }
+        }
+    });
+    thread::sleep(Duration::from_secs(1));
+    wait_for(follower_addr, "key1", Some("value1"))?;
+
+    // several compactions worth of overwrites
+    proxy.cut();
+    let value = "v".repeat(1000);
+    let mut client = KvsClient::connect(leader_addr)?;
+    for i in 0..5000 {
+        client.set(format!("key{}", i % 100), value.clone())?;
+    }
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    drop(client);
+
+    proxy.restore();
+    wait_for(follower_addr, "key1", Some("value2"))?;
+    wait_for(follower_addr, "key99", Some(&value))?;
+    let snapshots = log
+        .try_iter()
+        .filter(|line| line.contains("Snapshot of the default namespace"))
+        .count();
+    assert_eq!(snapshots, 1);
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use kvs::{KvStore, KvsEngine, Result};
+use std::thread;
+use tempfile::TempDir;
+
+// Values of files sealed by reopening or compaction are read by every
+// handle alike, while writes keep sealing new ones.
+#[test]
+fn get_from_sealed_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            let value = "v".repeat(1000);
+            for i in 0..3000 {
+                store.set(format!("hot{}", i % 50), value.clone())?;
+                if i % 1000 == 0 {
+                    store.set(format!("key{}", i % 100), format!("value{}", i % 100))?;
+                }
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for round in 0..20 {
+                    for i in 0..100 {
+                        let value = store.get(format!("key{}", (i + round) % 100))?;
+                        assert_eq!(value, Some(format!("value{}", (i + round) % 100)));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    writer.join().unwrap()?;
+    assert_eq!(store.get("hot7".to_owned())?, Some("v".repeat(1000)));
+    Ok(())
+}
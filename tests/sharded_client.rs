@@ -0,0 +1,127 @@
+mod common;
+
+use assert_cmd::prelude::*;
+use common::spawn_server;
+use kvs::{KvsClient, PoolOption, Result, ShardedKvsClient};
+use predicates::str::contains;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn addrs(n: u16) -> Vec<SocketAddr> {
+    (0..n)
+        .map(|i| format!("127.0.0.1:{}", 6000 + i).parse().unwrap())
+        .collect()
+}
+
+#[test]
+fn keys_spread_over_all_shards() {
+    let client = ShardedKvsClient::new(&addrs(3), PoolOption::default());
+    let mut counts = HashMap::new();
+    for i in 0..3000 {
+        *counts
+            .entry(client.shard_of(&format!("key{}", i)))
+            .or_insert(0) += 1;
+    }
+    assert_eq!(counts.len(), 3);
+    for count in counts.values() {
+        assert!(
+            (600..1400).contains(count),
+            "unbalanced shards {:?}",
+            counts
+        );
+    }
+
+    // placement only depends on the set of servers
+    let other = ShardedKvsClient::new(&addrs(3), PoolOption::default());
+    for i in 0..100 {
+        let key = format!("key{}", i);
+        assert_eq!(client.shard_of(&key), other.shard_of(&key));
+    }
+}
+
+#[test]
+fn adding_a_shard_moves_few_keys() {
+    let before = ShardedKvsClient::new(&addrs(4), PoolOption::default());
+    let after = ShardedKvsClient::new(&addrs(5), PoolOption::default());
+    let new_shard = addrs(5)[4];
+    let mut moved = 0;
+    for i in 0..5000 {
+        let key = format!("key{}", i);
+        if before.shard_of(&key) != after.shard_of(&key) {
+            // keys only move to the new server
+            assert_eq!(after.shard_of(&key), new_shard);
+            moved += 1;
+        }
+    }
+    assert!((500..1600).contains(&moved), "{} keys moved", moved);
+}
+
+#[test]
+fn mset_mget_across_servers() -> Result<()> {
+    let addrs: Vec<SocketAddr> = ["127.0.0.1:4017", "127.0.0.1:4018", "127.0.0.1:4019"]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+    let dirs: Vec<_> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    let _servers: Vec<_> = dirs
+        .iter()
+        .zip(&addrs)
+        .map(|(dir, addr)| spawn_server(dir, &["--addr", &addr.to_string()]))
+        .collect();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = ShardedKvsClient::new(&addrs, PoolOption::default());
+    let pairs: Vec<_> = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client.mset(pairs.clone())?;
+    client.remove("key0".to_owned())?;
+
+    let mut keys: Vec<_> = pairs.iter().map(|(key, _)| key.clone()).collect();
+    keys.push("missing".to_owned());
+    let values = client.mget(keys)?;
+    assert_eq!(values[0], None);
+    for (i, value) in values.iter().enumerate().take(100).skip(1) {
+        assert_eq!(value, &Some(format!("value{}", i)));
+    }
+    assert_eq!(values[100], None);
+
+    // Idle pooled connections hold on to the servers' workers,
+    // so let them go before talking to a server directly.
+    drop(client);
+    let client = ShardedKvsClient::new(&addrs, PoolOption::default());
+
+    // every key lives on its own shard only
+    for (key, value) in &pairs[1..] {
+        for addr in &addrs {
+            let expected = (*addr == client.shard_of(key)).then(|| value.clone());
+            assert_eq!(KvsClient::connect(addr)?.get(key.clone())?, expected);
+        }
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--cluster", "127.0.0.1:4017,127.0.0.1:4018,127.0.0.1:4019"])
+        .args(["mset", "key1", "value1", "key2", "value2"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mget", "key1", "key2", "key0"])
+        .args(["--cluster", "127.0.0.1:4017,127.0.0.1:4018,127.0.0.1:4019"])
+        .assert()
+        .success()
+        .stdout("value1\nvalue2\nKey not found\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--cluster", "127.0.0.1:4017,127.0.0.1:4018,127.0.0.1:4019"])
+        .args(["get", "key50"])
+        .assert()
+        .success()
+        .stdout(contains("value50"));
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use kvs::{KvStore, KvsEngine, Result, StoreOption};
+use std::fs;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+// Removes survive merges as tombstones for as long as an older file may
+// still set their key, and are dropped once it is merged too.
+#[test]
+fn tombstones_through_merges() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logs = || -> Vec<String> {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| fs::read_to_string(entry.path()).unwrap())
+            .collect()
+    };
+    let option = StoreOption {
+        merge_file_size: 64 * 1024,
+        ..StoreOption::default()
+    };
+    let value = "v".repeat(1000);
+    let store = KvStore::open_with(temp_dir.path(), option.clone())?;
+    for i in 0..500 {
+        store.set(format!("cold{}", i), value.clone())?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), option.clone())?;
+    for i in 0..10 {
+        store.remove(format!("cold{}", i))?;
+    }
+    for i in 0..2000 {
+        store.set(format!("hot{}", i % 10), value.clone())?;
+    }
+    assert!(store.stats().compactions > 0);
+    assert!(temp_dir.path().join("1.log").exists());
+    assert!(logs().iter().any(|log| log.contains("Tombstone")));
+    assert!(logs().iter().all(|log| !log.contains("Remove")));
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), option.clone())?;
+    assert_eq!(store.get("cold0".to_owned())?, None);
+    for i in 10..500 {
+        store.set(format!("cold{}", i), "new".to_owned())?;
+    }
+    for i in 0..3000 {
+        store.set(format!("hot{}", i % 10), format!("{}{}", i, value))?;
+    }
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert!(logs().iter().all(|log| !log.contains("Tombstone")));
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), option)?;
+    assert_eq!(store.stats().keys, 500);
+    assert_eq!(store.get("cold0".to_owned())?, None);
+    assert_eq!(store.get("cold9".to_owned())?, None);
+    assert_eq!(store.get("cold10".to_owned())?, Some("new".to_owned()));
+    assert_eq!(
+        store.get("hot9".to_owned())?,
+        Some(format!("2999{}", value))
+    );
+    Ok(())
+}
+
+// Files a merge did not get to delete before a crash, even copied in
+// under a higher id, change nothing on open.
+#[test]
+fn merge_leftovers_after_crash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let option = StoreOption {
+        merge_file_size: 64 * 1024,
+        ..StoreOption::default()
+    };
+    let value = "v".repeat(1000);
+    let store = KvStore::open_with(temp_dir.path(), option.clone())?;
+    for i in 0..500 {
+        store.set(format!("cold{}", i), value.clone())?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), option.clone())?;
+    for i in 0..10 {
+        store.remove(format!("cold{}", i))?;
+    }
+    for i in 0..2000 {
+        store.set(format!("hot{}", i % 10), i.to_string())?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), option.clone())?;
+    let sealed: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name.to_string_lossy().ends_with(".log"))
+        .collect();
+    for name in &sealed {
+        fs::hard_link(temp_dir.path().join(name), backup_dir.path().join(name))?;
+    }
+    for i in 10..500 {
+        store.set(format!("cold{}", i), "new".to_owned())?;
+    }
+    for i in 0..3000 {
+        store.set(format!("hot{}", i % 10), format!("{}{}", 2000 + i, value))?;
+    }
+    assert!(!temp_dir.path().join("1.log").exists());
+    drop(store);
+
+    // as if the merge had crashed before deleting the files it merged
+    for name in &sealed {
+        let path = temp_dir.path().join(name);
+        if !path.exists() {
+            fs::hard_link(backup_dir.path().join(name), path)?;
+        }
+    }
+    fs::copy(
+        backup_dir.path().join("1.log"),
+        temp_dir.path().join("999.log"),
+    )?;
+
+    let store = KvStore::open_with(temp_dir.path(), option)?;
+    assert_eq!(store.stats().keys, 500);
+    assert_eq!(store.get("cold0".to_owned())?, None);
+    assert_eq!(store.get("cold9".to_owned())?, None);
+    assert_eq!(store.get("cold10".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("cold499".to_owned())?, Some("new".to_owned()));
+    assert_eq!(
+        store.get("hot9".to_owned())?,
+        Some(format!("4999{}", value))
+    );
+    Ok(())
+}
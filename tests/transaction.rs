@@ -0,0 +1,208 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Result, SledWrapper, Transaction};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Concurrent transfers between accounts never create nor lose money.
+fn transfers_keep_the_total<E: KvsEngine>(engine: E) -> Result<()> {
+    for account in 0..4 {
+        engine.set(format!("account{}", account), "100".to_owned())?;
+    }
+
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    let (from, to) = (format!("account{}", (t + i) % 4), format!("account{}", t));
+                    if from == to {
+                        continue;
+                    }
+                    engine.transaction(|tx| {
+                        let balance =
+                            |value: Option<String>| -> u64 { value.unwrap().parse().unwrap() };
+                        let from_balance = balance(tx.get(from.clone())?);
+                        let to_balance = balance(tx.get(to.clone())?);
+                        if from_balance > 0 {
+                            tx.set(from.clone(), (from_balance - 1).to_string())?;
+                            tx.set(to.clone(), (to_balance + 1).to_string())?;
+                        }
+                        Ok(())
+                    })?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let total: u64 = (0..4)
+        .map(|account| {
+            let value = engine.get(format!("account{}", account)).unwrap();
+            value.unwrap().parse::<u64>().unwrap()
+        })
+        .sum();
+    assert_eq!(total, 400);
+    Ok(())
+}
+
+fn conflicting_commit_fails<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut tx = engine.begin()?;
+    assert_eq!(tx.get("key1".to_owned())?, Some("value1".to_owned()));
+    tx.set("key2".to_owned(), "value2".to_owned())?;
+    tx.remove("key1".to_owned())?;
+    // writes are only seen by the transaction until committed
+    assert_eq!(tx.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    engine.set("key1".to_owned(), "changed".to_owned())?;
+    assert!(matches!(tx.commit(), Err(KvsError::TransactionConflict)));
+    assert_eq!(engine.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    // keys which are only written do not conflict
+    let mut tx = engine.begin()?;
+    tx.set("key1".to_owned(), "blind".to_owned())?;
+    engine.set("key1".to_owned(), "changed again".to_owned())?;
+    tx.commit()?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("blind".to_owned()));
+
+    let mut tx = engine.begin()?;
+    assert!(matches!(
+        tx.remove("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // an error out of the closure is no conflict, nothing is written
+    let result: Result<()> = engine.transaction(|tx| {
+        tx.set("key3".to_owned(), "value3".to_owned())?;
+        tx.remove("missing".to_owned())
+    });
+    assert!(matches!(result, Err(KvsError::KeyNotFound)));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn kvs_transfers() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    transfers_keep_the_total(KvStore::open(temp_dir.path())?)?;
+    // committed transactions survive a reopen
+    transfers_keep_the_total(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_transfers() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    transfers_keep_the_total(SledWrapper::new(sled::open(temp_dir.path())?))
+}
+
+#[test]
+fn kvs_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    conflicting_commit_fails(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    conflicting_commit_fails(SledWrapper::new(sled::open(temp_dir.path())?))
+}
+
+// A transaction is either in the key dir after a crash, or not at all.
+#[test]
+fn torn_transaction_is_ignored() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    store.transaction(|tx| {
+        tx.set("key1".to_owned(), "value1".to_owned())?;
+        tx.set("key2".to_owned(), "value2".to_owned())
+    })?;
+    drop(store);
+
+    // the first command of a transaction, as if the process died right after it
+    let last_log = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .max_by_key(|path| fs::metadata(path).unwrap().len())
+        .unwrap();
+    let mut file = OpenOptions::new().append(true).open(last_log)?;
+    file.write_all(
+        br#"{"Set":{"seq":100,"timestamp":0,"key":"key1","value":"torn","txn_left":1}}"#,
+    )?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    // numbered on from the last complete transaction, which the feed ends with
+    let changes = store
+        .changes_since(0)?
+        .map(|change| change.map(|change| (change.seq, change.event.key().to_owned())))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        changes,
+        [
+            (1, "key1".to_owned()),
+            (2, "key2".to_owned()),
+            (3, "key3".to_owned())
+        ]
+    );
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn server_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4027".parse().unwrap();
+    let engine = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || KvsServer::new(engine, pool).run(&addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut other = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    client.begin()?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(other.get("key2".to_owned())?, None);
+    client.commit()?;
+    assert_eq!(other.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    client.begin()?;
+    client.get("key1".to_owned())?;
+    client.remove("key2".to_owned())?;
+    other.set("key1".to_owned(), "changed".to_owned())?;
+    assert!(matches!(
+        client.commit(),
+        Err(KvsError::TransactionConflict)
+    ));
+    assert_eq!(other.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    client.begin()?;
+    client.remove("key2".to_owned())?;
+    client.abort()?;
+    assert_eq!(other.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(client.commit().is_err());
+
+    // a transaction stays in its namespace
+    client.begin()?;
+    let mut client = client.with_namespace(Some("users".to_owned()));
+    assert!(client.get("key1".to_owned()).is_err());
+    client.abort()?;
+    Ok(())
+}
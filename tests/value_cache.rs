@@ -0,0 +1,54 @@
+use kvs::{KvStore, KvsEngine, Result, StoreOption, Transaction};
+use tempfile::TempDir;
+
+// Cached values follow every write, and stay valid through compaction.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let option = StoreOption {
+        value_cache_size: 4096,
+        ..StoreOption::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), option)?;
+    let tree = store.open_tree("other")?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    tree.set("key1".to_owned(), "other1".to_owned())?;
+
+    for _ in 0..3 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(tree.get("key1".to_owned())?, Some("other1".to_owned()));
+    }
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses), (4, 2));
+    assert_eq!(stats.bytes, 20);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    let mut tx = store.begin()?;
+    assert_eq!(tx.get("key1".to_owned())?, Some("value2".to_owned()));
+    tx.remove("key1".to_owned())?;
+    tx.commit()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.cache_stats().hits, 5);
+
+    // compaction moves the value, which is still a hit
+    let filler = "v".repeat(1000);
+    for i in 0..2000 {
+        store.set(format!("filler{}", i % 10), filler.clone())?;
+    }
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.cache_stats().hits, 6);
+
+    // least recently read values make room for new ones
+    for i in 0..10 {
+        assert_eq!(store.get(format!("filler{}", i))?, Some(filler.clone()));
+    }
+    assert!(store.cache_stats().bytes <= 4096);
+    assert_eq!(store.get("filler9".to_owned())?, Some(filler));
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses), (7, 14));
+    Ok(())
+}
@@ -0,0 +1,166 @@
+mod common;
+
+use common::spawn_server;
+use kvs::{KvStore, KvsClient, KvsEngine, Result, SledWrapper, WatchEvent};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn watch_prefix<E: KvsEngine>(engine: E) -> Result<()> {
+    let mut events = engine.watch("user/".to_owned())?;
+
+    engine.set("user/1".to_owned(), "alice".to_owned())?;
+    engine.set("config".to_owned(), "ignored".to_owned())?;
+    engine.set("user/2".to_owned(), "bob".to_owned())?;
+    engine.remove("user/1".to_owned())?;
+
+    match events.next().unwrap() {
+        WatchEvent::Set { key, value, .. } => {
+            assert_eq!(key, "user/1");
+            assert_eq!(value, "alice");
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+    match events.next().unwrap() {
+        WatchEvent::Set { key, value, .. } => {
+            assert_eq!(key, "user/2");
+            assert_eq!(value, "bob");
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+    match events.next().unwrap() {
+        WatchEvent::Remove { key, .. } => assert_eq!(key, "user/1"),
+        event => panic!("unexpected event {:?}", event),
+    }
+    Ok(())
+}
+
+#[test]
+fn watch_prefix_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch_prefix(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn watch_prefix_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    watch_prefix(SledWrapper::new(sled::open(temp_dir.path())?))
+}
+
+// Writes never wait for a watcher which does not read its events,
+// its stream ends instead.
+#[test]
+fn slow_watcher_is_dropped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut events = store.watch("key".to_owned())?;
+    for i in 0..10_000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let mut received = 0;
+    while let Ok(event) = events.next_timeout(Duration::from_secs(1)) {
+        match event {
+            WatchEvent::Set { key, .. } => assert_eq!(key, format!("key{}", received)),
+            event => panic!("unexpected event {:?}", event),
+        }
+        received += 1;
+    }
+    assert!(received > 0 && received < 10_000, "received {}", received);
+    assert!(matches!(
+        events.next_timeout(Duration::from_millis(10)),
+        Err(mpsc::RecvTimeoutError::Disconnected)
+    ));
+    Ok(())
+}
+
+// A watcher reading a key as soon as it is told of a write sees that write.
+#[test]
+fn get_after_event_sees_the_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut events = store.watch("key".to_owned())?;
+    // the writer waits for every event to be checked before writing again
+    let (checked, wait_checked) = mpsc::channel();
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..200 {
+                store.set("key".to_owned(), i.to_string())?;
+                wait_checked.recv().unwrap();
+                store.remove("key".to_owned())?;
+                wait_checked.recv().unwrap();
+            }
+            Ok(())
+        })
+    };
+    for i in 0..200 {
+        match events.next_timeout(Duration::from_secs(5)).unwrap() {
+            WatchEvent::Set { value, .. } => {
+                assert_eq!(value, i.to_string());
+                assert_eq!(store.get("key".to_owned())?, Some(value));
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        checked.send(()).unwrap();
+        match events.next_timeout(Duration::from_secs(5)).unwrap() {
+            WatchEvent::Remove { .. } => assert_eq!(store.get("key".to_owned())?, None),
+            event => panic!("unexpected event {:?}", event),
+        }
+        checked.send(()).unwrap();
+    }
+    writer.join().unwrap()
+}
+
+#[test]
+fn watch_through_server() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let _server = spawn_server(&temp_dir, &["--engine", "kvs", "--addr", addr]);
+    thread::sleep(Duration::from_secs(1));
+
+    let mut events = KvsClient::connect(addr)?.watch("key".to_owned())?;
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("other".to_owned(), "value2".to_owned())?;
+    client.remove("key1".to_owned())?;
+
+    match events.next().unwrap()? {
+        WatchEvent::Set { key, value, .. } => {
+            assert_eq!(key, "key1");
+            assert_eq!(value, "value1");
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+    match events.next().unwrap()? {
+        WatchEvent::Remove { key, .. } => assert_eq!(key, "key1"),
+        event => panic!("unexpected event {:?}", event),
+    }
+    Ok(())
+}
+
+// The streaming thread notices a watcher that went away even if no
+// matching key is written, and ends with its subscription.
+#[cfg(target_os = "linux")]
+#[test]
+fn watcher_disconnect_ends_stream() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4029";
+    let server = spawn_server(&temp_dir, &["--engine", "kvs", "--addr", addr]);
+    thread::sleep(Duration::from_secs(1));
+    let threads = || -> usize {
+        let status = std::fs::read_to_string(format!("/proc/{}/status", server.0.id())).unwrap();
+        let line = status.lines().find(|line| line.starts_with("Threads:"));
+        line.unwrap()["Threads:".len()..].trim().parse().unwrap()
+    };
+
+    let idle = threads();
+    let events = KvsClient::connect(addr)?.watch("key".to_owned())?;
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(threads(), idle + 1);
+    drop(events);
+    thread::sleep(Duration::from_secs(3));
+    assert_eq!(threads(), idle);
+    Ok(())
+}